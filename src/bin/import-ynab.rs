@@ -2,7 +2,7 @@ use import_ynab_lib::cli;
 use import_ynab_lib::prelude::*;
 
 fn main() -> Result<()> {
-    if std::env::var_os("RUST_LOG") == None {
+    if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info");
     }
     pretty_env_logger::init();
@@ -0,0 +1,410 @@
+//! Reads ISO 20022 CAMT.053 account statements, and CAMT.052 intraday
+//! account reports which use the same entry format.
+
+use crate::files::{Source, Statement, StatementProvider};
+use crate::import_id::ImportIds;
+use crate::prelude::*;
+use crate::{AccountIdentifiers, AccountType, ConnectedProvider, TransactionState};
+use chrono::{NaiveDate, TimeZone, Utc};
+use roxmltree::Node;
+
+const IMPORT_ID_PREFIX: &str = "CAMT";
+
+pub fn initialize(source: &mut Source) -> (bool, Result<Box<dyn ConnectedProvider>>) {
+    (
+        false,
+        load(source).map(|it| Box::new(it) as Box<dyn ConnectedProvider>),
+    )
+}
+
+fn load(source: &Source) -> Result<StatementProvider> {
+    let mut statements = vec![];
+    for file in crate::files::statement_files(&source.path, &["xml", "camt", "053", "052"])? {
+        let data = std::fs::read_to_string(&file)
+            .with_context(|| format!("Error reading {}", file.display()))?;
+        statements.extend(
+            read_statements(&data).with_context(|| format!("Error in {}", file.display()))?,
+        );
+    }
+
+    Ok(crate::files::combine_statements(
+        &source.display_name,
+        statements,
+    ))
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|it| it.has_tag_name(name))
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children().filter(move |it| it.has_tag_name(name))
+}
+
+/// Follows a path of element names, e.g. `["Acct", "Id", "IBAN"]`
+fn path<'a, 'input>(node: Node<'a, 'input>, names: &[&str]) -> Option<Node<'a, 'input>> {
+    names.iter().try_fold(node, |node, name| child(node, name))
+}
+
+fn text<'a>(node: Node<'a, '_>, names: &[&str]) -> Option<&'a str> {
+    path(node, names)
+        .and_then(|it| it.text())
+        .map(str::trim)
+        .filter(|it| !it.is_empty())
+}
+
+fn read_statements(data: &str) -> Result<Vec<Statement>> {
+    let document = roxmltree::Document::parse(data)?;
+    let root = document.root_element();
+    let message = child(root, "BkToCstmrStmt")
+        .or_else(|| child(root, "BkToCstmrAcctRpt"))
+        .ok_or_else(|| anyhow!("Not a CAMT.053 or CAMT.052 document"))?;
+
+    message
+        .children()
+        .filter(|it| it.has_tag_name("Stmt") || it.has_tag_name("Rpt"))
+        .map(read_statement)
+        .collect()
+}
+
+fn read_statement(statement: Node) -> Result<Statement> {
+    let iban = text(statement, &["Acct", "Id", "IBAN"]);
+    let number = iban
+        .or_else(|| text(statement, &["Acct", "Id", "Othr", "Id"]))
+        .ok_or_else(|| anyhow!("Statement has no account id"))?;
+
+    let mut balances: HashMap<&str, (i64, Option<UtcDate>, Option<&str>)> = HashMap::new();
+    for balance in children(statement, "Bal") {
+        let code = match text(balance, &["Tp", "CdOrPrtry", "Cd"]) {
+            Some(code) => code,
+            None => continue,
+        };
+        let date = match text(balance, &["Dt", "Dt"]).or_else(|| text(balance, &["Dt", "DtTm"])) {
+            Some(date) => Some(parse_date(date)?),
+            None => None,
+        };
+        let currency = child(balance, "Amt").and_then(|it| it.attribute("Ccy"));
+        balances.insert(code, (signed_amount(balance)?, date, currency));
+    }
+
+    let currency = text(statement, &["Acct", "Ccy"])
+        .or_else(|| balances.values().find_map(|it| it.2))
+        .ok_or_else(|| anyhow!("Statement for {} has no currency", number))?
+        .to_string();
+
+    let mut import_ids = ImportIds::default();
+    let mut transactions = vec![];
+    for entry in children(statement, "Ntry") {
+        if let Some(transaction) = read_entry(entry, &mut import_ids)? {
+            transactions.push(transaction);
+        }
+    }
+
+    // Closing booked balance, or the interim one in intraday reports,
+    // otherwise the opening balance plus the booked entries
+    let closing = balances
+        .get("CLBD")
+        .or_else(|| balances.get("ITBD"))
+        .map(|(amount, date, _)| (*amount, *date));
+    let (balance, balance_date) = match closing {
+        Some((amount, date)) => (Some(amount), date.map(|it| it.and_hms(0, 0, 0))),
+        None => match balances.get("OPBD").or_else(|| balances.get("PRCD")) {
+            Some((opening, date, _)) => (
+                Some(
+                    opening
+                        + transactions
+                            .iter()
+                            .filter(|it| it.state == TransactionState::Cleared)
+                            .map(|it| it.amount)
+                            .sum::<i64>(),
+                ),
+                date.map(|it| it.and_hms(0, 0, 0)),
+            ),
+            None => (None, None),
+        },
+    };
+
+    Ok(Statement {
+        account: crate::Account {
+            account_id: format!("camt:{}", number),
+            currency,
+            display_name: text(statement, &["Acct", "Nm"])
+                .unwrap_or(number)
+                .to_string(),
+            ty: AccountType::Account,
+            balance,
+            balances: crate::Balances {
+                available: balances
+                    .get("CLAV")
+                    .or_else(|| balances.get("ITAV"))
+                    .map(|it| it.0),
+                ..Default::default()
+            },
+            identifiers: AccountIdentifiers {
+                iban: iban.map(|it| it.to_string()),
+                account_number: text(statement, &["Acct", "Id", "Othr", "Id"])
+                    .map(|it| it.to_string()),
+                ..Default::default()
+            },
+        },
+        balance_date,
+        transactions,
+    })
+}
+
+fn read_entry(entry: Node, import_ids: &mut ImportIds) -> Result<Option<crate::Transaction>> {
+    let status = text(entry, &["Sts", "Cd"]).or_else(|| text(entry, &["Sts"]));
+    match status {
+        // Booked entries usually have a different reference, so pending ones
+        // would be imported twice
+        Some("PDNG") => return Ok(None),
+        // Informational entries aren't on the account
+        Some("INFO") => return Ok(None),
+        _ => {}
+    }
+
+    // Reversals are booked in the opposite direction, so CdtDbtInd is still right
+    let amount = signed_amount(entry)?;
+    let date = ["BookgDt", "ValDt"]
+        .iter()
+        .find_map(|name| text(entry, &[name, "Dt"]).or_else(|| text(entry, &[name, "DtTm"])))
+        .ok_or_else(|| anyhow!("Entry has no booking or value date"))?;
+    let date = parse_date(date)?;
+
+    // Batched entries have several transaction details, so only describe the
+    // counterparty when there's just one
+    let details: Vec<Node> = entry
+        .children()
+        .filter(|it| it.has_tag_name("NtryDtls"))
+        .flat_map(|it| children(it, "TxDtls"))
+        .collect();
+    let single = if details.len() == 1 {
+        Some(details[0])
+    } else {
+        None
+    };
+
+    let counterparty = if amount < 0 { "Cdtr" } else { "Dbtr" };
+    let payee_name = single.and_then(|it| {
+        text(it, &["RltdPties", counterparty, "Nm"])
+            .or_else(|| text(it, &["RltdPties", counterparty, "Pty", "Nm"]))
+            .or_else(|| {
+                text(
+                    it,
+                    &["RltdPties", &format!("{}Acct", counterparty), "Id", "IBAN"],
+                )
+            })
+    });
+
+    let remittance: Vec<&str> = single
+        .and_then(|it| child(it, "RmtInf"))
+        .map(|it| {
+            children(it, "Ustrd")
+                .filter_map(|it| it.text())
+                .map(str::trim)
+                .collect()
+        })
+        .unwrap_or_default();
+    let description = if remittance.is_empty() {
+        text(entry, &["AddtlNtryInf"]).unwrap_or("").to_string()
+    } else {
+        remittance.join(" ")
+    };
+
+    let reference = text(entry, &["AcctSvcrRef"])
+        .or_else(|| text(entry, &["NtryRef"]))
+        .or_else(|| single.and_then(|it| text(it, &["Refs", "AcctSvcrRef"])));
+    let transaction_id = match reference {
+        Some(reference) => reference.to_string(),
+        None => {
+            // Set by the payer, so only used to tell transactions apart
+            let end_to_end_id = single
+                .and_then(|it| text(it, &["Refs", "EndToEndId"]))
+                .filter(|it| *it != "NOTPROVIDED");
+            import_ids.next_hashed(
+                IMPORT_ID_PREFIX,
+                date,
+                amount,
+                &format!(
+                    "{} {} {}",
+                    payee_name.unwrap_or(""),
+                    description,
+                    end_to_end_id.unwrap_or("")
+                ),
+            )
+        }
+    };
+
+    Ok(Some(crate::Transaction {
+        transaction_id,
+        timestamp: date.and_hms(0, 0, 0),
+        amount,
+        description,
+        payee_name: payee_name.map(|it| it.to_string()),
+        category: None,
+        state: TransactionState::Cleared,
+        running_balance: None,
+        splits: vec![],
+        exchanged: None,
+    }))
+}
+
+/// Reads `Amt` and `CdtDbtInd`, which says whether it's money in or out
+fn signed_amount(node: Node) -> Result<i64> {
+    let amount = text(node, &["Amt"]).ok_or_else(|| anyhow!("Missing amount"))?;
+    let amount: f64 = amount
+        .parse()
+        .with_context(|| format!("Invalid amount {}", amount))?;
+    let amount = (amount * 1000.0).round() as i64;
+    Ok(match text(node, &["CdtDbtInd"]) {
+        Some("DBIT") => -amount,
+        _ => amount,
+    })
+}
+
+fn parse_date(value: &str) -> Result<UtcDate> {
+    let date = value
+        .get(..10)
+        .and_then(|it| NaiveDate::parse_from_str(it, "%Y-%m-%d").ok())
+        .ok_or_else(|| anyhow!("Invalid date {}", value))?;
+    Ok(Utc.from_utc_date(&date))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::TransactionState;
+
+    const CAMT_053: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.053.001.02">
+  <BkToCstmrStmt>
+    <GrpHdr><MsgId>1</MsgId><CreDtTm>2021-03-05T18:00:00</CreDtTm></GrpHdr>
+    <Stmt>
+      <Id>1</Id>
+      <Acct><Id><IBAN>DE89370400440532013000</IBAN></Id><Ccy>EUR</Ccy></Acct>
+      <Bal>
+        <Tp><CdOrPrtry><Cd>OPBD</Cd></CdOrPrtry></Tp>
+        <Amt Ccy="EUR">100.00</Amt><CdtDbtInd>CRDT</CdtDbtInd><Dt><Dt>2021-03-04</Dt></Dt>
+      </Bal>
+      <Bal>
+        <Tp><CdOrPrtry><Cd>CLBD</Cd></CdOrPrtry></Tp>
+        <Amt Ccy="EUR">1087.50</Amt><CdtDbtInd>CRDT</CdtDbtInd><Dt><Dt>2021-03-05</Dt></Dt>
+      </Bal>
+      <Ntry>
+        <Amt Ccy="EUR">12.50</Amt><CdtDbtInd>DBIT</CdtDbtInd><Sts>BOOK</Sts>
+        <BookgDt><Dt>2021-03-04</Dt></BookgDt><ValDt><Dt>2021-03-03</Dt></ValDt>
+        <AcctSvcrRef>REF-1</AcctSvcrRef>
+        <NtryDtls><TxDtls>
+          <Refs><EndToEndId>NOTPROVIDED</EndToEndId></Refs>
+          <RltdPties><Cdtr><Nm>Bakery</Nm></Cdtr></RltdPties>
+          <RmtInf><Ustrd>Bread</Ustrd><Ustrd>and cake</Ustrd></RmtInf>
+        </TxDtls></NtryDtls>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">1000.00</Amt><CdtDbtInd>CRDT</CdtDbtInd><Sts><Cd>BOOK</Cd></Sts>
+        <BookgDt><DtTm>2021-03-05T09:00:00</DtTm></BookgDt>
+        <NtryDtls><TxDtls>
+          <Refs><EndToEndId>SALARY-03</EndToEndId></Refs>
+          <RltdPties><Dbtr><Pty><Nm>Employer</Nm></Pty></Dbtr></RltdPties>
+        </TxDtls></NtryDtls>
+        <AddtlNtryInf>Salary</AddtlNtryInf>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">5.00</Amt><CdtDbtInd>DBIT</CdtDbtInd><Sts>PDNG</Sts>
+        <ValDt><Dt>2021-03-06</Dt></ValDt>
+        <NtryRef>PENDING-1</NtryRef>
+      </Ntry>
+    </Stmt>
+  </BkToCstmrStmt>
+</Document>
+"#;
+
+    #[test]
+    fn camt_053() -> Result<()> {
+        let provider = crate::files::combine_statements("test", super::read_statements(CAMT_053)?);
+
+        let account = &provider.accounts[0];
+        assert_eq!("camt:DE89370400440532013000", account.account_id);
+        assert_eq!("EUR", account.currency);
+        assert_eq!(Some(1087500), account.balance);
+
+        let transactions: Vec<_> = provider
+            .transactions
+            .iter()
+            .map(|(_, it)| {
+                (
+                    it.transaction_id.as_str(),
+                    it.amount,
+                    it.payee_name.as_deref(),
+                    it.description.as_str(),
+                    it.state,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "REF-1",
+                    -12500,
+                    Some("Bakery"),
+                    "Bread and cake",
+                    TransactionState::Cleared
+                ),
+                (
+                    transactions[1].0,
+                    1000000,
+                    Some("Employer"),
+                    "Salary",
+                    TransactionState::Cleared
+                ),
+            ],
+            transactions
+        );
+        // The end-to-end id is the payer's, so isn't used on its own
+        assert!(transactions[1].0.starts_with("CAMT:1000000:2021-03-05:"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn repeated_end_to_end_id() -> Result<()> {
+        // e.g. a payer reusing its reference for monthly payments of the same amount
+        let entry = CAMT_053
+            .split("<Ntry>")
+            .nth(2)
+            .and_then(|it| it.split("</Ntry>").next())
+            .ok_or_else(|| anyhow!("No salary entry"))?;
+        let repeated = CAMT_053.replacen(
+            "<Ntry>",
+            &format!(
+                "<Ntry>{}</Ntry><Ntry>",
+                entry.replace("2021-03-05", "2021-02-05")
+            ),
+            1,
+        );
+        let provider = crate::files::combine_statements("test", super::read_statements(&repeated)?);
+
+        let ids: Vec<_> = provider
+            .transactions
+            .iter()
+            .map(|(_, it)| it.transaction_id.as_str())
+            .collect();
+        assert_eq!(3, ids.len());
+        assert_ne!(ids[0], ids[2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn balance_from_opening() -> Result<()> {
+        let without_closing = CAMT_053.replace("<Cd>CLBD</Cd>", "<Cd>XXXX</Cd>");
+        let provider =
+            crate::files::combine_statements("test", super::read_statements(&without_closing)?);
+        assert_eq!(Some(100000 - 12500 + 1000000), provider.accounts[0].balance);
+
+        Ok(())
+    }
+}
@@ -5,9 +5,10 @@ pub fn run() -> Result<()> {
 
     match args.command {
         SyncYnabCommands::Config(n) => config::handle(args.args, n),
-        SyncYnabCommands::Sync(_n) => {
-            crate::ynab::sync(&mut crate::config::load_config(args.args.config_directory)?)
-        }
+        SyncYnabCommands::Sync(n) => crate::ynab::sync(
+            &mut crate::config::load_config(args.args.config_directory)?,
+            n.from.map(|it| UtcDate::from_utc(it, chrono::Utc)),
+        ),
     }
 }
 
@@ -38,6 +39,7 @@ pub mod config {
     use crate::cli::SyncYnabArgs;
     use crate::config::*;
     use crate::prelude::*;
+    use oauth2::TokenResponse;
     use std::io::{stdin, BufRead};
 
     #[derive(StructOpt)]
@@ -45,7 +47,140 @@ pub mod config {
     pub enum ConfigCommands {
         TestProviders,
         TestYnab,
-        AddTruelayer,
+        AddTruelayer(OAuthArgs),
+        /// Renew the consent of an existing truelayer provider
+        ReauthTruelayer(ReauthArgs),
+        AddRevolutStatement(StatementArgs),
+        /// Import OFX or QFX statement files
+        AddOfx(StatementArgs),
+        /// Import QIF files
+        AddQif(QifArgs),
+        /// Import CSV files, using a profile from csv_profiles in ynab.json
+        AddCsv(CsvArgs),
+        /// Import ISO 20022 CAMT.053 statements or CAMT.052 reports
+        AddCamt(StatementArgs),
+        /// Import SWIFT MT940 statements
+        AddMt940(StatementArgs),
+        /// Connect to Monzo's own API, using a client from developers.monzo.com
+        AddMonzo(OAuthArgs),
+        /// Connect to Starling with a personal access token
+        AddStarling(StarlingArgs),
+        /// Connect to Wise with a read only API token
+        AddWise(WiseArgs),
+        /// Link an EU bank through GoCardless Bank Account Data
+        AddGocardless(GoCardlessArgs),
+        /// Claim a SimpleFIN Bridge setup token
+        AddSimplefin(SimpleFinArgs),
+        /// Encrypt the config files with a passphrase, or change the passphrase
+        Encrypt,
+        /// Store the config files as plain JSON again
+        Decrypt,
+    }
+
+    #[derive(StructOpt)]
+    #[structopt(rename_all = "kebab-case")]
+    pub struct StarlingArgs {
+        /// Name shown in the sync log
+        #[structopt(long, default_value = "Starling")]
+        pub display_name: String,
+    }
+
+    #[derive(StructOpt)]
+    #[structopt(rename_all = "kebab-case")]
+    pub struct WiseArgs {
+        /// Name shown in the sync log
+        #[structopt(long, default_value = "Wise")]
+        pub display_name: String,
+        /// Import a business profile's balances instead of the personal ones
+        #[structopt(long)]
+        pub profile_id: Option<i64>,
+    }
+
+    #[derive(StructOpt)]
+    #[structopt(rename_all = "kebab-case")]
+    pub struct GoCardlessArgs {
+        /// ISO 3166 country code, used to list the banks to choose from
+        #[structopt(long)]
+        pub country: String,
+        /// GoCardless institution ID, chosen from a list if not given
+        #[structopt(long)]
+        pub institution: Option<String>,
+        /// Name shown in the sync log
+        #[structopt(long)]
+        pub display_name: Option<String>,
+    }
+
+    #[derive(StructOpt)]
+    #[structopt(rename_all = "kebab-case")]
+    pub struct SimpleFinArgs {
+        /// Name shown in the sync log
+        #[structopt(long, default_value = "SimpleFIN")]
+        pub display_name: String,
+    }
+
+    #[derive(StructOpt)]
+    #[structopt(rename_all = "kebab-case")]
+    pub struct OAuthArgs {
+        /// Capture the redirect on http://localhost:<port>/ instead of pasting the code.
+        /// The URL must be registered as an allowed redirect URI with the provider.
+        #[structopt(long)]
+        pub listen_port: Option<u16>,
+    }
+
+    #[derive(StructOpt)]
+    #[structopt(rename_all = "kebab-case")]
+    pub struct ReauthArgs {
+        /// Index in providers.json, or display name of the provider
+        pub provider: String,
+        #[structopt(flatten)]
+        pub oauth: OAuthArgs,
+    }
+
+    #[derive(StructOpt)]
+    #[structopt(rename_all = "kebab-case")]
+    pub struct StatementArgs {
+        /// Statement file, or a directory containing statement files
+        pub path: std::path::PathBuf,
+        /// Name to show for this provider, defaults to the path
+        #[structopt(long)]
+        pub display_name: Option<String>,
+    }
+
+    #[derive(StructOpt)]
+    #[structopt(rename_all = "kebab-case")]
+    pub struct QifArgs {
+        #[structopt(flatten)]
+        pub statement: StatementArgs,
+        /// Currency of the account, which QIF files don't include
+        #[structopt(long)]
+        pub currency: String,
+        /// Order of dates in the file: dmy, mdy, ymd or auto
+        #[structopt(long, default_value = "auto")]
+        pub date_format: crate::qif::DateFormat,
+    }
+
+    #[derive(StructOpt)]
+    #[structopt(rename_all = "kebab-case")]
+    pub struct CsvArgs {
+        #[structopt(flatten)]
+        pub statement: StatementArgs,
+        /// Name of the profile in csv_profiles
+        #[structopt(long)]
+        pub profile: String,
+        #[structopt(long)]
+        pub currency: String,
+    }
+
+    impl StatementArgs {
+        fn into_source(self) -> crate::files::Source {
+            let path = self.path;
+            crate::files::Source {
+                display_name: self
+                    .display_name
+                    .unwrap_or_else(|| path.display().to_string()),
+                path,
+            }
+        }
     }
 
     pub fn handle(args: SyncYnabArgs, command: ConfigCommands) -> Result<()> {
@@ -53,8 +188,6 @@ pub mod config {
 
         let mut config = crate::config::load_config(&args.config_directory)?;
 
-        use oauth2::TokenResponse;
-
         match command {
             ConfigCommands::TestProviders => {
                 let mut result = crate::load_connections(&mut config)?;
@@ -73,7 +206,7 @@ pub mod config {
                     "access_token for YNAB must be set in config"
                 );
 
-                let mut rc = crate::ynab::new_rest_client(&config.ynab_config.access_token);
+                let mut rc = crate::ynab::new_rest_client(config.ynab_config.access_token.secret());
                 println!(
                     "Getting accounts in YNAB budget_id: {}",
                     &config.ynab_config.budget_id
@@ -83,7 +216,7 @@ pub mod config {
                     crate::ynab::get_accounts(&mut rc, &config.ynab_config.budget_id)
                 );
             }
-            ConfigCommands::AddTruelayer => {
+            ConfigCommands::AddTruelayer(oauth) => {
                 if config.ynab_config.truelayer_client_id.is_empty() {
                     println!("Missing truelayer client ID. Enter truelayer client ID:");
                     config.ynab_config.truelayer_client_id = read_line()?.trim().to_string();
@@ -91,48 +224,369 @@ pub mod config {
 
                 if config.ynab_config.truelayer_client_secret.is_empty() {
                     println!("Missing truelayer client secret. Enter truelayer client secret:");
-                    config.ynab_config.truelayer_client_secret = read_line()?.trim().to_string();
+                    config.ynab_config.truelayer_client_secret =
+                        read_line()?.trim().to_string().into();
                 }
 
-                println!(
-                    "Please authenticate at:\n{}",
-                    crate::truelayer::get_auth_url(&config.ynab_config)?
-                );
+                let mut token = authorize_truelayer(&config, &oauth)?;
 
-                println!("Enter code:\n");
-                for line in stdin().lock().lines() {
-                    let line = line?;
-                    let line = line.trim();
-
-                    if !line.is_empty() {
-                        let token = crate::truelayer::authorize(&config.ynab_config, line)?;
-
-                        let mut token = crate::truelayer::Token {
-                            display_name: "unknown".to_string(),
-                            access_token: token.access_token().clone(),
-                            access_token_expiry: crate::truelayer::calculate_expiry_time(
-                                token.expires_in().unwrap(),
-                            ),
-                            refresh_token: token.refresh_token().unwrap().clone(),
-                        };
+                let (_refresh, result) =
+                    crate::truelayer::initialize(&config.ynab_config, &mut token);
+                result?;
+                println!("Connected");
 
-                        let (_refresh, result) =
-                            crate::truelayer::initialize(&config.ynab_config, &mut token);
-                        result?;
-                        println!("Connected");
+                config.providers.push(Provider::Truelayer(token));
 
-                        config.providers.push(Provider::Truelayer(token));
+                crate::config::save_config(&args.config_directory, &config)?;
+                print_backfill_hint();
+            }
+            ConfigCommands::ReauthTruelayer(reauth) => {
+                let idx = find_provider(&config, &reauth.provider, |it| {
+                    matches!(it, Provider::Truelayer(_))
+                })?;
+                let authorized = authorize_truelayer(&config, &reauth.oauth)?;
 
-                        break;
+                let reconnected = match &mut config.providers[idx] {
+                    Provider::Truelayer(token) => {
+                        token.replace_credentials(authorized);
+                        let (_refresh, result) =
+                            crate::truelayer::initialize(&config.ynab_config, token);
+                        Some(result.map(|_| token.display_name.clone()))
                     }
+                    _ => None,
+                };
+
+                // Saved even if the connection check failed, so the new token isn't lost
+                crate::config::save_config(&args.config_directory, &config)?;
+                if let Some(reconnected) = reconnected {
+                    println!("Reconnected {}", reconnected?);
+                }
+                print_backfill_hint();
+            }
+            ConfigCommands::AddRevolutStatement(statement) => {
+                let source = statement.into_source();
+                let provider = load_statements(source, crate::revolut::statement::initialize)?;
+                config.providers.push(Provider::RevolutStatement(provider));
+                crate::config::save_config(&args.config_directory, &config)?;
+            }
+            ConfigCommands::AddQif(qif) => {
+                let statement = qif.statement.into_source();
+                let source = crate::qif::Source {
+                    display_name: statement.display_name,
+                    path: statement.path,
+                    currency: qif.currency.to_ascii_uppercase(),
+                    date_format: qif.date_format,
+                };
+                let source = load_statements(source, crate::qif::initialize)?;
+                config.providers.push(Provider::Qif(source));
+                crate::config::save_config(&args.config_directory, &config)?;
+            }
+            ConfigCommands::AddCsv(csv) => {
+                let statement = csv.statement.into_source();
+                let source = crate::csv_file::Source {
+                    display_name: statement.display_name,
+                    path: statement.path,
+                    currency: csv.currency.to_ascii_uppercase(),
+                    profile: csv.profile,
+                };
+                let ynab_config = &config.ynab_config;
+                let source = load_statements(source, |source| {
+                    crate::csv_file::initialize(ynab_config, source)
+                })?;
+                config.providers.push(Provider::Csv(source));
+                crate::config::save_config(&args.config_directory, &config)?;
+            }
+            ConfigCommands::AddCamt(statement) => {
+                let source = load_statements(statement.into_source(), crate::camt::initialize)?;
+                config.providers.push(Provider::Camt(source));
+                crate::config::save_config(&args.config_directory, &config)?;
+            }
+            ConfigCommands::AddMt940(statement) => {
+                let source = load_statements(statement.into_source(), crate::mt940::initialize)?;
+                config.providers.push(Provider::Mt940(source));
+                crate::config::save_config(&args.config_directory, &config)?;
+            }
+            ConfigCommands::AddMonzo(oauth) => {
+                if config.ynab_config.monzo_client_id.is_empty() {
+                    println!("Missing monzo client ID. Enter monzo client ID:");
+                    config.ynab_config.monzo_client_id = read_line()?.trim().to_string();
+                }
+
+                if config.ynab_config.monzo_client_secret.is_empty() {
+                    println!("Missing monzo client secret. Enter monzo client secret:");
+                    config.ynab_config.monzo_client_secret = read_line()?.trim().to_string().into();
                 }
 
+                let mut token = authorize_monzo(&config, &oauth)?;
+
+                println!("Approve access in the Monzo app, then press enter");
+                read_line()?;
+                let (_refresh, result) = crate::monzo::initialize(&config.ynab_config, &mut token);
+                result?;
+                println!("Connected");
+
+                config.providers.push(Provider::Monzo(token));
+
+                crate::config::save_config(&args.config_directory, &config)?;
+                print_backfill_hint();
+            }
+            ConfigCommands::AddStarling(starling) => {
+                let access_token = rpassword::prompt_password("Starling personal access token: ")?;
+                let mut token = crate::starling::Token {
+                    display_name: starling.display_name,
+                    access_token: access_token.trim().to_string().into(),
+                    history_days: crate::provider::DEFAULT_HISTORY_DAYS,
+                    category_map: Default::default(),
+                };
+
+                let (_refresh, result) = crate::starling::initialize(&mut token);
+                result?;
+                println!("Connected");
+
+                config.providers.push(Provider::Starling(token));
+
+                crate::config::save_config(&args.config_directory, &config)?;
+            }
+            ConfigCommands::AddWise(wise) => {
+                let api_token = rpassword::prompt_password("Wise API token: ")?;
+                let mut token = crate::wise::Token {
+                    display_name: wise.display_name,
+                    api_token: api_token.trim().to_string().into(),
+                    profile_id: wise.profile_id,
+                    history_days: crate::provider::DEFAULT_HISTORY_DAYS,
+                    category_map: Default::default(),
+                };
+
+                let (_refresh, result) = crate::wise::initialize(&mut token);
+                result?;
+                println!("Connected");
+
+                config.providers.push(Provider::Wise(token));
+
+                crate::config::save_config(&args.config_directory, &config)?;
+            }
+            ConfigCommands::AddGocardless(gocardless) => {
+                if config.ynab_config.gocardless_secret_id.is_empty() {
+                    println!("Missing gocardless secret ID. Enter gocardless secret ID:");
+                    config.ynab_config.gocardless_secret_id = read_line()?.trim().to_string();
+                }
+
+                if config.ynab_config.gocardless_secret_key.is_empty() {
+                    println!("Missing gocardless secret key. Enter gocardless secret key:");
+                    config.ynab_config.gocardless_secret_key =
+                        read_line()?.trim().to_string().into();
+                }
+
+                let institution = match gocardless.institution {
+                    Some(institution) => institution,
+                    None => {
+                        let institutions = crate::gocardless::institutions(
+                            &config.ynab_config,
+                            &gocardless.country,
+                        )?;
+                        for institution in institutions {
+                            println!("{}\t{}", institution.id, institution.name);
+                        }
+                        println!("Enter institution ID:");
+                        read_code()?
+                    }
+                };
+
+                let (link, mut token) = crate::gocardless::create_requisition(
+                    &config.ynab_config,
+                    &institution,
+                    gocardless.display_name.as_deref().unwrap_or(&institution),
+                    crate::gocardless::DEFAULT_REDIRECT_URL,
+                )?;
+                println!("Please give consent at:\n{}", link);
+                println!("Press enter once you've been redirected");
+                read_line()?;
+                crate::gocardless::link_accounts(&config.ynab_config, &mut token)?;
+
+                let (_refresh, result) =
+                    crate::gocardless::initialize(&config.ynab_config, &mut token);
+                result?;
+                println!("Connected");
+
+                config.providers.push(Provider::GoCardless(token));
+
+                crate::config::save_config(&args.config_directory, &config)?;
+                print_backfill_hint();
+            }
+            ConfigCommands::AddSimplefin(simplefin) => {
+                let setup_token = rpassword::prompt_password("SimpleFIN setup token: ")?;
+                let mut token = crate::simplefin::Token {
+                    display_name: simplefin.display_name,
+                    access_url: crate::simplefin::claim(&setup_token)?,
+                    history_days: crate::provider::DEFAULT_HISTORY_DAYS,
+                };
+
+                let (_refresh, result) = crate::simplefin::initialize(&mut token);
+                let mut provider = result?;
+                // Setup tokens only work once, so keep the access URL even if listing fails
+                config.providers.push(Provider::SimpleFin(token));
+                crate::config::save_config(&args.config_directory, &config)?;
+
+                for account in provider.get_accounts()? {
+                    println!("Found {} ({})", account.display_name, account.account_id);
+                }
+            }
+            ConfigCommands::AddOfx(statement) => {
+                let source = load_statements(statement.into_source(), crate::ofx::initialize)?;
+                config.providers.push(Provider::Ofx(source));
+                crate::config::save_config(&args.config_directory, &config)?;
+            }
+            ConfigCommands::Encrypt => {
+                // The new passphrase would be the one the config was just decrypted with
+                ensure!(
+                    config.passphrase.is_none() || !crate::encryption::Passphrase::is_in_env(),
+                    "To change the passphrase, unset {} and {} so the new one is prompted for, \
+                     then set them to the new passphrase",
+                    crate::encryption::PASSPHRASE_ENV,
+                    crate::encryption::KEY_FILE_ENV
+                );
+                config.passphrase = Some(crate::encryption::Passphrase::obtain_new()?);
+                crate::config::save_config(&args.config_directory, &config)?;
+                println!("Config encrypted");
+            }
+            ConfigCommands::Decrypt => {
+                ensure!(config.passphrase.is_some(), "Config is not encrypted");
+                config.passphrase = None;
                 crate::config::save_config(&args.config_directory, &config)?;
+                println!("Config decrypted. Backups remain encrypted.");
             }
         }
         Ok(())
     }
 
+    /// Runs the Truelayer OAuth flow, either capturing the redirect on a local
+    /// port or asking for the code shown on Truelayer's redirect page.
+    fn authorize_truelayer(config: &Config, oauth: &OAuthArgs) -> Result<crate::truelayer::Token> {
+        let redirect_url = match oauth.listen_port {
+            Some(port) => crate::redirect_listener::redirect_url(port),
+            None => crate::truelayer::CONSOLE_REDIRECT_URL.to_string(),
+        };
+        let (url, state) = crate::truelayer::get_auth_url(&config.ynab_config, &redirect_url)?;
+        println!("Please authenticate at:\n{}", url);
+
+        let code = match oauth.listen_port {
+            Some(port) => crate::redirect_listener::receive_code(port, &state)?,
+            None => {
+                println!("Enter code:\n");
+                read_code()?
+            }
+        };
+
+        let token = crate::truelayer::authorize(&config.ynab_config, &code, &redirect_url)?;
+        Ok(crate::truelayer::Token {
+            display_name: "unknown".to_string(),
+            access_token: token.access_token().clone(),
+            access_token_expiry: crate::truelayer::calculate_expiry_time(
+                token.expires_in().unwrap(),
+            ),
+            refresh_token: token.refresh_token().unwrap().clone(),
+            consent_expiry: None,
+            history_days: crate::provider::DEFAULT_HISTORY_DAYS,
+            request_window_days: crate::truelayer::DEFAULT_REQUEST_WINDOW_DAYS,
+            category_map: Default::default(),
+            stable_import_ids: true,
+            balance_source: Default::default(),
+            invert_balance: false,
+        })
+    }
+
+    /// Runs the Monzo OAuth flow, either capturing the redirect on a local
+    /// port or asking for the code from the address bar after the redirect.
+    fn authorize_monzo(config: &Config, oauth: &OAuthArgs) -> Result<crate::monzo::Token> {
+        let redirect_url = match oauth.listen_port {
+            Some(port) => crate::redirect_listener::redirect_url(port),
+            None => crate::monzo::DEFAULT_REDIRECT_URL.to_string(),
+        };
+        let (url, state) = crate::monzo::get_auth_url(&config.ynab_config, &redirect_url)?;
+        println!("Please authenticate at:\n{}", url);
+
+        let code = match oauth.listen_port {
+            Some(port) => crate::redirect_listener::receive_code(port, &state)?,
+            None => {
+                println!("Enter the code parameter from the URL you're redirected to:\n");
+                read_code()?
+            }
+        };
+
+        crate::monzo::authorize(&config.ynab_config, &code, &redirect_url)
+    }
+
+    /// Finds a provider by its index in providers.json, or by display name.
+    fn find_provider(
+        config: &Config,
+        selector: &str,
+        filter: impl Fn(&Provider) -> bool,
+    ) -> Result<usize> {
+        if let Ok(idx) = selector.parse::<usize>() {
+            return match config.providers.get(idx) {
+                Some(provider) if filter(provider) => Ok(idx),
+                Some(_) => Err(anyhow!("Provider {} is not the right type", idx)),
+                None => Err(anyhow!("No provider at index {}", idx)),
+            };
+        }
+
+        let matching: Vec<usize> = config
+            .providers
+            .iter()
+            .enumerate()
+            .filter(|(_, it)| filter(it))
+            .filter(|(_, it)| {
+                it.display_name()
+                    .to_lowercase()
+                    .contains(&selector.to_lowercase())
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+        match matching[..] {
+            [idx] => Ok(idx),
+            [] => Err(anyhow!("No provider matching {}", selector)),
+            _ => Err(anyhow!(
+                "{} matches more than one provider, use its index instead",
+                selector
+            )),
+        }
+    }
+
+    /// Checks the statements at a new source can be read before it's added
+    fn load_statements<S>(
+        mut source: S,
+        initialize: impl FnOnce(&mut S) -> (bool, Result<Box<dyn crate::ConnectedProvider>>),
+    ) -> Result<S> {
+        let (_refresh, result) = initialize(&mut source);
+        for account in result?.get_accounts()? {
+            println!(
+                "Found account {} with ACCOUNT_ID=\"{}\"",
+                account.display_name, account.account_id
+            );
+        }
+        Ok(source)
+    }
+
+    fn print_backfill_hint() {
+        println!(
+            "Many banks only allow fetching full history shortly after consent is given. \
+             Run `sync --from YYYY-MM-DD` now to backfill older transactions."
+        );
+    }
+
+    fn read_code() -> Result<String> {
+        for line in stdin().lock().lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if !line.is_empty() {
+                return Ok(line.to_string());
+            }
+        }
+
+        Err(anyhow!("No code entered"))
+    }
+
     fn read_line() -> Result<String> {
         stdin()
             .lock()
@@ -151,6 +605,9 @@ pub mod sync {
     pub struct SyncArgs {
         //        #[structopt(subcommand)]
         //        command: SyncCommands
+        /// Backfill transactions from this date (YYYY-MM-DD), where the provider allows it
+        #[structopt(long)]
+        pub from: Option<chrono::NaiveDate>,
     }
 
     //    #[derive(StructOpt)]
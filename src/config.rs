@@ -1,7 +1,10 @@
 pub const DEFAULT_PATH: &str = "secrets/";
 
+use crate::encryption::Passphrase;
 use crate::prelude::*;
+use crate::secret::Secret;
 use std::fs::{File, OpenOptions};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -9,6 +12,9 @@ pub struct Config {
     pub path: PathBuf,
     pub providers: Vec<Provider>,
     pub ynab_config: YnabConfig,
+    /// Set if the config files are encrypted at rest
+    #[serde(skip)]
+    pub passphrase: Option<Passphrase>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,71 +22,168 @@ pub struct YnabConfig {
     #[serde(default)]
     pub budget_id: String,
     #[serde(default)]
-    pub access_token: String,
+    pub access_token: Secret,
     #[serde(default)]
     pub truelayer_client_id: String,
     #[serde(default)]
-    pub truelayer_client_secret: String,
+    pub truelayer_client_secret: Secret,
+    #[serde(default)]
+    pub truelayer: crate::truelayer::Settings,
+    #[serde(default)]
+    pub monzo_client_id: String,
+    #[serde(default)]
+    pub monzo_client_secret: Secret,
+    #[serde(default)]
+    pub gocardless_secret_id: String,
+    #[serde(default)]
+    pub gocardless_secret_key: Secret,
+    /// Create scheduled transactions for standing orders and direct debits
+    /// missing from YNAB, instead of only listing them
+    #[serde(default)]
+    pub create_scheduled_transactions: bool,
+    /// Column layouts of bank CSV exports, by name
+    #[serde(default)]
+    pub csv_profiles: HashMap<String, crate::csv_file::Profile>,
 }
 
 impl Default for YnabConfig {
     fn default() -> Self {
         YnabConfig {
             budget_id: "default".to_string(),
-            access_token: "".to_string(),
+            access_token: Secret::default(),
             truelayer_client_id: "".to_string(),
-            truelayer_client_secret: "".to_string(),
+            truelayer_client_secret: Secret::default(),
+            truelayer: Default::default(),
+            monzo_client_id: "".to_string(),
+            monzo_client_secret: Secret::default(),
+            gocardless_secret_id: "".to_string(),
+            gocardless_secret_key: Secret::default(),
+            create_scheduled_transactions: false,
+            csv_profiles: HashMap::new(),
         }
     }
 }
 
+/// Maps provider specific category names or merchant category codes to YNAB
+/// category names.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(transparent)]
+pub struct CategoryMap(pub HashMap<String, String>);
+
+impl CategoryMap {
+    /// Returns the YNAB category for the first key which has a mapping.
+    /// Keys are compared ignoring case.
+    pub fn lookup<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Option<String> {
+        keys.into_iter().find_map(|key| {
+            self.0
+                .iter()
+                .find(|(it, _)| it.eq_ignore_ascii_case(key))
+                .map(|(_, category)| category.clone())
+        })
+    }
+}
+
+/// Turns a provider's category code such as `eating_out` or `EATING_OUT`
+/// into `Eating out`, for categories without a mapping.
+pub fn category_name(code: &str) -> Option<String> {
+    let name = code.to_lowercase().replace('_', " ");
+    let mut chars = name.trim().chars();
+    let first = chars.next()?;
+    Some(first.to_uppercase().chain(chars).collect())
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Provider {
     Truelayer(crate::truelayer::Token),
     Revolut(crate::revolut::Token),
+    RevolutStatement(crate::revolut::statement::Source),
+    Ofx(crate::files::Source),
+    Qif(crate::qif::Source),
+    Csv(crate::csv_file::Source),
+    Camt(crate::files::Source),
+    Mt940(crate::files::Source),
+    Monzo(crate::monzo::Token),
+    Starling(crate::starling::Token),
+    Wise(crate::wise::Token),
+    GoCardless(crate::gocardless::Token),
+    SimpleFin(crate::simplefin::Token),
+}
+
+impl Provider {
+    pub fn display_name(&self) -> &str {
+        match self {
+            Provider::Truelayer(token) => &token.display_name,
+            Provider::Revolut(token) => &token.display_name,
+            Provider::RevolutStatement(source) => &source.display_name,
+            Provider::Ofx(source) => &source.display_name,
+            Provider::Qif(source) => &source.display_name,
+            Provider::Csv(source) => &source.display_name,
+            Provider::Camt(source) => &source.display_name,
+            Provider::Mt940(source) => &source.display_name,
+            Provider::Monzo(token) => &token.display_name,
+            Provider::Starling(token) => &token.display_name,
+            Provider::Wise(token) => &token.display_name,
+            Provider::GoCardless(token) => &token.display_name,
+            Provider::SimpleFin(token) => &token.display_name,
+        }
+    }
 }
 
 pub fn load_config(path: impl Into<PathBuf>) -> Result<Config> {
     let (path, providers, ynab) = config_paths(path);
-    let providers = load_or_default(&providers)?;
-    let ynab_config = load_or_default(&ynab)?;
+    let mut passphrase = None;
+    let providers = load_or_default(&providers, &mut passphrase)?;
+    let ynab_config = load_or_default(&ynab, &mut passphrase)?;
 
     Ok(Config {
         path,
         providers,
         ynab_config,
+        passphrase,
     })
 }
 
 pub fn save_config(path: impl Into<PathBuf>, cfg: &Config) -> Result<()> {
     let (_path, providers, ynab) = config_paths(path);
+    let passphrase = cfg.passphrase.as_ref();
 
-    make_backup(&providers)?;
-    save_json(&providers, &cfg.providers)?;
+    make_backup(&providers, passphrase)?;
+    save_json(&providers, &cfg.providers, passphrase)?;
 
-    make_backup(&ynab)?;
-    save_json(&ynab, &cfg.ynab_config)?;
+    make_backup(&ynab, passphrase)?;
+    save_json(&ynab, &cfg.ynab_config, passphrase)?;
 
     Ok(())
 }
 
-fn save_json<T>(path: &Path, t: &T) -> Result<()>
+fn save_json<T>(path: &Path, t: &T, passphrase: Option<&Passphrase>) -> Result<()>
 where
     T: serde::Serialize,
 {
+    let mut data = serde_json::to_vec_pretty(t)?;
+    if let Some(passphrase) = passphrase {
+        data = crate::encryption::encrypt(passphrase, &data)?;
+    }
+
+    write_synced(path, &data)
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> Result<()> {
     match path.parent() {
         None => {}
         Some(parent) => std::fs::create_dir_all(parent)?,
     }
 
     let mut file: File = File::create(path)?;
-    serde_json::to_writer_pretty(&mut file, t)?;
+    file.write_all(data)?;
     file.sync_all()?;
 
     Ok(())
 }
 
-fn load_or_default<'a, T>(path: &Path) -> Result<T>
+/// Loads a config file, decrypting it if needed.
+/// The passphrase is requested the first time an encrypted file is found.
+fn load_or_default<'a, T>(path: &Path, passphrase: &mut Option<Passphrase>) -> Result<T>
 where
     for<'de> T: Deserialize<'de> + 'a,
     T: Default,
@@ -94,7 +197,16 @@ where
         return Ok(Default::default());
     }
 
-    Ok(serde_json::from_reader(File::open(path)?)?)
+    let mut data = std::fs::read(path)?;
+    if crate::encryption::is_encrypted(&data) {
+        if passphrase.is_none() {
+            *passphrase = Some(Passphrase::obtain()?);
+        }
+        data = crate::encryption::decrypt(passphrase.as_ref().unwrap(), &data)
+            .with_context(|| format!("Error decrypting {}", path.display()))?;
+    }
+
+    Ok(serde_json::from_slice(&data)?)
 }
 
 fn config_paths(path: impl Into<PathBuf>) -> (PathBuf, PathBuf, PathBuf) {
@@ -108,17 +220,32 @@ fn config_paths(path: impl Into<PathBuf>) -> (PathBuf, PathBuf, PathBuf) {
     (path, providers, ynab)
 }
 
-fn make_backup(path: &Path) -> Result<()> {
+/// Copies the current file to a `.json.bak` backup before it is overwritten.
+/// When saving encrypted, a plaintext original is encrypted on the way so no
+/// plaintext copy of the config is left behind.
+fn make_backup(path: &Path, passphrase: Option<&Passphrase>) -> Result<()> {
     let bak: PathBuf = path.with_extension("json.bak");
 
-    if path.exists() {
-        std::fs::copy(&path, &bak)?;
-        OpenOptions::new()
-            .write(true)
-            .open(&bak)
-            .with_context(|| format!("Error opening {}", bak.display()))?
-            .sync_all()
-            .with_context(|| format!("Error calling sync_all on {}", bak.display()))?;
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let data = std::fs::read(path)?;
+    match passphrase {
+        Some(passphrase) if !crate::encryption::is_encrypted(&data) => {
+            let data = crate::encryption::encrypt(passphrase, &data)?;
+            write_synced(&bak, &data)
+                .with_context(|| format!("Error writing {}", bak.display()))?;
+        }
+        _ => {
+            std::fs::copy(path, &bak)?;
+            OpenOptions::new()
+                .write(true)
+                .open(&bak)
+                .with_context(|| format!("Error opening {}", bak.display()))?
+                .sync_all()
+                .with_context(|| format!("Error calling sync_all on {}", bak.display()))?;
+        }
     }
 
     Ok(())
@@ -135,4 +262,26 @@ pub mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn category_map() {
+        let map = super::CategoryMap(
+            vec![
+                ("5411".to_string(), "Groceries".to_string()),
+                ("restaurants".to_string(), "Eating Out".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        assert_eq!(
+            Some("Groceries".to_string()),
+            map.lookup(vec!["5411", "restaurants"])
+        );
+        assert_eq!(
+            Some("Eating Out".to_string()),
+            map.lookup(vec!["5812", "Restaurants"])
+        );
+        assert_eq!(None, map.lookup(vec!["5812"]));
+    }
 }
@@ -0,0 +1,435 @@
+//! Reads CSV exports from any bank, using a profile from `csv_profiles` in
+//! `ynab.json` which says how its columns are laid out.
+
+use crate::config::YnabConfig;
+use crate::files::StatementProvider;
+use crate::import_id::ImportIds;
+use crate::prelude::*;
+use crate::{AccountType, ConnectedProvider, TransactionState};
+use anyhow::{bail, ensure};
+use chrono::{NaiveDate, NaiveDateTime, TimeZone, Utc};
+use std::path::PathBuf;
+
+const IMPORT_ID_PREFIX: &str = "CSV";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Source {
+    pub display_name: String,
+    /// A CSV file, or a directory of them
+    pub path: PathBuf,
+    pub currency: String,
+    /// Name of the profile in `csv_profiles`
+    pub profile: String,
+}
+
+/// How to read one bank's CSV exports
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    /// Rows before the column names, such as account details. Blank lines
+    /// aren't counted.
+    #[serde(default)]
+    pub skip_rows: usize,
+    /// Whether there's a row of column names, otherwise columns are
+    /// referred to by their index from 0
+    #[serde(default = "default_has_headers")]
+    pub has_headers: bool,
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+    /// e.g. `windows-1252`, defaults to UTF-8
+    #[serde(default)]
+    pub encoding: Option<String>,
+    pub date: Column,
+    /// chrono format, e.g. `%d/%m/%Y`
+    pub date_format: String,
+    /// A signed amount, negative for money out
+    #[serde(default)]
+    pub amount: Option<Column>,
+    /// Money out, used with `credit` instead of `amount`
+    #[serde(default)]
+    pub debit: Option<Column>,
+    /// Money in, used with `debit` instead of `amount`
+    #[serde(default)]
+    pub credit: Option<Column>,
+    #[serde(default = "default_decimal_separator")]
+    pub decimal_separator: char,
+    #[serde(default)]
+    pub thousands_separator: Option<char>,
+    #[serde(default)]
+    pub payee: Option<Column>,
+    #[serde(default)]
+    pub description: Option<Column>,
+    /// Balance after each transaction. Without it the account isn't reconciled.
+    #[serde(default)]
+    pub balance: Option<Column>,
+}
+
+fn default_has_headers() -> bool {
+    true
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_decimal_separator() -> char {
+    '.'
+}
+
+/// A column name, or its index from 0
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Column {
+    Index(usize),
+    Name(String),
+}
+
+impl Column {
+    fn index(&self, headers: Option<&csv::StringRecord>) -> Result<usize> {
+        match (self, headers) {
+            (Column::Index(index), _) => Ok(*index),
+            (Column::Name(name), Some(headers)) => headers
+                .iter()
+                .position(|it| it.trim().eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow!("No column named {}", name)),
+            (Column::Name(name), None) => {
+                bail!("Column {} must be an index as there are no headers", name)
+            }
+        }
+    }
+}
+
+pub fn initialize(
+    ynab_config: &YnabConfig,
+    source: &mut Source,
+) -> (bool, Result<Box<dyn ConnectedProvider>>) {
+    (
+        false,
+        load(ynab_config, source).map(|it| Box::new(it) as Box<dyn ConnectedProvider>),
+    )
+}
+
+/// A transaction as written in the file, before dates are interpreted
+#[derive(Debug, PartialEq)]
+struct Row {
+    date: String,
+    amount: i64,
+    payee: Option<String>,
+    description: String,
+    balance: Option<i64>,
+}
+
+fn load(ynab_config: &YnabConfig, source: &Source) -> Result<StatementProvider> {
+    let profile = ynab_config
+        .csv_profiles
+        .get(&source.profile)
+        .ok_or_else(|| anyhow!("No CSV profile named {} in ynab.json", source.profile))?;
+    // The csv reader only splits on a single byte
+    ensure!(
+        profile.delimiter.is_ascii(),
+        "CSV profile {} has delimiter {}, which must be an ASCII character",
+        source.profile,
+        profile.delimiter
+    );
+
+    let mut rows = vec![];
+    for file in crate::files::statement_files(&source.path, &["csv"])? {
+        let data =
+            std::fs::read(&file).with_context(|| format!("Error reading {}", file.display()))?;
+        let file_rows =
+            read_rows(profile, &data).with_context(|| format!("Error in {}", file.display()))?;
+        crate::files::merge_overlapping(&mut rows, file_rows);
+    }
+
+    convert(source, profile, rows)
+}
+
+fn read_rows(profile: &Profile, data: &[u8]) -> Result<Vec<Row>> {
+    let text = match &profile.encoding {
+        Some(label) => {
+            let encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+                .ok_or_else(|| anyhow!("Unknown encoding {}", label))?;
+            encoding.decode(data).0
+        }
+        None => String::from_utf8_lossy(data),
+    };
+    let text = text.trim_start_matches('\u{feff}');
+
+    // Headers are read by hand, as they come after the skipped rows
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(profile.delimiter as u8)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+    let mut records = reader.records().skip(profile.skip_rows);
+    let headers = if profile.has_headers {
+        Some(records.next().ok_or_else(|| {
+            anyhow!("No column names after skipping {} rows", profile.skip_rows)
+        })??)
+    } else {
+        None
+    };
+    let column = |column: &Option<Column>| -> Result<Option<usize>> {
+        column
+            .as_ref()
+            .map(|it| it.index(headers.as_ref()))
+            .transpose()
+    };
+
+    let date = profile.date.index(headers.as_ref())?;
+    let amount = column(&profile.amount)?;
+    let (debit, credit) = (column(&profile.debit)?, column(&profile.credit)?);
+    ensure!(
+        amount.is_some() || debit.is_some() || credit.is_some(),
+        "Profile needs an amount column, or debit and credit columns"
+    );
+    let payee = column(&profile.payee)?;
+    let description = column(&profile.description)?;
+    let balance = column(&profile.balance)?;
+
+    let mut rows = vec![];
+    for record in records {
+        let record = record?;
+        let field = |index: Option<usize>| {
+            index
+                .and_then(|it| record.get(it))
+                .filter(|it| !it.is_empty())
+        };
+        let money = |index: Option<usize>| -> Result<Option<i64>> {
+            field(index).map(|it| parse_amount(profile, it)).transpose()
+        };
+
+        let date = match field(Some(date)) {
+            Some(date) => date.to_string(),
+            // Trailing totals or blank lines
+            None => continue,
+        };
+        let amount = match amount {
+            Some(_) => money(amount)?.unwrap_or(0),
+            None => money(credit)?.unwrap_or(0).abs() - money(debit)?.unwrap_or(0).abs(),
+        };
+        rows.push(Row {
+            date,
+            amount,
+            payee: field(payee).map(|it| it.to_string()),
+            description: field(description).unwrap_or("").to_string(),
+            balance: money(balance)?,
+        });
+    }
+
+    Ok(rows)
+}
+
+fn convert(source: &Source, profile: &Profile, rows: Vec<Row>) -> Result<StatementProvider> {
+    let mut rows = rows
+        .into_iter()
+        .map(|row| Ok((parse_date(&profile.date_format, &row.date)?, row)))
+        .collect::<Result<Vec<_>>>()?;
+    // Many banks list the newest transaction first
+    if rows.first().map(|it| it.0) > rows.last().map(|it| it.0) {
+        rows.reverse();
+    }
+    rows.sort_by_key(|(date, _)| *date);
+
+    // Not the display name, so renaming the source doesn't unlink it from YNAB
+    let account_id = format!("csv:{}:{}", source.profile, source.path.display());
+    let mut import_ids = ImportIds::default();
+    let transactions = rows
+        .iter()
+        .map(|(date, row)| {
+            (
+                account_id.clone(),
+                crate::Transaction {
+                    transaction_id: import_ids.next_hashed(
+                        IMPORT_ID_PREFIX,
+                        date.date(),
+                        row.amount,
+                        &format!("{} {}", row.payee.as_deref().unwrap_or(""), row.description),
+                    ),
+                    timestamp: *date,
+                    amount: row.amount,
+                    description: row.description.clone(),
+                    payee_name: row.payee.clone(),
+                    category: None,
+                    state: TransactionState::Cleared,
+                    running_balance: row.balance,
+                    splits: vec![],
+                    exchanged: None,
+                },
+            )
+        })
+        .collect();
+
+    Ok(StatementProvider {
+        display_name: source.display_name.clone(),
+        accounts: vec![crate::Account {
+            account_id,
+            currency: source.currency.clone(),
+            display_name: source.display_name.clone(),
+            ty: AccountType::Account,
+            balance: rows.last().and_then(|(_, row)| row.balance),
+            balances: Default::default(),
+            identifiers: Default::default(),
+        }],
+        transactions,
+    })
+}
+
+fn parse_date(format: &str, value: &str) -> Result<UtcDateTime> {
+    if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+        return Ok(Utc.from_utc_datetime(&date));
+    }
+    let date = NaiveDate::parse_from_str(value, format)
+        .with_context(|| format!("Date {} doesn't match {}", value, format))?;
+    Ok(Utc.from_utc_datetime(&date.and_hms(0, 0, 0)))
+}
+
+/// Parses amounts such as `-1,234.56`, `1.234,56-` or `(£12.50)`
+fn parse_amount(profile: &Profile, value: &str) -> Result<i64> {
+    let negative = value.contains('-') || (value.starts_with('(') && value.ends_with(')'));
+    let normalised: String = value
+        .chars()
+        .filter(|c| Some(*c) != profile.thousands_separator)
+        .map(|c| {
+            if c == profile.decimal_separator {
+                '.'
+            } else {
+                c
+            }
+        })
+        .filter(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    let amount: f64 = normalised
+        .parse()
+        .with_context(|| format!("Invalid amount {}", value))?;
+    let amount = (amount * 1000.0).round() as i64;
+    Ok(if negative { -amount } else { amount })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+
+    fn source() -> super::Source {
+        super::Source {
+            display_name: "Giro".to_string(),
+            path: "statements/giro".into(),
+            currency: "EUR".to_string(),
+            profile: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn debit_credit_columns() -> Result<()> {
+        let profile: super::Profile = serde_json::from_str(
+            r#"{
+                "skip_rows": 1,
+                "delimiter": ";",
+                "encoding": "windows-1252",
+                "date": "Datum",
+                "date_format": "%d.%m.%Y",
+                "debit": "Soll",
+                "credit": "Haben",
+                "decimal_separator": ",",
+                "thousands_separator": ".",
+                "payee": "Empfänger",
+                "description": 4,
+                "balance": "Saldo"
+            }"#,
+        )?;
+        let (data, _, _) = encoding_rs::WINDOWS_1252.encode(
+            "Konto 123\n\
+            Datum;Empfänger;Soll;Haben;Verwendungszweck;Saldo\n\
+            05.03.2021;Bäckerei;3,50;;Brötchen;1.996,50\n\
+            04.03.2021;Arbeitgeber;;2.000,00;Gehalt;2.000,00\n",
+        );
+        let rows = super::read_rows(&profile, &data)?;
+
+        let provider = super::convert(&source(), &profile, rows)?;
+
+        assert_eq!(Some(1996500), provider.accounts[0].balance);
+        let transactions: Vec<_> = provider
+            .transactions
+            .iter()
+            .map(|(_, it)| {
+                (
+                    it.amount,
+                    it.payee_name.as_deref().unwrap_or(""),
+                    it.description.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (2000000, "Arbeitgeber", "Gehalt"),
+                (-3500, "Bäckerei", "Brötchen")
+            ],
+            transactions
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn quoted_fields_and_balance() -> Result<()> {
+        let profile: super::Profile = serde_json::from_str(
+            r#"{
+                "skip_rows": 2,
+                "date": "Date",
+                "date_format": "%Y-%m-%d",
+                "amount": "Amount",
+                "payee": "Payee",
+                "description": "Memo",
+                "balance": "Balance"
+            }"#,
+        )?;
+        let data = "\
+\"Account\",\"Main, joint\"
+\"Exported\",\"2021-03-10\"
+
+Date,Payee,Memo,Amount,Balance
+2021-03-05,Shop,\"Line one
+line two\",-12.50,87.50
+2021-03-06,Cafe,,-3.00,84.50
+";
+        let rows = super::read_rows(&profile, data.as_bytes())?;
+        let provider = super::convert(&source(), &profile, rows)?;
+
+        let account = &provider.accounts[0];
+        assert_eq!("csv:test:statements/giro", account.account_id);
+        // From the last row, as the file lists the oldest first
+        assert_eq!(Some(84500), account.balance);
+
+        let transactions: Vec<_> = provider
+            .transactions
+            .iter()
+            .map(|(_, it)| (it.amount, it.description.as_str(), it.running_balance))
+            .collect();
+        assert_eq!(
+            vec![
+                (-12500, "Line one\nline two", Some(87500)),
+                (-3000, "", Some(84500))
+            ],
+            transactions
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn non_ascii_delimiter() -> Result<()> {
+        let profile: super::Profile = serde_json::from_str(
+            r#"{"delimiter": "§", "date": 0, "date_format": "%d.%m.%Y", "amount": 1}"#,
+        )?;
+        let mut ynab_config = crate::config::YnabConfig::default();
+        ynab_config.csv_profiles.insert("test".to_string(), profile);
+
+        let error = super::load(&ynab_config, &source())
+            .err()
+            .ok_or_else(|| anyhow!("Loaded a profile with a non-ASCII delimiter"))?
+            .to_string();
+        assert!(error.contains("CSV profile test"), "{}", error);
+
+        Ok(())
+    }
+}
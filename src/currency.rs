@@ -34,7 +34,7 @@ pub fn load_currency_converter() -> Result<CurrencyConverter> {
 
         rates.insert(
             date,
-            (&records[1..])
+            records[1..]
                 .iter()
                 .map(|it| it.parse::<Rate>().unwrap_or(0.0))
                 .collect(),
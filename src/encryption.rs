@@ -0,0 +1,147 @@
+//! Optional encryption of config files at rest.
+//!
+//! Encrypted files start with [`MAGIC`], followed by the argon2 salt, the
+//! nonce and the ChaCha20Poly1305 ciphertext.
+
+use crate::prelude::*;
+use anyhow::ensure;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::path::Path;
+
+const MAGIC: &[u8] = b"import-ynab-encrypted-v1\n";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Passphrase used to encrypt config files, if set.
+pub const PASSPHRASE_ENV: &str = "IMPORT_YNAB_PASSPHRASE";
+/// Path to a file whose contents are used as the passphrase, if set.
+pub const KEY_FILE_ENV: &str = "IMPORT_YNAB_KEY_FILE";
+
+pub struct Passphrase(Vec<u8>);
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(fmt, "Passphrase([redacted])")
+    }
+}
+
+impl Passphrase {
+    /// Reads the passphrase from the environment, or prompts for it if
+    /// neither [`PASSPHRASE_ENV`] nor [`KEY_FILE_ENV`] are set.
+    pub fn obtain() -> Result<Passphrase> {
+        if let Some(passphrase) = Self::from_env()? {
+            return Ok(passphrase);
+        }
+
+        let passphrase = rpassword::prompt_password("Config passphrase: ")?;
+        ensure!(!passphrase.is_empty(), "Passphrase must not be empty");
+        Ok(Passphrase(passphrase.into_bytes()))
+    }
+
+    /// Prompts for a new passphrase twice, unless one is set in the environment.
+    pub fn obtain_new() -> Result<Passphrase> {
+        if let Some(passphrase) = Self::from_env()? {
+            return Ok(passphrase);
+        }
+
+        let passphrase = Self::obtain()?;
+        let confirmation = rpassword::prompt_password("Confirm passphrase: ")?;
+        ensure!(
+            passphrase.0 == confirmation.as_bytes(),
+            "Passphrases did not match"
+        );
+        Ok(passphrase)
+    }
+
+    /// Whether the passphrase is taken from the environment rather than prompted for.
+    pub fn is_in_env() -> bool {
+        std::env::var_os(PASSPHRASE_ENV).is_some() || std::env::var_os(KEY_FILE_ENV).is_some()
+    }
+
+    fn from_env() -> Result<Option<Passphrase>> {
+        if let Some(passphrase) = std::env::var_os(PASSPHRASE_ENV) {
+            let passphrase = passphrase
+                .into_string()
+                .map_err(|_| anyhow!("{} is not valid unicode", PASSPHRASE_ENV))?;
+            return Ok(Some(Passphrase(passphrase.into_bytes())));
+        }
+
+        if let Some(path) = std::env::var_os(KEY_FILE_ENV) {
+            let path: &Path = path.as_ref();
+            let key = std::fs::read(path)
+                .with_context(|| format!("Error reading key file {}", path.display()))?;
+            let key = key.trim_ascii_end().to_vec();
+            ensure!(!key.is_empty(), "Key file {} is empty", path.display());
+            return Ok(Some(Passphrase(key)));
+        }
+
+        Ok(None)
+    }
+
+    fn derive_key(&self, salt: &[u8]) -> Result<Key> {
+        let mut key = Key::default();
+        argon2::Argon2::default()
+            .hash_password_into(&self.0, salt, &mut key)
+            .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+        Ok(key)
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn encrypt(passphrase: &Passphrase, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let cipher = ChaCha20Poly1305::new(&passphrase.derive_key(&salt)?);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow!("Encryption failed"))?;
+
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+pub fn decrypt(passphrase: &Passphrase, data: &[u8]) -> Result<Vec<u8>> {
+    ensure!(is_encrypted(data), "Data is not encrypted");
+    let data = &data[MAGIC.len()..];
+    ensure!(
+        data.len() > SALT_LEN + NONCE_LEN,
+        "Encrypted data is truncated"
+    );
+    let (salt, data) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+    let cipher = ChaCha20Poly1305::new(&passphrase.derive_key(salt)?);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Decryption failed, is the passphrase correct?"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let passphrase = Passphrase(b"correct horse".to_vec());
+        let encrypted = encrypt(&passphrase, b"{\"secret\": true}")?;
+
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(
+            b"{\"secret\": true}".to_vec(),
+            decrypt(&passphrase, &encrypted)?
+        );
+        assert!(decrypt(&Passphrase(b"wrong".to_vec()), &encrypted).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use crate::prelude::*;
+use crate::ConnectedProvider;
+use anyhow::ensure;
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+
+/// Config for providers which read downloaded statement files
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Source {
+    pub display_name: String,
+    /// A statement file, or a directory of them
+    pub path: PathBuf,
+}
+
+/// Accounts and transactions read from statement files
+pub struct StatementProvider {
+    pub display_name: String,
+    pub accounts: Vec<crate::Account>,
+    /// Transactions with the `account_id` of the account they belong to
+    pub transactions: Vec<(String, crate::Transaction)>,
+}
+
+impl Debug for StatementProvider {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "StatementProvider: {} with {} accounts",
+            self.display_name,
+            self.accounts.len()
+        )
+    }
+}
+
+impl ConnectedProvider for StatementProvider {
+    fn get_accounts(&mut self) -> Result<Vec<crate::Account>> {
+        Ok(self.accounts.clone())
+    }
+
+    fn get_transactions(&mut self, acc: &crate::Account) -> Result<Vec<crate::Transaction>> {
+        Ok(self
+            .transactions
+            .iter()
+            .filter(|(account_id, _)| account_id == &acc.account_id)
+            .map(|(_, tran)| tran.clone())
+            .collect())
+    }
+}
+
+/// Lists the statement files at `path`.
+/// `path` may be a single file, or a directory in which case every file with
+/// one of the given extensions is returned in name order.
+pub fn statement_files(path: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        ensure!(path.exists(), "Statement file {} not found", path.display());
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = vec![];
+    for entry in std::fs::read_dir(path)
+        .with_context(|| format!("Error reading directory {}", path.display()))?
+    {
+        let file = entry?.path();
+        let matches = file
+            .extension()
+            .and_then(|it| it.to_str())
+            .is_some_and(|ext| extensions.iter().any(|it| it.eq_ignore_ascii_case(ext)));
+        if file.is_file() && matches {
+            files.push(file);
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+/// A statement for one account. A file may contain several.
+#[derive(Debug)]
+pub struct Statement {
+    pub account: crate::Account,
+    /// When the balance was reported, so the newest statement's is used
+    pub balance_date: Option<UtcDateTime>,
+    pub transactions: Vec<crate::Transaction>,
+}
+
+/// Combines statements which may overlap, using the newest balance for each
+/// account and skipping transactions with IDs which were already read.
+/// A statement without a balance never replaces the account read from
+/// another, which only CAMT and MT940 files can have, as OFX requires one.
+pub fn combine_statements(display_name: &str, statements: Vec<Statement>) -> StatementProvider {
+    let mut provider = StatementProvider {
+        display_name: display_name.to_string(),
+        accounts: vec![],
+        transactions: vec![],
+    };
+    let mut balance_dates: HashMap<String, Option<UtcDateTime>> = HashMap::new();
+
+    for statement in statements {
+        let account_id = statement.account.account_id.clone();
+        match provider
+            .accounts
+            .iter_mut()
+            .find(|it| it.account_id == account_id)
+        {
+            Some(account) => {
+                if statement.account.balance.is_some()
+                    && statement.balance_date >= balance_dates[&account_id]
+                {
+                    *account = statement.account;
+                    balance_dates.insert(account_id.clone(), statement.balance_date);
+                }
+            }
+            None => {
+                provider.accounts.push(statement.account);
+                balance_dates.insert(account_id.clone(), statement.balance_date);
+            }
+        }
+
+        for transaction in statement.transactions {
+            if !provider.transactions.iter().any(|(id, it)| {
+                id == &account_id && it.transaction_id == transaction.transaction_id
+            }) {
+                provider
+                    .transactions
+                    .push((account_id.clone(), transaction));
+            }
+        }
+    }
+
+    provider
+}
+
+/// Adds the records read from another file, skipping ones already read from
+/// an overlapping file. Identical records within one file are all kept, as
+/// statements without IDs can't tell repeated transactions apart.
+pub fn merge_overlapping<T: PartialEq>(records: &mut Vec<T>, file_records: Vec<T>) {
+    let mut added = vec![];
+    for (index, record) in file_records.iter().enumerate() {
+        let occurrence = file_records[..=index]
+            .iter()
+            .filter(|it| *it == record)
+            .count();
+        if records.iter().filter(|it| *it == record).count() < occurrence {
+            added.push(index);
+        }
+    }
+    records.extend(
+        file_records
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| added.contains(index))
+            .map(|(_, record)| record),
+    );
+}
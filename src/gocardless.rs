@@ -0,0 +1,801 @@
+//! GoCardless Bank Account Data, formerly Nordigen, which covers banks
+//! across the EU. Access is consented per bank through a requisition link,
+//! and API tokens come from the `gocardless_secret_id` and
+//! `gocardless_secret_key` in `ynab.json`.
+
+use crate::config::{CategoryMap, YnabConfig};
+use crate::import_id::ImportIds;
+use crate::prelude::*;
+use crate::provider::{debug_as_display_name, History};
+use crate::secret::Secret;
+use crate::{AccountIdentifiers, AccountType, TransactionState};
+use anyhow::ensure;
+use api::*;
+use chrono::Utc;
+use restson::Response;
+
+const API_URL: &str = "https://bankaccountdata.gocardless.com/";
+/// Where the bank sends the browser after consent. Nothing needs to listen
+/// there, the requisition is checked once the user says they're done.
+pub const DEFAULT_REDIRECT_URL: &str = "http://localhost/";
+/// How long consent is asked for, the most every bank supports
+const ACCESS_VALID_FOR_DAYS: i64 = 90;
+/// Warn about consent expiring this many days in advance
+const CONSENT_EXPIRY_WARNING_DAYS: i64 = 14;
+/// Prefix for import IDs generated from the transaction details
+const HASHED_ID_PREFIX: &str = "GC";
+
+struct GoCardlessProvider {
+    display_name: String,
+    rest_client: RestClient,
+    accounts: Vec<String>,
+    history: History,
+    category_map: CategoryMap,
+}
+
+debug_as_display_name!(GoCardlessProvider);
+
+impl crate::ConnectedProvider for GoCardlessProvider {
+    fn get_accounts(&mut self) -> Result<Vec<crate::Account>> {
+        let mut converted = vec![];
+        for id in &self.accounts {
+            let details: Response<AccountDetailsResponse> = self.rest_client.get(id.as_str())?;
+            let balances: Response<BalancesResponse> = self.rest_client.get(id.as_str())?;
+            converted.push(convert_account(
+                id,
+                details.into_inner().account,
+                &balances.into_inner().balances,
+            )?);
+        }
+
+        Ok(converted)
+    }
+
+    fn get_transactions(&mut self, acc: &crate::Account) -> Result<Vec<crate::Transaction>> {
+        let from = self.history.from().format("%Y-%m-%d").to_string();
+
+        let transactions: Response<TransactionsResponse> = self
+            .rest_client
+            .get_with(acc.account_id.as_str(), &[("date_from", from.as_str())])?;
+
+        convert_transactions(transactions.into_inner().transactions, &self.category_map)
+    }
+
+    fn set_history_start(&mut self, from: UtcDate) {
+        self.history.set_start(from);
+    }
+
+    fn pending_ids_change(&self, _acc: &crate::Account) -> bool {
+        // Many banks give pending transactions no ID, so they're hashed, and
+        // booking them adds the bank's ID
+        true
+    }
+}
+
+fn milliunits(amount: &str) -> Result<i64> {
+    let amount: f64 = amount
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid amount {}", amount))?;
+    Ok((amount * 1000.0).round() as i64)
+}
+
+fn convert_account(
+    id: &str,
+    details: AccountDetails,
+    balances: &[Balance],
+) -> Result<crate::Account> {
+    let find = |types: &[&str]| {
+        types.iter().find_map(|ty| {
+            balances
+                .iter()
+                .find(|it| it.balance_type.eq_ignore_ascii_case(ty))
+        })
+    };
+    // Pending transactions are imported, so prefer a balance which includes them
+    let balance = find(&["expected", "interimBooked", "closingBooked"])
+        .or_else(|| balances.first())
+        .ok_or_else(|| anyhow!("No balances reported for {}", id))?;
+    let available = find(&["interimAvailable", "closingAvailable"])
+        .map(|it| milliunits(&it.balance_amount.amount))
+        .transpose()?;
+
+    Ok(crate::Account {
+        account_id: id.to_string(),
+        currency: details
+            .currency
+            .unwrap_or_else(|| balance.balance_amount.currency.clone()),
+        display_name: details
+            .name
+            .or(details.product)
+            .or(details.owner_name)
+            .unwrap_or_else(|| id.to_string()),
+        ty: AccountType::Account,
+        balance: Some(milliunits(&balance.balance_amount.amount)?),
+        balances: crate::Balances {
+            available,
+            ..Default::default()
+        },
+        identifiers: AccountIdentifiers {
+            iban: details.iban,
+            ..Default::default()
+        },
+    })
+}
+
+fn convert_transactions(
+    transactions: TransactionLists,
+    category_map: &CategoryMap,
+) -> Result<Vec<crate::Transaction>> {
+    let mut converted = vec![];
+    for (list, state) in [
+        (transactions.booked, TransactionState::Cleared),
+        (transactions.pending, TransactionState::Pending),
+    ] {
+        let mut list = list
+            .into_iter()
+            .map(|it| Ok((transaction_time(&it)?, it)))
+            .collect::<Result<Vec<_>>>()?;
+        // Stable sort, so same day transactions keep the order the bank lists them in
+        list.sort_by_key(|(time, _)| *time);
+
+        let mut import_ids = ImportIds::default();
+        for (timestamp, transaction) in list {
+            converted.push(convert_transaction(
+                transaction,
+                timestamp,
+                state,
+                &mut import_ids,
+                category_map,
+            )?);
+        }
+    }
+
+    Ok(converted)
+}
+
+fn transaction_time(transaction: &Transaction) -> Result<UtcDateTime> {
+    if let Some(time) = &transaction.booking_date_time {
+        if let Ok(time) = chrono::DateTime::parse_from_rfc3339(time) {
+            return Ok(time.with_timezone(&Utc));
+        }
+    }
+    let date = transaction
+        .booking_date
+        .as_deref()
+        .or(transaction.value_date.as_deref())
+        .ok_or_else(|| anyhow!("No date for transaction {:?}", transaction))?;
+    let date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date {}", date))?;
+    Ok(chrono::TimeZone::from_utc_datetime(
+        &Utc,
+        &date.and_hms(0, 0, 0),
+    ))
+}
+
+fn convert_transaction(
+    transaction: Transaction,
+    timestamp: UtcDateTime,
+    state: TransactionState,
+    import_ids: &mut ImportIds,
+    category_map: &CategoryMap,
+) -> Result<crate::Transaction> {
+    let amount = milliunits(&transaction.transaction_amount.amount)?;
+    let description = transaction
+        .remittance_information_unstructured
+        .clone()
+        .or_else(|| {
+            transaction
+                .remittance_information_unstructured_array
+                .as_ref()
+                .map(|it| it.join(" "))
+        })
+        .or_else(|| transaction.remittance_information_structured.clone())
+        .or_else(|| transaction.additional_information.clone())
+        .unwrap_or_default();
+    let counterparty = if amount < 0 {
+        &transaction.creditor_name
+    } else {
+        &transaction.debtor_name
+    };
+
+    let transaction_id = match transaction
+        .transaction_id
+        .as_deref()
+        .or(transaction.internal_transaction_id.as_deref())
+        .filter(|it| !it.is_empty())
+    {
+        Some(id) => crate::import_id::fit(id),
+        None => import_ids.next_hashed(
+            HASHED_ID_PREFIX,
+            timestamp.date(),
+            amount,
+            &format!("{} {}", counterparty.as_deref().unwrap_or(""), description),
+        ),
+    };
+
+    let category = category_map.lookup(
+        transaction
+            .merchant_category_code
+            .iter()
+            .chain(transaction.proprietary_bank_transaction_code.iter())
+            .map(String::as_str),
+    );
+
+    Ok(crate::Transaction {
+        transaction_id,
+        timestamp,
+        amount,
+        payee_name: counterparty.clone().or_else(|| Some(description.clone())),
+        description,
+        category,
+        state,
+        running_balance: transaction
+            .balance_after_transaction
+            .map(|it| milliunits(&it.balance_amount.amount))
+            .transpose()?,
+        splits: vec![],
+        exchanged: None,
+    })
+}
+
+pub fn initialize(
+    ynab_config: &YnabConfig,
+    token: &mut Token,
+) -> (bool, Result<Box<dyn crate::ConnectedProvider>>) {
+    warn_consent_expiry(token);
+    let (refreshed, access_token) = match refresh(ynab_config, token) {
+        Ok((refreshed, token)) => (refreshed, token),
+        Err(e) => return (false, Err(e)),
+    };
+    let rest_client = match crate::provider::rest_client(API_URL, Some(access_token.secret())) {
+        Ok(rest_client) => rest_client,
+        Err(e) => return (refreshed, Err(e)),
+    };
+
+    let provider = GoCardlessProvider {
+        display_name: token.display_name.clone(),
+        rest_client,
+        accounts: token.accounts.clone(),
+        history: History::new(token.history_days),
+        category_map: token.category_map.clone(),
+    };
+    (refreshed, Ok(Box::new(provider)))
+}
+
+fn warn_consent_expiry(token: &Token) {
+    let remaining = token.consent_expiry - Utc::now();
+    if remaining <= chrono::Duration::zero() {
+        eprintln!(
+            "GoCardless consent for {} expired at {}. Run `config add-gocardless` to link it again.",
+            token.display_name, token.consent_expiry
+        );
+    } else if remaining < chrono::Duration::days(CONSENT_EXPIRY_WARNING_DAYS) {
+        eprintln!(
+            "GoCardless consent for {} expires in {} days at {}. Run `config add-gocardless` to link it again.",
+            token.display_name,
+            remaining.num_days(),
+            token.consent_expiry
+        );
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Token {
+    pub display_name: String,
+    pub institution_id: String,
+    pub requisition_id: String,
+    /// Accounts the user gave access to
+    pub accounts: Vec<String>,
+    pub access_token: Secret,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub access_token_expiry: UtcDateTime,
+    pub refresh_token: Secret,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub refresh_token_expiry: UtcDateTime,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub consent_expiry: UtcDateTime,
+    /// Days of transactions fetched on each sync, at most the
+    /// `max_historical_days` of the bank's agreement
+    #[serde(default = "crate::provider::default_history_days")]
+    pub history_days: i64,
+    /// Merchant category codes or bank transaction codes to YNAB category names
+    #[serde(default)]
+    pub category_map: CategoryMap,
+}
+
+fn expiry_time(seconds: i64) -> UtcDateTime {
+    Utc::now() + chrono::Duration::seconds(seconds)
+}
+
+/// Gets new tokens with the secret ID and key
+fn new_tokens(config: &YnabConfig) -> Result<NewTokenResponse> {
+    ensure!(
+        !config.gocardless_secret_id.is_empty() && !config.gocardless_secret_key.is_empty(),
+        "Missing gocardless_secret_id or gocardless_secret_key in ynab.json"
+    );
+    let rest_client = crate::provider::rest_client(API_URL, None)?;
+    let response: Response<NewTokenResponse> = rest_client
+        .post_capture(
+            (),
+            &NewTokenRequest {
+                secret_id: config.gocardless_secret_id.clone(),
+                secret_key: config.gocardless_secret_key.secret().to_string(),
+            },
+        )
+        .context("Failed to get gocardless token")?;
+    Ok(response.into_inner())
+}
+
+pub fn refresh(ynab_config: &YnabConfig, token: &mut Token) -> Result<(bool, Secret)> {
+    let now = Utc::now();
+
+    if now < token.access_token_expiry {
+        return Ok((false, token.access_token.clone()));
+    }
+
+    if now < token.refresh_token_expiry {
+        let rest_client = crate::provider::rest_client(API_URL, None)?;
+        let response: Response<RefreshTokenResponse> = rest_client
+            .post_capture(
+                (),
+                &RefreshTokenRequest {
+                    refresh: token.refresh_token.secret().to_string(),
+                },
+            )
+            .context("Error refreshing gocardless token")?;
+        let response = response.into_inner();
+        token.access_token = response.access.into();
+        token.access_token_expiry = expiry_time(response.access_expires);
+    } else {
+        let response = new_tokens(ynab_config)?;
+        token.access_token = response.access.into();
+        token.access_token_expiry = expiry_time(response.access_expires);
+        token.refresh_token = response.refresh.into();
+        token.refresh_token_expiry = expiry_time(response.refresh_expires);
+    }
+
+    Ok((true, token.access_token.clone()))
+}
+
+/// Banks which can be linked in a country, by ISO 3166 code
+pub fn institutions(config: &YnabConfig, country: &str) -> Result<Vec<Institution>> {
+    let tokens = new_tokens(config)?;
+    let rest_client = crate::provider::rest_client(API_URL, Some(&tokens.access))?;
+    let institutions: Response<InstitutionsResponse> =
+        rest_client.get_with((), &[("country", country)])?;
+    Ok(institutions.into_inner().0)
+}
+
+/// Creates an agreement and requisition for the bank, returning the link
+/// where the user gives consent, and the token to finish linking with
+/// [`link_accounts`] once they have.
+pub fn create_requisition(
+    config: &YnabConfig,
+    institution_id: &str,
+    display_name: &str,
+    redirect_url: &str,
+) -> Result<(String, Token)> {
+    let tokens = new_tokens(config)?;
+    let access_token: Secret = tokens.access.into();
+    let rest_client = crate::provider::rest_client(API_URL, Some(access_token.secret()))?;
+
+    let institution: Response<Institution> = rest_client.get(institution_id)?;
+    let max_historical_days = institution
+        .into_inner()
+        .transaction_total_days
+        .and_then(|it| it.parse::<i64>().ok())
+        .unwrap_or(crate::provider::DEFAULT_HISTORY_DAYS);
+
+    let agreement: Response<Agreement> = rest_client.post_capture(
+        (),
+        &NewAgreement {
+            institution_id: institution_id.to_string(),
+            max_historical_days,
+            access_valid_for_days: ACCESS_VALID_FOR_DAYS,
+            access_scope: vec![
+                "balances".to_string(),
+                "details".to_string(),
+                "transactions".to_string(),
+            ],
+        },
+    )?;
+    let requisition: Response<Requisition> = rest_client.post_capture(
+        (),
+        &NewRequisition {
+            redirect: redirect_url.to_string(),
+            institution_id: institution_id.to_string(),
+            agreement: agreement.into_inner().id,
+        },
+    )?;
+    let requisition = requisition.into_inner();
+
+    Ok((
+        requisition.link,
+        Token {
+            display_name: display_name.to_string(),
+            institution_id: institution_id.to_string(),
+            requisition_id: requisition.id,
+            accounts: vec![],
+            access_token,
+            access_token_expiry: expiry_time(tokens.access_expires),
+            refresh_token: tokens.refresh.into(),
+            refresh_token_expiry: expiry_time(tokens.refresh_expires),
+            consent_expiry: expiry_time(ACCESS_VALID_FOR_DAYS * 24 * 60 * 60),
+            history_days: max_historical_days.min(crate::provider::DEFAULT_HISTORY_DAYS),
+            category_map: Default::default(),
+        },
+    ))
+}
+
+/// Stores the accounts the user gave access to, once the requisition is linked
+pub fn link_accounts(config: &YnabConfig, token: &mut Token) -> Result<()> {
+    let (_refreshed, access_token) = refresh(config, token)?;
+    let rest_client = crate::provider::rest_client(API_URL, Some(access_token.secret()))?;
+    let requisition: Response<Requisition> = rest_client.get(token.requisition_id.as_str())?;
+    let requisition = requisition.into_inner();
+
+    // LN is linked, anything else means consent wasn't given (yet)
+    ensure!(
+        requisition.status == "LN",
+        "Requisition status is {}, consent hasn't been given",
+        requisition.status
+    );
+    ensure!(!requisition.accounts.is_empty(), "No accounts were shared");
+    token.accounts = requisition.accounts;
+    Ok(())
+}
+
+pub mod api {
+    use crate::prelude::*;
+
+    // https://developer.gocardless.com/bank-account-data/endpoints
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct NewTokenRequest {
+        pub secret_id: String,
+        pub secret_key: String,
+    }
+
+    impl RestPath<()> for NewTokenRequest {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("api/v2/token/new/".to_string())
+        }
+    }
+
+    /// Expiry times are in seconds
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct NewTokenResponse {
+        pub access: String,
+        pub access_expires: i64,
+        pub refresh: String,
+        pub refresh_expires: i64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct RefreshTokenRequest {
+        pub refresh: String,
+    }
+
+    impl RestPath<()> for RefreshTokenRequest {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("api/v2/token/refresh/".to_string())
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct RefreshTokenResponse {
+        pub access: String,
+        pub access_expires: i64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Institution {
+        pub id: String,
+        pub name: String,
+        /// Days of history the bank gives, sent as a string
+        #[serde(default, deserialize_with = "crate::serialisation::deserialize_code")]
+        pub transaction_total_days: Option<String>,
+    }
+
+    impl RestPath<&str> for Institution {
+        fn get_path(id: &str) -> Result<String, restson::Error> {
+            Ok(format!("api/v2/institutions/{}/", id))
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(transparent)]
+    pub struct InstitutionsResponse(pub Vec<Institution>);
+
+    impl RestPath<()> for InstitutionsResponse {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("api/v2/institutions/".to_string())
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct NewAgreement {
+        pub institution_id: String,
+        pub max_historical_days: i64,
+        pub access_valid_for_days: i64,
+        pub access_scope: Vec<String>,
+    }
+
+    impl RestPath<()> for NewAgreement {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("api/v2/agreements/enduser/".to_string())
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Agreement {
+        pub id: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct NewRequisition {
+        pub redirect: String,
+        pub institution_id: String,
+        pub agreement: String,
+    }
+
+    impl RestPath<()> for NewRequisition {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("api/v2/requisitions/".to_string())
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Requisition {
+        pub id: String,
+        pub status: String,
+        pub link: String,
+        #[serde(default)]
+        pub accounts: Vec<String>,
+    }
+
+    impl RestPath<&str> for Requisition {
+        fn get_path(id: &str) -> Result<String, restson::Error> {
+            Ok(format!("api/v2/requisitions/{}/", id))
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct AccountDetails {
+        pub iban: Option<String>,
+        pub currency: Option<String>,
+        pub owner_name: Option<String>,
+        pub name: Option<String>,
+        pub product: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct AccountDetailsResponse {
+        pub account: AccountDetails,
+    }
+
+    impl RestPath<&str> for AccountDetailsResponse {
+        fn get_path(id: &str) -> Result<String, restson::Error> {
+            Ok(format!("api/v2/accounts/{}/details/", id))
+        }
+    }
+
+    /// Amounts are decimal strings, negative for money out
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Amount {
+        pub amount: String,
+        pub currency: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Balance {
+        pub balance_amount: Amount,
+        /// e.g. `closingBooked`, `expected` or `interimAvailable`
+        pub balance_type: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct BalancesResponse {
+        pub balances: Vec<Balance>,
+    }
+
+    impl RestPath<&str> for BalancesResponse {
+        fn get_path(id: &str) -> Result<String, restson::Error> {
+            Ok(format!("api/v2/accounts/{}/balances/", id))
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Transaction {
+        pub transaction_id: Option<String>,
+        pub internal_transaction_id: Option<String>,
+        pub booking_date: Option<String>,
+        pub booking_date_time: Option<String>,
+        pub value_date: Option<String>,
+        pub transaction_amount: Amount,
+        pub creditor_name: Option<String>,
+        pub debtor_name: Option<String>,
+        pub remittance_information_unstructured: Option<String>,
+        pub remittance_information_unstructured_array: Option<Vec<String>>,
+        pub remittance_information_structured: Option<String>,
+        pub additional_information: Option<String>,
+        pub balance_after_transaction: Option<Balance>,
+        pub merchant_category_code: Option<String>,
+        pub proprietary_bank_transaction_code: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct TransactionLists {
+        #[serde(default)]
+        pub booked: Vec<Transaction>,
+        #[serde(default)]
+        pub pending: Vec<Transaction>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct TransactionsResponse {
+        pub transactions: TransactionLists,
+    }
+
+    impl RestPath<&str> for TransactionsResponse {
+        fn get_path(id: &str) -> Result<String, restson::Error> {
+            Ok(format!("api/v2/accounts/{}/transactions/", id))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::TransactionState;
+
+    #[test]
+    fn booked_and_pending() -> Result<()> {
+        let response: super::TransactionsResponse = serde_json::from_str(
+            r#"{"transactions": {
+                "booked": [
+                    {
+                        "transactionId": "2021030100001",
+                        "bookingDate": "2021-03-01",
+                        "transactionAmount": {"amount": "-12.50", "currency": "EUR"},
+                        "creditorName": "Bäckerei Müller",
+                        "remittanceInformationUnstructured": "Brötchen",
+                        "balanceAfterTransaction": {
+                            "balanceAmount": {"amount": "987.50", "currency": "EUR"},
+                            "balanceType": "interimBooked"
+                        },
+                        "merchantCategoryCode": "5462"
+                    },
+                    {
+                        "internalTransactionId": "b1c2d3",
+                        "bookingDateTime": "2021-02-28T09:00:00Z",
+                        "transactionAmount": {"amount": "2000.00", "currency": "EUR"},
+                        "debtorName": "Arbeitgeber GmbH",
+                        "remittanceInformationUnstructuredArray": ["Gehalt", "Februar"]
+                    }
+                ],
+                "pending": [
+                    {
+                        "bookingDate": "2021-03-02",
+                        "transactionAmount": {"amount": "-4.20", "currency": "EUR"},
+                        "remittanceInformationUnstructured": "KARTE Café"
+                    }
+                ]
+            }}"#,
+        )?;
+        let category_map = crate::config::CategoryMap(
+            vec![("5462".to_string(), "Groceries".to_string())]
+                .into_iter()
+                .collect(),
+        );
+
+        let transactions = super::convert_transactions(response.transactions, &category_map)?;
+        let converted: Vec<_> = transactions
+            .iter()
+            .map(|it| {
+                (
+                    it.transaction_id.as_str(),
+                    it.amount,
+                    it.payee_name.as_deref().unwrap_or(""),
+                    it.description.as_str(),
+                    it.state,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "b1c2d3",
+                    2000000,
+                    "Arbeitgeber GmbH",
+                    "Gehalt Februar",
+                    TransactionState::Cleared
+                ),
+                (
+                    "2021030100001",
+                    -12500,
+                    "Bäckerei Müller",
+                    "Brötchen",
+                    TransactionState::Cleared
+                ),
+                (
+                    transactions[2].transaction_id.as_str(),
+                    -4200,
+                    "KARTE Café",
+                    "KARTE Café",
+                    TransactionState::Pending
+                ),
+            ],
+            converted
+        );
+        assert!(transactions[2]
+            .transaction_id
+            .starts_with("GC:-4200:2021-03-02:"));
+        assert_eq!(Some("Groceries"), transactions[1].category.as_deref());
+        assert_eq!(Some(987500), transactions[1].running_balance);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pending_booked_under_new_id() -> Result<()> {
+        use crate::ConnectedProvider;
+
+        let pending: super::TransactionsResponse = serde_json::from_str(
+            r#"{"transactions": {
+                "pending": [
+                    {
+                        "valueDate": "2021-03-02",
+                        "transactionAmount": {"amount": "-4.20", "currency": "EUR"},
+                        "remittanceInformationUnstructured": "KARTE Café"
+                    }
+                ]
+            }}"#,
+        )?;
+        let booked: super::TransactionsResponse = serde_json::from_str(
+            r#"{"transactions": {
+                "booked": [
+                    {
+                        "transactionId": "2021030300002",
+                        "bookingDate": "2021-03-03",
+                        "valueDate": "2021-03-02",
+                        "transactionAmount": {"amount": "-4.20", "currency": "EUR"},
+                        "remittanceInformationUnstructured": "KARTE Café"
+                    }
+                ]
+            }}"#,
+        )?;
+        let category_map = crate::config::CategoryMap(Default::default());
+
+        let pending = super::convert_transactions(pending.transactions, &category_map)?;
+        let booked = super::convert_transactions(booked.transactions, &category_map)?;
+        assert_eq!(TransactionState::Pending, pending[0].state);
+        assert_eq!("2021030300002", booked[0].transaction_id);
+        assert_ne!(pending[0].transaction_id, booked[0].transaction_id);
+
+        // So the pending copy is imported with a prefix, and deleted once
+        // the bank stops listing it
+        let provider = super::GoCardlessProvider {
+            display_name: "GoCardless".to_string(),
+            rest_client: crate::provider::rest_client(super::API_URL, None)?,
+            accounts: vec![],
+            history: crate::provider::History::new(90),
+            category_map,
+        };
+        let account = crate::Account {
+            account_id: "acc".to_string(),
+            currency: "EUR".to_string(),
+            display_name: "Girokonto".to_string(),
+            ty: crate::AccountType::Account,
+            balance: None,
+            balances: Default::default(),
+            identifiers: Default::default(),
+        };
+        assert!(provider.pending_ids_change(&account));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use crate::prelude::*;
+
+/// YNAB rejects import IDs longer than this.
+pub const MAX_LEN: usize = 36;
+
+/// Generates deterministic import IDs for providers which don't have stable
+/// transaction IDs, in the same `PREFIX:amount:date:occurrence` format YNAB
+/// uses for its own file imports.
+///
+/// Transactions must be passed in a stable order, so that importing the
+/// same statement again produces the same IDs.
+#[derive(Default)]
+pub struct ImportIds {
+    occurrences: HashMap<String, u32>,
+}
+
+impl ImportIds {
+    pub fn next(&mut self, prefix: &str, date: UtcDate, amount: i64) -> String {
+        let key = format!("{}:{}:{}", prefix, amount, date.format("%Y-%m-%d"));
+        self.with_occurrence(key)
+    }
+
+    /// Like [`ImportIds::next`], but also includes a hash of the description
+    /// so different transactions for the same amount on the same day don't
+    /// depend on the order they're listed in.
+    pub fn next_hashed(
+        &mut self,
+        prefix: &str,
+        date: UtcDate,
+        amount: i64,
+        description: &str,
+    ) -> String {
+        let normalised: String = description
+            .chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect();
+        let key = format!(
+            "{}:{}:{}:{:08x}",
+            prefix,
+            amount,
+            date.format("%Y-%m-%d"),
+            fnv1a(normalised.as_bytes()) as u32
+        );
+        fit(&self.with_occurrence(key))
+    }
+
+    fn with_occurrence(&mut self, key: String) -> String {
+        let occurrence = self.occurrences.entry(key.clone()).or_insert(0);
+        *occurrence += 1;
+
+        format!("{}:{}", key, occurrence)
+    }
+}
+
+/// Shortens IDs which are longer than YNAB allows, replacing the end with a
+/// hash of the whole ID so they stay unique.
+pub fn fit(id: &str) -> String {
+    if id.len() <= MAX_LEN {
+        return id.to_string();
+    }
+
+    let hash = format!("{:016x}", fnv1a(id.as_bytes()));
+    let mut kept = String::new();
+    for c in id.chars() {
+        if kept.len() + c.len_utf8() + hash.len() + 1 > MAX_LEN {
+            break;
+        }
+        kept.push(c);
+    }
+
+    format!("{}~{}", kept, hash)
+}
+
+/// 64 bit FNV-1a, used as it is stable across builds unlike std's hashers
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn fit() {
+        assert_eq!("short", super::fit("short"));
+
+        let long = "P:0123456789abcdef0123456789abcdef0123";
+        let fitted = super::fit(long);
+        assert_eq!(super::MAX_LEN, fitted.len());
+        assert!(fitted.starts_with("P:0123456789abcdef"));
+        assert_ne!(fitted, super::fit(&long.replace('f', "e")));
+    }
+
+    #[test]
+    fn occurrences() {
+        let mut ids = super::ImportIds::default();
+        let date = Utc.ymd(2021, 3, 4);
+
+        assert_eq!("RS:-1250:2021-03-04:1", ids.next("RS", date, -1250));
+        assert_eq!("RS:-1250:2021-03-04:2", ids.next("RS", date, -1250));
+        assert_eq!("RS:500:2021-03-04:1", ids.next("RS", date, 500));
+    }
+
+    #[test]
+    fn hashed() {
+        let mut ids = super::ImportIds::default();
+        let date = Utc.ymd(2021, 3, 4);
+
+        let first = ids.next_hashed("TL", date, -1250, "CARD PAYMENT TO Tesco");
+        assert!(first.starts_with("TL:-1250:2021-03-04:"));
+        assert!(first.ends_with(":1"));
+        assert!(first.len() <= super::MAX_LEN);
+        assert_eq!(
+            first.replace(":1", ":2"),
+            ids.next_hashed("TL", date, -1250, "card payment to  TESCO")
+        );
+        assert!(ids
+            .next_hashed("TL", date, -1250, "Sainsburys")
+            .ends_with(":1"));
+    }
+}
@@ -1,9 +1,25 @@
+pub mod camt;
 pub mod cli;
 pub mod config;
+pub mod csv_file;
 pub mod currency;
+pub mod encryption;
+pub mod files;
+pub mod gocardless;
+pub mod import_id;
+pub mod monzo;
+pub mod mt940;
+pub mod ofx;
+pub mod provider;
+pub mod qif;
+pub mod redirect_listener;
 pub mod revolut;
+pub mod secret;
 pub mod serialisation;
+pub mod simplefin;
+pub mod starling;
 pub mod truelayer;
+pub mod wise;
 pub mod ynab;
 
 pub mod prelude {
@@ -17,22 +33,48 @@ pub mod prelude {
     pub type UtcDateTime = chrono::DateTime<chrono::Utc>;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Account {
     pub account_id: String,
     pub currency: String,
     pub display_name: String,
     pub ty: AccountType,
-    pub balance: i64,
+    /// The balance YNAB's is reconciled with, negative for money owed.
+    /// Not reconciled if the provider doesn't know the balance.
+    pub balance: Option<i64>,
+    pub balances: Balances,
+    pub identifiers: AccountIdentifiers,
 }
 
-#[derive(Debug)]
+/// Other balance figures a provider reports, for the sync log
+#[derive(Debug, Clone, Default)]
+pub struct Balances {
+    /// Funds available including any overdraft, or remaining credit on a card
+    pub available: Option<i64>,
+    pub overdraft: Option<i64>,
+    pub credit_limit: Option<i64>,
+    /// Negative for money owed, like `Account::balance`
+    pub last_statement_balance: Option<i64>,
+}
+
+/// Bank details which identify an account, unlike `account_id` which may
+/// change when a provider's consent is renewed.
+#[derive(Debug, Clone, Default)]
+pub struct AccountIdentifiers {
+    pub iban: Option<String>,
+    pub sort_code: Option<String>,
+    pub account_number: Option<String>,
+    /// Usually the last four digits
+    pub partial_card_number: Option<String>,
+}
+
+#[derive(Debug, Clone)]
 pub enum AccountType {
     Account,
     Card,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Transaction {
     pub transaction_id: String,
     pub timestamp: UtcDateTime,
@@ -40,6 +82,102 @@ pub struct Transaction {
     pub description: String,
     pub payee_name: Option<String>,
     pub category: Option<String>,
+    pub state: TransactionState,
+    /// Account balance after this transaction, if the provider reports it
+    pub running_balance: Option<i64>,
+    /// Parts of a transaction split between categories, which add up to
+    /// `amount`. Empty if it isn't split.
+    pub splits: Vec<Split>,
+    /// What it was exchanged for in another currency, such as the other side
+    /// of a conversion between balances. Used instead of an estimated rate
+    /// when it's in the budget's currency.
+    pub exchanged: Option<Money>,
+}
+
+/// An amount in a given currency, in milliunits
+#[derive(Debug, Clone, PartialEq)]
+pub struct Money {
+    pub amount: i64,
+    pub currency: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Split {
+    pub amount: i64,
+    pub category: Option<String>,
+    pub memo: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionState {
+    Cleared,
+    Pending,
+    /// Reported by the provider earlier but since reverted or declined.
+    /// Any copy already imported into YNAB should be removed.
+    Reverted,
+}
+
+/// A recurring payment, such as a standing order or direct debit
+#[derive(Debug, Clone)]
+pub struct ScheduledTransaction {
+    pub next_date: UtcDate,
+    pub amount: i64,
+    pub payee_name: String,
+    pub memo: String,
+    pub frequency: Frequency,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    EveryOtherWeek,
+    Every4Weeks,
+    Monthly,
+    EveryOtherMonth,
+    Every3Months,
+    Every4Months,
+    TwiceAYear,
+    Yearly,
+    EveryOtherYear,
+}
+
+impl Frequency {
+    /// Returns the date of the next occurrence after `date`
+    pub fn advance(self, date: UtcDate) -> UtcDate {
+        use chrono::Duration;
+
+        let months = match self {
+            Frequency::Daily => return date + Duration::days(1),
+            Frequency::Weekly => return date + Duration::weeks(1),
+            Frequency::EveryOtherWeek => return date + Duration::weeks(2),
+            Frequency::Every4Weeks => return date + Duration::weeks(4),
+            Frequency::Monthly => 1,
+            Frequency::EveryOtherMonth => 2,
+            Frequency::Every3Months => 3,
+            Frequency::Every4Months => 4,
+            Frequency::TwiceAYear => 6,
+            Frequency::Yearly => 12,
+            Frequency::EveryOtherYear => 24,
+        };
+        add_months(date, months)
+    }
+}
+
+/// Adds calendar months, clamping to the end of shorter months
+fn add_months(date: UtcDate, months: u32) -> UtcDate {
+    use chrono::Datelike;
+
+    let month0 = date.month0() + months;
+    let year = date.year() + (month0 / 12) as i32;
+    let month = month0 % 12 + 1;
+    let mut day = date.day();
+    loop {
+        if let Some(date) = chrono::NaiveDate::from_ymd_opt(year, month, day) {
+            return UtcDate::from_utc(date, chrono::Utc);
+        }
+        day -= 1;
+    }
 }
 
 use crate::config::Config;
@@ -48,6 +186,28 @@ use crate::prelude::*;
 pub trait ConnectedProvider: std::fmt::Debug {
     fn get_accounts(&mut self) -> Result<Vec<Account>>;
     fn get_transactions(&mut self, acc: &Account) -> Result<Vec<crate::Transaction>>;
+
+    /// Requests transactions back to `from` instead of the provider's usual
+    /// history, to backfill older transactions. Ignored by providers which
+    /// can't go back further.
+    fn set_history_start(&mut self, _from: UtcDate) {}
+
+    /// The IDs of this provider's accounts which are matched to YNAB
+    /// accounts. Called after `get_accounts`, before any `get_transactions`.
+    fn set_mapped_accounts(&mut self, _account_ids: &[&str]) {}
+
+    /// Whether pending transactions settle under a different ID. If so,
+    /// they're imported under a prefixed ID and deleted from YNAB once the
+    /// provider stops reporting them, rather than being marked as cleared.
+    /// Called after `get_transactions` for the account.
+    fn pending_ids_change(&self, _acc: &Account) -> bool {
+        false
+    }
+
+    /// Recurring payments known to the provider for this account
+    fn get_scheduled_transactions(&mut self, _acc: &Account) -> Result<Vec<ScheduledTransaction>> {
+        Ok(vec![])
+    }
 }
 
 /*
@@ -64,6 +224,17 @@ fn load_connections(cfg: &mut Config) -> Result<Vec<Box<dyn ConnectedProvider>>>
         let (refreshed, connection) = match &mut cfg.providers[idx] {
             config::Provider::Truelayer(token) => truelayer::initialize(&cfg.ynab_config, token),
             config::Provider::Revolut(token) => revolut::initialize(token),
+            config::Provider::RevolutStatement(source) => revolut::statement::initialize(source),
+            config::Provider::Ofx(source) => ofx::initialize(source),
+            config::Provider::Qif(source) => qif::initialize(source),
+            config::Provider::Csv(source) => csv_file::initialize(&cfg.ynab_config, source),
+            config::Provider::Camt(source) => camt::initialize(source),
+            config::Provider::Mt940(source) => mt940::initialize(source),
+            config::Provider::Monzo(token) => monzo::initialize(&cfg.ynab_config, token),
+            config::Provider::Starling(token) => starling::initialize(token),
+            config::Provider::Wise(token) => wise::initialize(token),
+            config::Provider::GoCardless(token) => gocardless::initialize(&cfg.ynab_config, token),
+            config::Provider::SimpleFin(token) => simplefin::initialize(token),
         };
         if refreshed {
             config::save_config(&cfg.path, cfg)?;
@@ -80,3 +251,21 @@ fn load_connections(cfg: &mut Config) -> Result<Vec<Box<dyn ConnectedProvider>>>
 
     Ok(connected)
 }
+
+#[cfg(test)]
+mod test {
+    use crate::Frequency;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn frequency_advance() {
+        let date = Utc.ymd(2021, 1, 31);
+
+        assert_eq!(Utc.ymd(2021, 2, 28), Frequency::Monthly.advance(date));
+        assert_eq!(Utc.ymd(2022, 1, 31), Frequency::Yearly.advance(date));
+        assert_eq!(
+            Utc.ymd(2021, 2, 14),
+            Frequency::EveryOtherWeek.advance(date)
+        );
+    }
+}
@@ -0,0 +1,569 @@
+//! Monzo's developer API, which gives merchants, categories, pots and
+//! pending transactions in more detail than Truelayer does.
+
+use crate::config::{CategoryMap, YnabConfig};
+use crate::prelude::*;
+use crate::provider::{debug_as_display_name, History};
+use crate::{AccountIdentifiers, AccountType, TransactionState};
+use api::*;
+use chrono::Utc;
+use std::collections::HashSet;
+
+use oauth2::basic::BasicClient;
+use oauth2::reqwest::http_client;
+use oauth2::url::Url;
+use oauth2::{
+    AccessToken, AuthType, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken,
+    RedirectUrl, TokenResponse, TokenUrl,
+};
+use restson::Response;
+
+const AUTH_URL: &str = "https://auth.monzo.com/";
+const TOKEN_URL: &str = "https://api.monzo.com/oauth2/token";
+const API_URL: &str = "https://api.monzo.com/";
+/// Monzo has no page which displays the code, so it's copied from the
+/// address bar after the redirect. Must be registered with the client.
+pub const DEFAULT_REDIRECT_URL: &str = "http://localhost/";
+/// Transactions requested at once, the most Monzo allows
+const PAGE_SIZE: usize = 100;
+
+struct MonzoProvider {
+    display_name: String,
+    rest_client: RestClient,
+    history: History,
+    category_map: CategoryMap,
+    pots: HashSet<String>,
+}
+
+debug_as_display_name!(MonzoProvider);
+
+impl crate::ConnectedProvider for MonzoProvider {
+    fn get_accounts(&mut self) -> Result<Vec<crate::Account>> {
+        let accounts: Response<AccountsResponse> = self.rest_client.get(())?;
+
+        let mut converted = vec![];
+        for account in accounts.into_inner().accounts {
+            if account.closed {
+                continue;
+            }
+            let balance: Response<Balance> = self
+                .rest_client
+                .get_with((), &[("account_id", account.id.as_str())])?;
+            let pots: Response<PotsResponse> = self
+                .rest_client
+                .get_with((), &[("current_account_id", account.id.as_str())])?;
+
+            converted.push(convert_account(&account, &balance.into_inner()));
+            for pot in pots.into_inner().pots {
+                if pot.deleted {
+                    continue;
+                }
+                self.pots.insert(pot.id.clone());
+                converted.push(convert_pot(pot));
+            }
+        }
+
+        Ok(converted)
+    }
+
+    fn get_transactions(&mut self, acc: &crate::Account) -> Result<Vec<crate::Transaction>> {
+        // Money moved in and out of pots is listed on the current account, with
+        // the pot as payee, and YNAB adds the other side of those transfers
+        if self.pots.contains(&acc.account_id) {
+            return Ok(vec![]);
+        }
+
+        let from = self.history.from();
+        let mut since = from.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        let limit = PAGE_SIZE.to_string();
+
+        let mut transactions = vec![];
+        loop {
+            let page: Response<TransactionsResponse> = self.rest_client.get_with(
+                (),
+                &[
+                    ("account_id", acc.account_id.as_str()),
+                    ("expand[]", "merchant"),
+                    ("since", since.as_str()),
+                    ("limit", limit.as_str()),
+                ],
+            )?;
+            let page = page.into_inner().transactions;
+            let full_page = page.len() == PAGE_SIZE;
+            if let Some(last) = page.last() {
+                since = last.id.clone();
+            }
+            transactions.extend(page);
+            if !full_page {
+                break;
+            }
+        }
+
+        Ok(transactions
+            .into_iter()
+            .map(|it| convert_transaction(it, &self.category_map))
+            .collect())
+    }
+
+    fn set_history_start(&mut self, from: UtcDate) {
+        self.history.set_start(from);
+    }
+}
+
+/// Monzo amounts are in pennies
+fn milliunits(amount: i64) -> i64 {
+    amount * 10
+}
+
+fn convert_account(account: &Account, balance: &Balance) -> crate::Account {
+    let (display_name, ty) = match account.ty.as_str() {
+        "uk_retail" => ("Monzo current account", AccountType::Account),
+        "uk_retail_joint" => ("Monzo joint account", AccountType::Account),
+        "uk_monzo_flex" => ("Monzo Flex", AccountType::Card),
+        _ => (account.description.as_str(), AccountType::Account),
+    };
+
+    crate::Account {
+        account_id: account.id.clone(),
+        currency: account.currency.clone(),
+        display_name: display_name.to_string(),
+        ty,
+        balance: Some(milliunits(balance.balance)),
+        balances: Default::default(),
+        identifiers: AccountIdentifiers {
+            sort_code: account.sort_code.clone(),
+            account_number: account.account_number.clone(),
+            ..Default::default()
+        },
+    }
+}
+
+fn convert_pot(pot: Pot) -> crate::Account {
+    crate::Account {
+        account_id: pot.id,
+        currency: pot.currency,
+        display_name: pot.name,
+        ty: AccountType::Account,
+        balance: Some(milliunits(pot.balance)),
+        balances: Default::default(),
+        identifiers: Default::default(),
+    }
+}
+
+fn convert_transaction(monzo_tran: Transaction, category_map: &CategoryMap) -> crate::Transaction {
+    let merchant_category = monzo_tran
+        .merchant
+        .as_ref()
+        .and_then(|it| it.category.as_deref());
+    let category = category_map
+        .lookup(
+            std::iter::once(monzo_tran.category.as_str())
+                .chain(merchant_category)
+                .filter(|it| !it.is_empty()),
+        )
+        .or_else(|| category_name(&monzo_tran.category));
+
+    let state = if monzo_tran.decline_reason.is_some() {
+        TransactionState::Reverted
+    } else if monzo_tran.settled.as_deref().unwrap_or("").is_empty() {
+        TransactionState::Pending
+    } else {
+        TransactionState::Cleared
+    };
+
+    // The pot's ID makes this a transfer, if a YNAB account has it as ACCOUNT_ID
+    let merchant_name = monzo_tran.merchant.and_then(|it| it.name);
+    let description = &monzo_tran.description;
+    let payee_name = monzo_tran
+        .metadata
+        .get("pot_id")
+        .cloned()
+        .or(merchant_name)
+        .or(monzo_tran.counterparty.name)
+        .unwrap_or_else(|| description.clone());
+
+    crate::Transaction {
+        transaction_id: crate::import_id::fit(&monzo_tran.id),
+        timestamp: monzo_tran.created,
+        amount: milliunits(monzo_tran.amount),
+        description: if monzo_tran.notes.is_empty() {
+            monzo_tran.description
+        } else {
+            monzo_tran.notes
+        },
+        payee_name: Some(payee_name),
+        category,
+        state,
+        running_balance: None,
+        splits: vec![],
+        exchanged: None,
+    }
+}
+
+/// Custom categories only have an ID, so need a `category_map` entry
+fn category_name(category: &str) -> Option<String> {
+    if category.starts_with("category_") {
+        return None;
+    }
+    crate::config::category_name(category)
+}
+
+pub fn initialize(
+    ynab_config: &YnabConfig,
+    token: &mut Token,
+) -> (bool, Result<Box<dyn crate::ConnectedProvider>>) {
+    let (refreshed, access_token) = match refresh(ynab_config, token) {
+        Ok((refreshed, token)) => (refreshed, token),
+        Err(e) => return (false, Err(e)),
+    };
+    let rest_client = match crate::provider::rest_client(API_URL, Some(access_token.secret())) {
+        Ok(rest_client) => rest_client,
+        Err(e) => return (refreshed, Err(e)),
+    };
+    let whoami: Result<Response<WhoAmI>, _> = rest_client.get(());
+    match whoami.map(|it| it.into_inner().authenticated) {
+        Ok(true) => {}
+        Ok(false) => return (refreshed, Err(anyhow!("Monzo token isn't authenticated"))),
+        Err(e) => return (refreshed, Err(e.into())),
+    }
+
+    let provider = MonzoProvider {
+        display_name: token.display_name.clone(),
+        rest_client,
+        history: History::new(token.history_days),
+        category_map: token.category_map.clone(),
+        pots: HashSet::new(),
+    };
+    (refreshed, Ok(Box::new(provider)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Token {
+    pub display_name: String,
+    pub access_token: oauth2::AccessToken,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub access_token_expiry: UtcDateTime,
+    pub refresh_token: oauth2::RefreshToken,
+    /// Days of transaction history requested on each sync. Monzo only
+    /// returns the last 90 days once the login is more than 5 minutes old.
+    #[serde(default = "default_history_days")]
+    pub history_days: i64,
+    /// Monzo categories, e.g. "eating_out", or custom category IDs to YNAB
+    /// category names. Unmapped built in categories are passed on as
+    /// their name, e.g. "Eating out".
+    #[serde(default)]
+    pub category_map: CategoryMap,
+}
+
+pub const DEFAULT_HISTORY_DAYS: i64 = 89;
+
+fn default_history_days() -> i64 {
+    DEFAULT_HISTORY_DAYS
+}
+
+pub mod api {
+    use crate::prelude::*;
+
+    // https://docs.monzo.com/#authenticating-requests
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct WhoAmI {
+        pub authenticated: bool,
+        pub client_id: String,
+        pub user_id: String,
+    }
+
+    impl RestPath<()> for WhoAmI {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("ping/whoami".to_string())
+        }
+    }
+
+    // https://docs.monzo.com/#accounts
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Account {
+        pub id: String,
+        pub description: String,
+        #[serde(rename = "type")]
+        pub ty: String,
+        pub currency: String,
+        #[serde(default)]
+        pub closed: bool,
+        pub sort_code: Option<String>,
+        pub account_number: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct AccountsResponse {
+        pub accounts: Vec<Account>,
+    }
+
+    impl RestPath<()> for AccountsResponse {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("accounts".to_string())
+        }
+    }
+
+    // https://docs.monzo.com/#read-balance
+    /// Amounts are in pennies. `balance` excludes pots, `total_balance`
+    /// includes them.
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Balance {
+        pub balance: i64,
+        pub total_balance: i64,
+        pub currency: String,
+    }
+
+    impl RestPath<()> for Balance {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("balance".to_string())
+        }
+    }
+
+    // https://docs.monzo.com/#pots
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Pot {
+        pub id: String,
+        pub name: String,
+        pub balance: i64,
+        pub currency: String,
+        #[serde(default)]
+        pub deleted: bool,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct PotsResponse {
+        pub pots: Vec<Pot>,
+    }
+
+    impl RestPath<()> for PotsResponse {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("pots".to_string())
+        }
+    }
+
+    // https://docs.monzo.com/#transactions
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Transaction {
+        pub id: String,
+        #[serde(deserialize_with = "crate::serialisation::deserialize")]
+        pub created: UtcDateTime,
+        pub description: String,
+        /// Pennies, negative for money out
+        pub amount: i64,
+        pub currency: String,
+        /// Only expanded into an object when requested with `expand[]=merchant`
+        pub merchant: Option<Merchant>,
+        #[serde(default)]
+        pub counterparty: Counterparty,
+        #[serde(default)]
+        pub category: String,
+        #[serde(default)]
+        pub notes: String,
+        /// Empty until the transaction settles
+        pub settled: Option<String>,
+        pub decline_reason: Option<String>,
+        #[serde(default)]
+        pub metadata: HashMap<String, String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Merchant {
+        pub name: Option<String>,
+        pub category: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Default)]
+    pub struct Counterparty {
+        pub name: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct TransactionsResponse {
+        pub transactions: Vec<Transaction>,
+    }
+
+    impl RestPath<()> for TransactionsResponse {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("transactions".to_string())
+        }
+    }
+}
+
+pub fn new_oauth2_client(config: &YnabConfig, redirect_url: &str) -> Result<BasicClient> {
+    Ok(BasicClient::new(
+        ClientId::new(config.monzo_client_id.clone()),
+        Some(ClientSecret::new(
+            config.monzo_client_secret.secret().to_string(),
+        )),
+        AuthUrl::new(AUTH_URL.to_string())?,
+        Some(TokenUrl::new(TOKEN_URL.to_string())?),
+    )
+    .set_auth_type(AuthType::RequestBody)
+    .set_redirect_uri(RedirectUrl::new(redirect_url.to_string())?))
+}
+
+/// Returns the URL to authenticate at, and the state parameter which the
+/// redirect back to `redirect_url` must carry.
+pub fn get_auth_url(config: &YnabConfig, redirect_url: &str) -> Result<(Url, CsrfToken)> {
+    let client = new_oauth2_client(config, redirect_url)?;
+    Ok(client.authorize_url(CsrfToken::new_random).url())
+}
+
+pub fn authorize(config: &YnabConfig, code: &str, redirect_url: &str) -> Result<Token> {
+    let client = new_oauth2_client(config, redirect_url)?;
+    let token = client
+        .exchange_code(AuthorizationCode::new(code.to_string()))
+        .request(http_client)
+        .map_err(|e| anyhow!("{}", e))
+        .context("Failed to authorize with monzo")?;
+
+    Ok(Token {
+        display_name: "Monzo".to_string(),
+        access_token: token.access_token().clone(),
+        access_token_expiry: crate::truelayer::calculate_expiry_time(
+            token
+                .expires_in()
+                .ok_or_else(|| anyhow!("Monzo token has no expiry"))?,
+        ),
+        refresh_token: token
+            .refresh_token()
+            .cloned()
+            .ok_or_else(|| anyhow!("No refresh token from monzo, is the client confidential?"))?,
+        history_days: DEFAULT_HISTORY_DAYS,
+        category_map: Default::default(),
+    })
+}
+
+pub fn refresh(ynab_config: &YnabConfig, token: &mut Token) -> Result<(bool, AccessToken)> {
+    let now = Utc::now();
+
+    if now < token.access_token_expiry {
+        return Ok((false, token.access_token.clone()));
+    }
+
+    let client = new_oauth2_client(ynab_config, DEFAULT_REDIRECT_URL)?;
+    let new_token = client
+        .exchange_refresh_token(&token.refresh_token)
+        .request(http_client)
+        .map_err(|e| anyhow!("{}", e))
+        .context("Error refreshing monzo token")?;
+
+    token.access_token = new_token.access_token().clone();
+    // Monzo issues a new refresh token each time, and the old one stops working
+    if let Some(refresh_token) = new_token.refresh_token() {
+        token.refresh_token = refresh_token.clone();
+    }
+    token.access_token_expiry = crate::truelayer::calculate_expiry_time(
+        new_token
+            .expires_in()
+            .ok_or_else(|| anyhow!("Monzo token has no expiry"))?,
+    );
+
+    Ok((true, new_token.access_token().clone()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::TransactionState;
+
+    #[test]
+    fn transactions() -> Result<()> {
+        let response: super::TransactionsResponse = serde_json::from_str(
+            r#"{"transactions": [
+                {
+                    "id": "tx_00009pjsa1", "created": "2021-03-01T12:30:00.123Z",
+                    "description": "TESCO STORES 3297 LONDON GBR", "amount": -1250,
+                    "currency": "GBP", "category": "groceries", "notes": "",
+                    "settled": "2021-03-02T06:00:00Z", "decline_reason": null,
+                    "merchant": {"name": "Tesco", "category": "groceries"},
+                    "counterparty": {}, "metadata": {}
+                },
+                {
+                    "id": "tx_00009pjsa2", "created": "2021-03-03T09:00:00Z",
+                    "description": "pot_0000abc", "amount": -5000, "currency": "GBP",
+                    "category": "savings", "notes": "", "settled": "",
+                    "merchant": null, "metadata": {"pot_id": "pot_0000abc"}
+                },
+                {
+                    "id": "tx_00009pjsa3", "created": "2021-03-04T09:00:00Z",
+                    "description": "PRET A MANGER", "amount": -450, "currency": "GBP",
+                    "category": "eating_out", "notes": "Lunch", "settled": "",
+                    "decline_reason": "INSUFFICIENT_FUNDS",
+                    "merchant": {"name": "Pret A Manger", "category": "eating_out"}
+                }
+            ]}"#,
+        )?;
+        let category_map = crate::config::CategoryMap(
+            vec![("groceries".to_string(), "Food".to_string())]
+                .into_iter()
+                .collect(),
+        );
+
+        let converted: Vec<_> = response
+            .transactions
+            .into_iter()
+            .map(|it| super::convert_transaction(it, &category_map))
+            .map(|it| {
+                (
+                    it.amount,
+                    it.payee_name.unwrap_or_default(),
+                    it.category,
+                    it.description,
+                    it.state,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    -12500,
+                    "Tesco".to_string(),
+                    Some("Food".to_string()),
+                    "TESCO STORES 3297 LONDON GBR".to_string(),
+                    TransactionState::Cleared
+                ),
+                (
+                    -50000,
+                    "pot_0000abc".to_string(),
+                    Some("Savings".to_string()),
+                    "pot_0000abc".to_string(),
+                    TransactionState::Pending
+                ),
+                (
+                    -4500,
+                    "Pret A Manger".to_string(),
+                    Some("Eating out".to_string()),
+                    "Lunch".to_string(),
+                    TransactionState::Reverted
+                ),
+            ],
+            converted
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn custom_categories_and_bank_transfers() -> Result<()> {
+        let response: super::TransactionsResponse = serde_json::from_str(
+            r#"{"transactions": [
+                {
+                    "id": "tx_00009pjsa4", "created": "2021-03-05T09:00:00Z",
+                    "description": "J SMITH", "amount": 2000, "currency": "GBP",
+                    "category": "category_00009abc", "notes": "", "settled": "2021-03-05T09:00:00Z",
+                    "merchant": null, "counterparty": {"name": "Jane Smith"}, "metadata": {}
+                }
+            ]}"#,
+        )?;
+        let transaction = response.transactions.into_iter().next().unwrap();
+
+        let converted = super::convert_transaction(transaction, &Default::default());
+        // Custom categories are only known by ID, so need mapping
+        assert_eq!(None, converted.category);
+        assert_eq!(Some("Jane Smith"), converted.payee_name.as_deref());
+        assert_eq!("J SMITH", converted.description);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,496 @@
+//! Reads SWIFT MT940 customer statements.
+//!
+//! The `:86:` field is free text in the standard. Banks fill it with German
+//! style `?NN` subfields, `/KEY/value` pairs or plain text, which are all
+//! read for the payee and memo.
+
+use crate::files::{Source, Statement, StatementProvider};
+use crate::import_id::ImportIds;
+use crate::prelude::*;
+use crate::{AccountIdentifiers, AccountType, ConnectedProvider, TransactionState};
+use anyhow::bail;
+use chrono::{Datelike, NaiveDate, TimeZone, Utc};
+
+const IMPORT_ID_PREFIX: &str = "MT940";
+
+pub fn initialize(source: &mut Source) -> (bool, Result<Box<dyn ConnectedProvider>>) {
+    (
+        false,
+        load(source).map(|it| Box::new(it) as Box<dyn ConnectedProvider>),
+    )
+}
+
+fn load(source: &Source) -> Result<StatementProvider> {
+    let mut statements = vec![];
+    for file in crate::files::statement_files(&source.path, &["sta", "mt940", "940", "txt"])? {
+        let data =
+            std::fs::read(&file).with_context(|| format!("Error reading {}", file.display()))?;
+        // Files which aren't UTF-8 are usually Latin-1
+        let data = match String::from_utf8(data) {
+            Ok(data) => data,
+            Err(e) => encoding_rs::WINDOWS_1252
+                .decode(e.as_bytes())
+                .0
+                .into_owned(),
+        };
+        statements.extend(parse(&data).with_context(|| format!("Error in {}", file.display()))?);
+    }
+
+    Ok(crate::files::combine_statements(
+        &source.display_name,
+        statements,
+    ))
+}
+
+/// Splits a file into statements, each a list of `(tag, value)` fields.
+/// Values which continue over several lines keep their line breaks.
+fn fields(data: &str) -> Vec<Vec<(String, String)>> {
+    let mut statements = vec![];
+    let mut current: Vec<(String, String)> = vec![];
+
+    for line in data.lines() {
+        let line = line.trim_end();
+        // SWIFT message headers, e.g. {1:F01...}{2:O940...}{4:
+        let line = match line.rfind("{4:") {
+            Some(index) => &line[(index + 3)..],
+            None if line.starts_with('{') => continue,
+            None => line,
+        };
+        if line == "-" || line == "-}" {
+            if !current.is_empty() {
+                statements.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+
+        let tag = line
+            .strip_prefix(':')
+            .and_then(|rest| rest.find(':').map(|end| &rest[..end]))
+            .filter(|tag| {
+                (2..=3).contains(&tag.len()) && tag.chars().take(2).all(|c| c.is_ascii_digit())
+            });
+        match tag {
+            Some(tag) => {
+                // Files without separators start each statement with :20:
+                if tag == "20" && !current.is_empty() {
+                    statements.push(std::mem::take(&mut current));
+                }
+                current.push((tag.to_string(), line[(tag.len() + 2)..].to_string()));
+            }
+            None => {
+                if let Some((_, value)) = current.last_mut() {
+                    value.push('\n');
+                    value.push_str(line);
+                }
+            }
+        }
+    }
+    if !current.is_empty() {
+        statements.push(current);
+    }
+
+    statements
+}
+
+fn parse(data: &str) -> Result<Vec<Statement>> {
+    fields(data)
+        .into_iter()
+        .filter(|fields| fields.iter().any(|(tag, _)| tag == "25"))
+        .map(|fields| read_statement(&fields))
+        .collect()
+}
+
+fn read_statement(fields: &[(String, String)]) -> Result<Statement> {
+    let field = |tag: &str| {
+        fields
+            .iter()
+            .find(|(it, _)| it == tag)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let account = field("25").unwrap().trim();
+    let opening = field("60F")
+        .or_else(|| field("60M"))
+        .map(parse_balance)
+        .transpose()?;
+    let closing = field("62F")
+        .or_else(|| field("62M"))
+        .map(parse_balance)
+        .transpose()?;
+    let available = field("64").map(parse_balance).transpose()?;
+    let currency = closing
+        .as_ref()
+        .or(opening.as_ref())
+        .map(|it| it.currency.clone())
+        .ok_or_else(|| anyhow!("Statement for {} has no balance", account))?;
+
+    let mut import_ids = ImportIds::default();
+    let mut transactions = vec![];
+    for (index, (tag, value)) in fields.iter().enumerate() {
+        if tag != "61" {
+            continue;
+        }
+        let information = match fields.get(index + 1) {
+            Some((tag, value)) if tag == "86" => Some(value.as_str()),
+            _ => None,
+        };
+        transactions.push(read_transaction(value, information, &mut import_ids)?);
+    }
+
+    // e.g. 10020030/1234567, or an IBAN
+    let identifiers = match account.split_once('/') {
+        Some((_, number)) => AccountIdentifiers {
+            account_number: Some(number.to_string()),
+            ..Default::default()
+        },
+        None if account.len() > 14 && account.chars().take(2).all(|c| c.is_ascii_alphabetic()) => {
+            AccountIdentifiers {
+                iban: Some(account.to_string()),
+                ..Default::default()
+            }
+        }
+        None => AccountIdentifiers {
+            account_number: Some(account.to_string()),
+            ..Default::default()
+        },
+    };
+
+    Ok(Statement {
+        account: crate::Account {
+            account_id: format!("mt940:{}", account),
+            currency,
+            display_name: account.to_string(),
+            ty: AccountType::Account,
+            balance: closing.as_ref().map(|it| it.amount),
+            balances: crate::Balances {
+                available: available.map(|it| it.amount),
+                ..Default::default()
+            },
+            identifiers,
+        },
+        balance_date: closing.map(|it| it.date.and_hms(0, 0, 0)),
+        transactions,
+    })
+}
+
+struct Balance {
+    date: UtcDate,
+    currency: String,
+    amount: i64,
+}
+
+/// Parses balances such as `C210305EUR987,50`
+fn parse_balance(value: &str) -> Result<Balance> {
+    let value = value.trim();
+    let invalid = || anyhow!("Invalid balance {}", value);
+    let sign = match value.get(..1) {
+        Some("C") => 1,
+        Some("D") => -1,
+        _ => return Err(invalid()),
+    };
+    Ok(Balance {
+        date: parse_date(value.get(1..7).ok_or_else(invalid)?)?,
+        currency: value.get(7..10).ok_or_else(invalid)?.to_string(),
+        amount: sign * parse_amount(value.get(10..).ok_or_else(invalid)?)?,
+    })
+}
+
+/// Reads a `:61:` statement line, e.g.
+/// `2103040304D12,50NTRFNONREF//BANKREF123`, with the `:86:` field after it.
+fn read_transaction(
+    line: &str,
+    information: Option<&str>,
+    import_ids: &mut ImportIds,
+) -> Result<crate::Transaction> {
+    let invalid = || anyhow!("Invalid statement line {}", line);
+    let (line, supplementary) = line.split_once('\n').unwrap_or((line, ""));
+
+    let value_date = parse_date(line.get(..6).ok_or_else(invalid)?)?;
+    let mut rest = &line[6..];
+    // The optional booking date has no year, so take the nearest one
+    let mut date = value_date;
+    if let Some(booking_date) = rest
+        .get(..4)
+        .filter(|it| it.chars().all(|c| c.is_ascii_digit()))
+    {
+        let month: u32 = booking_date[..2].parse()?;
+        let day: u32 = booking_date[2..].parse()?;
+        date = [
+            value_date.year() - 1,
+            value_date.year(),
+            value_date.year() + 1,
+        ]
+        .iter()
+        .filter_map(|year| NaiveDate::from_ymd_opt(*year, month, day))
+        .map(|it| Utc.from_utc_date(&it))
+        .min_by_key(|it| (*it - value_date).num_days().abs())
+        .ok_or_else(invalid)?;
+        rest = &rest[4..];
+    }
+
+    // RC and RD are reversals, booked in the opposite direction
+    let (sign, mark_len) = if rest.starts_with("RC") {
+        (-1, 2)
+    } else if rest.starts_with("RD") {
+        (1, 2)
+    } else if rest.starts_with('C') {
+        (1, 1)
+    } else if rest.starts_with('D') {
+        (-1, 1)
+    } else {
+        bail!("Invalid debit/credit mark in {}", line);
+    };
+    rest = &rest[mark_len..];
+    // Optional funds code, the last letter of the currency
+    if rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        rest = &rest[1..];
+    }
+
+    let amount_len = rest
+        .find(|c: char| !c.is_ascii_digit() && c != ',')
+        .unwrap_or(rest.len());
+    let amount = sign * parse_amount(&rest[..amount_len])?;
+    // Transaction type such as NTRF, then the references
+    let references = rest.get((amount_len + 4)..).unwrap_or("");
+    let bank_reference = references
+        .split_once("//")
+        .map(|(_, it)| it.trim())
+        .filter(|it| !it.is_empty() && *it != "NONREF");
+
+    let details = information.map(read_information).unwrap_or_default();
+    let description = match details.memo {
+        Some(memo) => memo,
+        None => supplementary.trim().to_string(),
+    };
+
+    Ok(crate::Transaction {
+        transaction_id: match bank_reference {
+            Some(reference) => reference.to_string(),
+            None => import_ids.next_hashed(
+                IMPORT_ID_PREFIX,
+                date,
+                amount,
+                &format!("{} {}", details.payee.as_deref().unwrap_or(""), description),
+            ),
+        },
+        timestamp: date.and_hms(0, 0, 0),
+        amount,
+        description,
+        payee_name: details.payee,
+        category: None,
+        state: TransactionState::Cleared,
+        running_balance: None,
+        splits: vec![],
+        exchanged: None,
+    })
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Information {
+    payee: Option<String>,
+    memo: Option<String>,
+}
+
+/// Reads the payee and memo from an `:86:` field
+fn read_information(field: &str) -> Information {
+    // Structured fields are wrapped at a fixed width, even mid-word
+    let value = field.replace('\n', "");
+
+    // German banks: 3 digit code, then ?NN subfields. ?20-?29 and ?60-?63 are
+    // the purpose, ?32-?33 the counterparty's name.
+    let separator = value.chars().nth(3);
+    let code = value.get(..3);
+    if code.is_some_and(|code| code.chars().all(|c| c.is_ascii_digit()))
+        && separator.is_some_and(|c| !c.is_ascii_alphanumeric() && c != ' ')
+    {
+        let separator = separator.unwrap();
+        let mut purpose = String::new();
+        let mut payee = String::new();
+        for subfield in value[3..].split(separator).skip(1) {
+            // Split after two characters rather than bytes, as text may not be ASCII
+            let code_len = subfield
+                .char_indices()
+                .nth(2)
+                .map_or(subfield.len(), |(index, _)| index);
+            let (code, text) = subfield.split_at(code_len);
+            match code.parse::<u32>() {
+                Ok(20..=29) | Ok(60..=63) => purpose.push_str(text),
+                Ok(32..=33) => payee.push_str(text),
+                _ => {}
+            }
+        }
+        // SEPA purposes are tagged, e.g. EREF+...SVWZ+Rent March
+        let memo = match purpose.find("SVWZ+") {
+            Some(start) => {
+                let memo = &purpose[(start + 5)..];
+                let end = [
+                    "ABWA+", "ABWE+", "EREF+", "KREF+", "MREF+", "CRED+", "DEBT+",
+                ]
+                .iter()
+                .filter_map(|tag| memo.find(tag))
+                .min()
+                .unwrap_or(memo.len());
+                memo[..end].to_string()
+            }
+            None => purpose,
+        };
+        return Information {
+            payee: Some(payee.trim().to_string()).filter(|it| !it.is_empty()),
+            memo: Some(memo.trim().to_string()).filter(|it| !it.is_empty()),
+        };
+    }
+
+    // Dutch and other banks: /KEY/value/KEY/value pairs
+    if value.starts_with('/') {
+        let parts: Vec<&str> = value.split('/').collect();
+        let lookup = |keys: &[&str]| {
+            parts.iter().enumerate().find_map(|(index, part)| {
+                if !keys.contains(part) {
+                    return None;
+                }
+                // Some values are preceded by an empty subfield, as in /REMI/USTD//text/
+                parts[(index + 1)..]
+                    .iter()
+                    .find(|it| !it.is_empty() && !["USTD", "STRD"].contains(it))
+                    .map(|it| it.trim().to_string())
+            })
+        };
+        return Information {
+            payee: lookup(&["NAME"]),
+            memo: lookup(&["REMI"]),
+        };
+    }
+
+    // Free text is wrapped between words
+    let memo: Vec<&str> = field
+        .lines()
+        .map(str::trim)
+        .filter(|it| !it.is_empty())
+        .collect();
+    let memo = memo.join(" ");
+    Information {
+        payee: None,
+        memo: Some(memo).filter(|it| !it.is_empty()),
+    }
+}
+
+fn parse_date(value: &str) -> Result<UtcDate> {
+    let date = NaiveDate::parse_from_str(value, "%y%m%d")
+        .with_context(|| format!("Invalid date {}", value))?;
+    Ok(Utc.from_utc_date(&date))
+}
+
+/// Amounts always use a decimal comma, e.g. `1234,5`
+fn parse_amount(value: &str) -> Result<i64> {
+    let amount: f64 = value
+        .trim()
+        .replace(',', ".")
+        .parse()
+        .with_context(|| format!("Invalid amount {}", value))?;
+    Ok((amount * 1000.0).round() as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+
+    const MT940: &str = "\
+{1:F01BANKDEFFXXXX0000000000}{2:O9400000000000BANKDEFFXXXX00000000000000000000N}{4:
+:20:STARTUMS
+:25:10020030/1234567
+:28C:00001/001
+:60F:C210303EUR1000,00
+:61:2103040304D12,50NTRFNONREF//BANK-1
+:86:166?00SEPA-UEBERWEISUNG?109310?20EREF+E2E-1?21SVWZ+Rent Ma?22rch?30BYLADEM1001
+?31DE02120300000000202051?32Landlord GmbH
+:62F:C210304EUR987,50
+-}
+{1:F01BANKDEFFXXXX0000000000}{2:O9400000000000BANKDEFFXXXX00000000000000000000N}{4:
+:20:STARTUMS
+:25:10020030/1234567
+:28C:00002/001
+:60F:C210304EUR987,50
+:61:210305C100,NMSCNONREF
+:86:/EREF/NOTPROVIDED/NAME/Friend/REMI/USTD//Dinner/
+:61:210305RC2,00NCHGNONREF
+:86:Fee refund
+:62F:C210305EUR1085,50
+-}
+";
+
+    #[test]
+    fn statements() -> Result<()> {
+        let provider = crate::files::combine_statements("test", super::parse(MT940)?);
+
+        let account = &provider.accounts[0];
+        assert_eq!("mt940:10020030/1234567", account.account_id);
+        assert_eq!(Some(1085500), account.balance);
+
+        let transactions: Vec<_> = provider
+            .transactions
+            .iter()
+            .map(|(_, it)| (it.amount, it.payee_name.as_deref(), it.description.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (-12500, Some("Landlord GmbH"), "Rent March"),
+                (100000, Some("Friend"), "Dinner"),
+                (-2000, None, "Fee refund"),
+            ],
+            transactions
+        );
+        assert_eq!("BANK-1", provider.transactions[0].1.transaction_id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn non_ascii_information() {
+        use super::{read_information, Information};
+
+        for text in ["Prämie", "Frühstück", "€5 refund"] {
+            assert_eq!(
+                Information {
+                    payee: None,
+                    memo: Some(text.to_string()),
+                },
+                read_information(text)
+            );
+        }
+        assert_eq!(
+            Information {
+                payee: Some("Bäckerei Müller".to_string()),
+                memo: Some("Brötchen".to_string()),
+            },
+            read_information("106?20Brötchen?32Bäckerei?33 Müller?ä")
+        );
+    }
+
+    #[test]
+    fn non_ascii_booking_date() {
+        let mut import_ids = crate::import_id::ImportIds::default();
+        // The byte after the value date starts a multi-byte character
+        assert!(super::read_transaction("2103040€D12,50NTRF", None, &mut import_ids).is_err());
+        assert!(super::read_transaction("210304€2,50NTRF", None, &mut import_ids).is_err());
+    }
+
+    #[test]
+    fn wrapped_information() {
+        use super::{read_information, Information};
+
+        assert_eq!(
+            Information {
+                payee: None,
+                memo: Some("Fee refund".to_string()),
+            },
+            read_information("Fee\nrefund")
+        );
+        assert_eq!(
+            Information {
+                payee: Some("Bäckerei Müller".to_string()),
+                memo: Some("Brötchen".to_string()),
+            },
+            read_information("106?20Bröt\nchen?32Bäckerei Mül\nler")
+        );
+    }
+}
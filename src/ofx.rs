@@ -0,0 +1,541 @@
+//! Reads OFX and QFX statement files, both the SGML based OFX 1.x and the
+//! XML based OFX 2.x.
+
+use crate::files::{Source, Statement, StatementProvider};
+use crate::prelude::*;
+use crate::{AccountIdentifiers, AccountType, ConnectedProvider, TransactionState};
+use chrono::{NaiveDate, TimeZone, Utc};
+
+pub fn initialize(source: &mut Source) -> (bool, Result<Box<dyn ConnectedProvider>>) {
+    (
+        false,
+        load(source).map(|it| Box::new(it) as Box<dyn ConnectedProvider>),
+    )
+}
+
+fn load(source: &Source) -> Result<StatementProvider> {
+    let mut statements = vec![];
+    for file in crate::files::statement_files(&source.path, &["ofx", "qfx"])? {
+        let data =
+            std::fs::read(&file).with_context(|| format!("Error reading {}", file.display()))?;
+        let document =
+            parse(&decode(&data)).with_context(|| format!("Error parsing {}", file.display()))?;
+        statements.extend(
+            read_statements(&document).with_context(|| format!("Error in {}", file.display()))?,
+        );
+    }
+
+    Ok(crate::files::combine_statements(
+        &source.display_name,
+        statements,
+    ))
+}
+
+/// Decodes a file with the charset given in the SGML header, e.g.
+/// `CHARSET:1252`. Files without one, which includes OFX 2.x, are UTF-8 or
+/// else taken to be Windows-1252.
+fn decode(data: &[u8]) -> String {
+    let header_end = data.iter().position(|it| *it == b'<').unwrap_or(data.len());
+    let header = String::from_utf8_lossy(&data[..header_end]);
+    let header_value = |key: &str| {
+        header
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(key))
+            .map(str::trim)
+    };
+    let encoding = match (header_value("ENCODING:"), header_value("CHARSET:")) {
+        (Some(encoding), _) if encoding.eq_ignore_ascii_case("UTF-8") => Some(encoding_rs::UTF_8),
+        // Windows code pages are given by number
+        (_, Some(charset)) if charset.bytes().all(|it| it.is_ascii_digit()) => {
+            encoding_rs::Encoding::for_label(format!("windows-{}", charset).as_bytes())
+        }
+        (_, Some(charset)) => encoding_rs::Encoding::for_label(charset.as_bytes()),
+        _ => None,
+    };
+
+    match encoding {
+        Some(encoding) => encoding.decode(data).0.into_owned(),
+        None => match std::str::from_utf8(data) {
+            Ok(data) => data.to_string(),
+            Err(_) => encoding_rs::WINDOWS_1252.decode(data).0.into_owned(),
+        },
+    }
+}
+
+/// An OFX element. SGML leaf elements have no closing tag, so are
+/// represented the same way as XML elements with only text content.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    /// First descendant with this name, depth first
+    fn find(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find_map(|child| {
+            if child.name == name {
+                Some(child)
+            } else {
+                child.find(name)
+            }
+        })
+    }
+
+    /// Every descendant with this name, not looking inside matches
+    fn find_all<'a>(&'a self, name: &str, found: &mut Vec<&'a Element>) {
+        for child in &self.children {
+            if child.name == name {
+                found.push(child);
+            } else {
+                child.find_all(name, found);
+            }
+        }
+    }
+
+    fn child_text(&self, name: &str) -> Option<&str> {
+        self.children
+            .iter()
+            .find(|it| it.name == name)
+            .map(|it| it.text.as_str())
+            .filter(|it| !it.is_empty())
+    }
+
+    fn required_text(&self, name: &str) -> Result<&str> {
+        self.child_text(name)
+            .ok_or_else(|| anyhow!("Missing {} in {}", name, self.name))
+    }
+}
+
+/// Parses the body of an OFX file, skipping the SGML header or XML prolog.
+fn parse(data: &str) -> Result<Element> {
+    let start = data
+        .to_ascii_uppercase()
+        .find("<OFX>")
+        .ok_or_else(|| anyhow!("No <OFX> element found"))?;
+    let mut rest = &data[start..];
+
+    let mut stack = vec![Element::default()];
+    while !rest.is_empty() {
+        let tag_start = rest.find('<').unwrap_or(rest.len());
+        let text = rest[..tag_start].trim();
+        if !text.is_empty() {
+            stack.last_mut().unwrap().text = decode_entities(text);
+        }
+        rest = &rest[tag_start..];
+        if rest.is_empty() {
+            break;
+        }
+
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map(|it| it + 3).unwrap_or(rest.len());
+            rest = &rest[end..];
+            continue;
+        }
+        let tag_end = rest
+            .find('>')
+            .ok_or_else(|| anyhow!("Unterminated tag {}", rest))?;
+        let tag = &rest[1..tag_end];
+        rest = &rest[(tag_end + 1)..];
+
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim().to_ascii_uppercase();
+            // Close any unclosed SGML leaves inside this element
+            if stack.iter().skip(1).any(|it| it.name == name) {
+                loop {
+                    let element = stack.pop().unwrap();
+                    if element.name == name {
+                        stack.last_mut().unwrap().children.push(element);
+                        break;
+                    }
+                    close_leaf(stack.last_mut().unwrap(), element);
+                }
+            }
+            continue;
+        }
+
+        // An element which already has text is an SGML leaf, ended by this tag
+        if stack.len() > 1 && !stack.last().unwrap().text.is_empty() {
+            let leaf = stack.pop().unwrap();
+            stack.last_mut().unwrap().children.push(leaf);
+        }
+        let self_closing = tag.ends_with('/');
+        let name = tag
+            .trim_end_matches('/')
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_ascii_uppercase();
+        let element = Element {
+            name,
+            ..Default::default()
+        };
+        if self_closing {
+            stack.last_mut().unwrap().children.push(element);
+        } else {
+            stack.push(element);
+        }
+    }
+
+    while stack.len() > 1 {
+        let element = stack.pop().unwrap();
+        close_leaf(stack.last_mut().unwrap(), element);
+    }
+    let root = stack.pop().unwrap();
+    root.children
+        .into_iter()
+        .find(|it| it.name == "OFX")
+        .ok_or_else(|| anyhow!("No <OFX> element found"))
+}
+
+/// Adds an element which was never closed, so is an SGML leaf. An empty
+/// leaf, like `<MEMO>` straight before `<NAME>`, looked like an aggregate
+/// while parsing, so the elements read into it are moved back out after it.
+fn close_leaf(parent: &mut Element, mut leaf: Element) {
+    let siblings = std::mem::take(&mut leaf.children);
+    parent.children.push(leaf);
+    parent.children.extend(siblings);
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+fn read_statements(ofx: &Element) -> Result<Vec<Statement>> {
+    let mut statements = vec![];
+    let mut bank = vec![];
+    ofx.find_all("STMTRS", &mut bank);
+    for statement in bank {
+        statements.push(read_statement(statement, AccountType::Account)?);
+    }
+    let mut cards = vec![];
+    ofx.find_all("CCSTMTRS", &mut cards);
+    for statement in cards {
+        statements.push(read_statement(statement, AccountType::Card)?);
+    }
+    Ok(statements)
+}
+
+fn read_statement(statement: &Element, ty: AccountType) -> Result<Statement> {
+    let currency = statement.required_text("CURDEF")?.to_string();
+    let from = statement
+        .find("BANKACCTFROM")
+        .or_else(|| statement.find("CCACCTFROM"))
+        .ok_or_else(|| anyhow!("Missing account details in {}", statement.name))?;
+    let account_number = from.required_text("ACCTID")?;
+    let bank_id = from.child_text("BANKID");
+
+    let account_id = match bank_id {
+        Some(bank_id) => format!("ofx:{}:{}", bank_id, account_number),
+        None => format!("ofx:{}", account_number),
+    };
+    let identifiers = match ty {
+        AccountType::Card => AccountIdentifiers {
+            partial_card_number: Some(last_digits(account_number, 4)),
+            ..Default::default()
+        },
+        // UK banks give the sort code as the bank ID
+        AccountType::Account => match bank_id {
+            Some(sort_code)
+                if sort_code.len() == 6 && sort_code.chars().all(|c| c.is_ascii_digit()) =>
+            {
+                AccountIdentifiers {
+                    sort_code: Some(sort_code.to_string()),
+                    account_number: Some(account_number.to_string()),
+                    ..Default::default()
+                }
+            }
+            _ if account_number.len() > 14
+                && account_number
+                    .chars()
+                    .take(2)
+                    .all(|c| c.is_ascii_alphabetic()) =>
+            {
+                AccountIdentifiers {
+                    iban: Some(account_number.to_string()),
+                    ..Default::default()
+                }
+            }
+            _ => AccountIdentifiers {
+                account_number: Some(account_number.to_string()),
+                ..Default::default()
+            },
+        },
+    };
+
+    // Required, as reconciliation would otherwise zero the YNAB account
+    let ledger = statement
+        .find("LEDGERBAL")
+        .ok_or_else(|| anyhow!("Missing LEDGERBAL in {}", statement.name))?;
+    let balance = parse_amount(ledger.required_text("BALAMT")?)?;
+    let balance_date = match ledger.child_text("DTASOF") {
+        Some(date) => Some(parse_date(date)?),
+        None => None,
+    };
+    let available = match statement
+        .find("AVAILBAL")
+        .and_then(|it| it.child_text("BALAMT"))
+    {
+        Some(amount) => Some(parse_amount(amount)?),
+        None => None,
+    };
+
+    let mut transactions = vec![];
+    if let Some(list) = statement.find("BANKTRANLIST") {
+        let mut entries = vec![];
+        list.find_all("STMTTRN", &mut entries);
+        for entry in entries {
+            transactions.push(read_transaction(entry)?);
+        }
+    }
+
+    Ok(Statement {
+        account: crate::Account {
+            display_name: match ty {
+                AccountType::Card => format!("Card {}", last_digits(account_number, 4)),
+                AccountType::Account => account_number.to_string(),
+            },
+            account_id,
+            currency,
+            ty,
+            balance: Some(balance),
+            balances: crate::Balances {
+                available,
+                ..Default::default()
+            },
+            identifiers,
+        },
+        balance_date,
+        transactions,
+    })
+}
+
+fn read_transaction(entry: &Element) -> Result<crate::Transaction> {
+    let name = entry
+        .child_text("NAME")
+        .or_else(|| entry.find("PAYEE").and_then(|it| it.child_text("NAME")));
+    let memo = entry.child_text("MEMO");
+    let date = entry
+        .child_text("DTPOSTED")
+        .or_else(|| entry.child_text("DTUSER"))
+        .ok_or_else(|| anyhow!("Missing DTPOSTED in STMTTRN"))?;
+
+    Ok(crate::Transaction {
+        transaction_id: entry.required_text("FITID")?.to_string(),
+        timestamp: parse_date(date)?,
+        amount: parse_amount(entry.required_text("TRNAMT")?)?,
+        description: memo.or(name).unwrap_or("").to_string(),
+        payee_name: name.map(|it| it.to_string()),
+        category: None,
+        state: TransactionState::Cleared,
+        running_balance: None,
+        splits: vec![],
+        exchanged: None,
+    })
+}
+
+fn last_digits(value: &str, count: usize) -> String {
+    let digits: Vec<char> = value.chars().filter(|c| c.is_ascii_digit()).collect();
+    digits[digits.len().saturating_sub(count)..]
+        .iter()
+        .collect()
+}
+
+fn parse_amount(value: &str) -> Result<i64> {
+    let amount: f64 = value
+        .trim()
+        .replace(',', ".")
+        .parse()
+        .with_context(|| format!("Invalid amount {}", value))?;
+    Ok((amount * 1000.0).round() as i64)
+}
+
+/// Parses OFX dates such as `20210304`, `20210304120000` or
+/// `20210304120000.000[-5:EST]`
+fn parse_date(value: &str) -> Result<UtcDateTime> {
+    let invalid = || anyhow!("Invalid date {}", value);
+    let digits: String = value
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .filter(|c| c.is_ascii_digit())
+        .collect();
+    let date = NaiveDate::parse_from_str(digits.get(..8).ok_or_else(invalid)?, "%Y%m%d")
+        .map_err(|_| invalid())?;
+    let time = match digits.get(8..14) {
+        Some(time) => chrono::NaiveTime::parse_from_str(time, "%H%M%S").map_err(|_| invalid())?,
+        None => chrono::NaiveTime::from_hms(0, 0, 0),
+    };
+
+    let offset_hours: f64 = match value.find('[') {
+        Some(start) => value[(start + 1)..]
+            .split([':', ']'])
+            .next()
+            .and_then(|it| it.parse().ok())
+            .ok_or_else(invalid)?,
+        None => 0.0,
+    };
+    let offset = chrono::Duration::minutes((offset_hours * 60.0) as i64);
+
+    Ok(Utc.from_utc_datetime(&(date.and_time(time) - offset)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+
+    const SGML: &str = "\
+OFXHEADER:100
+DATA:OFXSGML
+VERSION:102
+ENCODING:USASCII
+
+<OFX>
+<SIGNONMSGSRSV1><SONRS><STATUS><CODE>0<SEVERITY>INFO</STATUS><DTSERVER>20210310</SONRS></SIGNONMSGSRSV1>
+<BANKMSGSRSV1><STMTTRNRS><TRNUID>1<STMTRS>
+<CURDEF>GBP
+<BANKACCTFROM><BANKID>123456<ACCTID>12345678<ACCTTYPE>CHECKING</BANKACCTFROM>
+<BANKTRANLIST><DTSTART>20210301<DTEND>20210310
+<STMTTRN><TRNTYPE>DEBIT<DTPOSTED>20210304120000.000[-5:EST]<TRNAMT>-12.50<FITID>abc1<NAME>TESCO &amp; CO<MEMO>Card payment</STMTTRN>
+<STMTTRN><TRNTYPE>CREDIT<DTPOSTED>20210305<TRNAMT>1000.00<FITID>abc2<NAME>Salary</STMTTRN>
+</BANKTRANLIST>
+<LEDGERBAL><BALAMT>987.50<DTASOF>20210310</LEDGERBAL>
+</STMTRS></STMTTRNRS></BANKMSGSRSV1>
+</OFX>
+";
+
+    const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<?OFX OFXHEADER="200" VERSION="220"?>
+<OFX>
+  <CREDITCARDMSGSRSV1><CCSTMTTRNRS><CCSTMTRS>
+    <CURDEF>USD</CURDEF>
+    <CCACCTFROM><ACCTID>4111111111114321</ACCTID></CCACCTFROM>
+    <BANKTRANLIST>
+      <STMTTRN><TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>20210306</DTPOSTED><TRNAMT>-3.00</TRNAMT><FITID>x1</FITID><NAME>Cafe</NAME></STMTTRN>
+    </BANKTRANLIST>
+    <LEDGERBAL><BALAMT>-3.00</BALAMT><DTASOF>20210310</DTASOF></LEDGERBAL>
+    <AVAILBAL><BALAMT>997.00</BALAMT><DTASOF>20210310</DTASOF></AVAILBAL>
+  </CCSTMTRS></CCSTMTTRNRS></CREDITCARDMSGSRSV1>
+</OFX>
+"#;
+
+    #[test]
+    fn sgml_statement() -> Result<()> {
+        let statements = super::read_statements(&super::parse(SGML)?)?;
+        // Same download twice, as overlapping files would be
+        let provider = crate::files::combine_statements(
+            "test",
+            statements
+                .into_iter()
+                .chain(super::read_statements(&super::parse(SGML)?)?)
+                .collect(),
+        );
+
+        let account = &provider.accounts[0];
+        assert_eq!("ofx:123456:12345678", account.account_id);
+        assert_eq!(Some(987500), account.balance);
+        assert_eq!(Some("123456"), account.identifiers.sort_code.as_deref());
+
+        let transactions: Vec<_> = provider
+            .transactions
+            .iter()
+            .map(|(_, it)| {
+                (
+                    it.transaction_id.as_str(),
+                    it.amount,
+                    it.payee_name.as_deref(),
+                    it.timestamp.to_rfc3339(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "abc1",
+                    -12500,
+                    Some("TESCO & CO"),
+                    "2021-03-04T17:00:00+00:00".to_string()
+                ),
+                (
+                    "abc2",
+                    1000000,
+                    Some("Salary"),
+                    "2021-03-05T00:00:00+00:00".to_string()
+                ),
+            ],
+            transactions
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn xml_card_statement() -> Result<()> {
+        let provider =
+            crate::files::combine_statements("test", super::read_statements(&super::parse(XML)?)?);
+
+        let account = &provider.accounts[0];
+        assert_eq!("ofx:4111111111114321", account.account_id);
+        assert_eq!(Some(-3000), account.balance);
+        assert_eq!(Some(997000), account.balances.available);
+        assert_eq!(
+            Some("4321"),
+            account.identifiers.partial_card_number.as_deref()
+        );
+        assert_eq!("x1", provider.transactions[0].1.transaction_id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sgml_empty_leaf_and_charset() -> Result<()> {
+        let sgml = SGML
+            .replace("ENCODING:USASCII", "ENCODING:USASCII\nCHARSET:1252")
+            .replace("<NAME>Salary", "<MEMO><NAME>Caf\u{e9} cr\u{e8}me");
+        let (data, _, _) = encoding_rs::WINDOWS_1252.encode(&sgml);
+        let document = super::parse(&super::decode(&data))?;
+        let provider = crate::files::combine_statements("test", super::read_statements(&document)?);
+
+        // The empty MEMO doesn't swallow the NAME after it
+        let salary = &provider.transactions[1].1;
+        assert_eq!("abc2", salary.transaction_id);
+        assert_eq!(Some("Caf\u{e9} cr\u{e8}me"), salary.payee_name.as_deref());
+
+        Ok(())
+    }
+
+    #[test]
+    fn newest_balance_is_used() -> Result<()> {
+        let older = SGML
+            .replace(
+                "<BALAMT>987.50<DTASOF>20210310",
+                "<BALAMT>500.00<DTASOF>20210228",
+            )
+            .replace("TESCO", "Tesco");
+        let statements = [SGML.to_string(), older]
+            .iter()
+            .map(|it| super::read_statements(&super::parse(it)?))
+            .collect::<Result<Vec<_>>>()?;
+        let provider =
+            crate::files::combine_statements("test", statements.into_iter().flatten().collect());
+
+        assert_eq!(1, provider.accounts.len());
+        assert_eq!(Some(987500), provider.accounts[0].balance);
+        // Same FITIDs, so read once
+        assert_eq!(2, provider.transactions.len());
+        assert_eq!(
+            Some("TESCO & CO"),
+            provider.transactions[0].1.payee_name.as_deref()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+//! Helpers shared by the providers which call a bank's API directly.
+
+use crate::prelude::*;
+use chrono::Utc;
+
+pub const DEFAULT_HISTORY_DAYS: i64 = 90;
+
+pub fn default_history_days() -> i64 {
+    DEFAULT_HISTORY_DAYS
+}
+
+/// How far back transactions are requested: the token's `history_days` on
+/// each sync, or from the start of a backfill with `sync --from`.
+#[derive(Debug, Clone, Copy)]
+pub struct History {
+    days: i64,
+    start: Option<UtcDate>,
+}
+
+impl History {
+    pub fn new(days: i64) -> History {
+        History { days, start: None }
+    }
+
+    pub fn set_start(&mut self, from: UtcDate) {
+        self.start = Some(from);
+    }
+
+    pub fn from(&self) -> UtcDateTime {
+        match self.start {
+            Some(date) => date.and_hms(0, 0, 0),
+            None => Utc::now() - chrono::Duration::days(self.days),
+        }
+    }
+}
+
+/// A client for an API authenticated with a bearer token, if one is given
+pub fn rest_client(base_url: &str, bearer: Option<&str>) -> Result<RestClient> {
+    let mut rc = restson::RestClient::builder()
+        .blocking(base_url)
+        .with_context(|| format!("Invalid api url {}", base_url))?;
+
+    let header_fail = "Header should be valid";
+
+    if let Some(bearer) = bearer {
+        rc.set_header("Authorization", &("Bearer ".to_string() + bearer))
+            .expect(header_fail);
+    }
+    rc.set_header("User-Agent", "moonstruck.dev/import-ynab")
+        .expect(header_fail);
+
+    Ok(rc)
+}
+
+/// Implements `Debug` as the provider's type and display name, which is all
+/// the sync log needs, without printing the clients and tokens it holds.
+macro_rules! debug_as_display_name {
+    ($provider:ident) => {
+        impl std::fmt::Debug for $provider {
+            fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(fmt, "{}: {}", stringify!($provider), &self.display_name)
+            }
+        }
+    };
+}
+
+pub(crate) use debug_as_display_name;
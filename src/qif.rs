@@ -0,0 +1,408 @@
+//! Reads QIF files, which older bank and credit card portals still export.
+//!
+//! QIF has no transaction IDs, balances or currency, and its dates are
+//! written in the exporting machine's locale.
+
+use crate::files::StatementProvider;
+use crate::import_id::ImportIds;
+use crate::prelude::*;
+use crate::{AccountType, ConnectedProvider, Split, TransactionState};
+use anyhow::bail;
+use chrono::{NaiveDate, TimeZone, Utc};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const IMPORT_ID_PREFIX: &str = "QIF";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Source {
+    pub display_name: String,
+    /// A QIF file, or a directory of them
+    pub path: PathBuf,
+    /// QIF files don't say which currency they're in
+    pub currency: String,
+    #[serde(default)]
+    pub date_format: DateFormat,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DateFormat {
+    /// Work out the order from dates which can only be read one way
+    #[default]
+    Auto,
+    DayMonthYear,
+    MonthDayYear,
+    YearMonthDay,
+}
+
+impl FromStr for DateFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let format = match value.to_ascii_lowercase().replace('-', "_").as_str() {
+            "auto" => DateFormat::Auto,
+            "dmy" | "day_month_year" => DateFormat::DayMonthYear,
+            "mdy" | "month_day_year" => DateFormat::MonthDayYear,
+            "ymd" | "year_month_day" => DateFormat::YearMonthDay,
+            _ => bail!(
+                "Unknown date format {}, expected dmy, mdy, ymd or auto",
+                value
+            ),
+        };
+        Ok(format)
+    }
+}
+
+pub fn initialize(source: &mut Source) -> (bool, Result<Box<dyn ConnectedProvider>>) {
+    (
+        false,
+        load(source).map(|it| Box::new(it) as Box<dyn ConnectedProvider>),
+    )
+}
+
+/// A transaction as written in the file, before dates are interpreted
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Record {
+    account: Option<String>,
+    ty: Option<String>,
+    date: String,
+    amount: i64,
+    payee: Option<String>,
+    memo: Option<String>,
+    category: Option<String>,
+    splits: Vec<Split>,
+}
+
+fn load(source: &Source) -> Result<StatementProvider> {
+    let mut records: Vec<Record> = vec![];
+    for file in crate::files::statement_files(&source.path, &["qif"])? {
+        let data =
+            std::fs::read(&file).with_context(|| format!("Error reading {}", file.display()))?;
+        let file_records = parse(&String::from_utf8_lossy(&data))
+            .with_context(|| format!("Error parsing {}", file.display()))?;
+        crate::files::merge_overlapping(&mut records, file_records);
+    }
+
+    convert(source, records)
+}
+
+fn parse(data: &str) -> Result<Vec<Record>> {
+    let mut records = vec![];
+    let mut account: Option<String> = None;
+    let mut ty: Option<String> = None;
+    let mut in_account_header = false;
+    let mut record = Record::default();
+
+    for line in data.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('!') {
+            let header = header.trim();
+            if header.eq_ignore_ascii_case("Account") {
+                in_account_header = true;
+            } else if let Some(account_type) = header.strip_prefix("Type:") {
+                in_account_header = false;
+                ty = Some(account_type.trim().to_string());
+            }
+            continue;
+        }
+
+        let (code, value) = line.split_at(line.chars().next().unwrap().len_utf8());
+        let value = value.trim();
+        if in_account_header {
+            match code {
+                "N" => account = Some(value.to_string()),
+                "^" => in_account_header = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match code {
+            "D" => record.date = value.to_string(),
+            "T" | "U" => record.amount = parse_amount(value)?,
+            "P" => record.payee = Some(value.to_string()).filter(|it| !it.is_empty()),
+            "M" => record.memo = Some(value.to_string()).filter(|it| !it.is_empty()),
+            "L" => record.category = category(value),
+            "S" => record.splits.push(Split {
+                amount: 0,
+                category: category(value),
+                memo: String::new(),
+            }),
+            "E" => {
+                if let Some(split) = record.splits.last_mut() {
+                    split.memo = value.to_string();
+                }
+            }
+            "$" => {
+                if let Some(split) = record.splits.last_mut() {
+                    split.amount = parse_amount(value)?;
+                }
+            }
+            "^" => {
+                if record.date.is_empty() {
+                    bail!("Transaction without a date: {:?}", record);
+                }
+                record.account = account.clone();
+                record.ty = ty.clone();
+                records.push(std::mem::take(&mut record));
+            }
+            // Cleared status, cheque numbers, addresses and investment fields
+            _ => {}
+        }
+    }
+
+    Ok(records)
+}
+
+/// Reads a category such as `Food:Groceries/Class`, keeping the last part
+/// of the category. Transfers such as `[Savings]` have no category.
+fn category(value: &str) -> Option<String> {
+    if value.starts_with('[') {
+        return None;
+    }
+    let category = value.split('/').next()?;
+    let category = category.rsplit(':').next()?.trim();
+    Some(category.to_string()).filter(|it| !it.is_empty())
+}
+
+fn convert(source: &Source, records: Vec<Record>) -> Result<StatementProvider> {
+    let date_format = match source.date_format {
+        DateFormat::Auto => detect_date_format(records.iter().map(|it| it.date.as_str()))?,
+        format => format,
+    };
+
+    let mut records = records
+        .into_iter()
+        .map(|record| Ok((parse_date(&record.date, date_format)?, record)))
+        .collect::<Result<Vec<_>>>()?;
+    records.sort_by_key(|(date, _)| *date);
+
+    let mut provider = StatementProvider {
+        display_name: source.display_name.clone(),
+        accounts: vec![],
+        transactions: vec![],
+    };
+    let mut import_ids: HashMap<String, ImportIds> = HashMap::new();
+    for (date, record) in records {
+        let name = record.account.as_deref().unwrap_or(&source.display_name);
+        let account_id = format!("qif:{}", name);
+        if !provider
+            .accounts
+            .iter()
+            .any(|it| it.account_id == account_id)
+        {
+            provider.accounts.push(crate::Account {
+                account_id: account_id.clone(),
+                currency: source.currency.clone(),
+                display_name: name.to_string(),
+                ty: match record.ty.as_deref() {
+                    Some(ty) if ty.eq_ignore_ascii_case("CCard") => AccountType::Card,
+                    _ => AccountType::Account,
+                },
+                balance: None,
+                balances: Default::default(),
+                identifiers: Default::default(),
+            });
+        }
+
+        let description = record.memo.clone().unwrap_or_default();
+        let transaction_id = import_ids
+            .entry(account_id.clone())
+            .or_default()
+            .next_hashed(
+                IMPORT_ID_PREFIX,
+                date,
+                record.amount,
+                &format!("{} {}", record.payee.as_deref().unwrap_or(""), description),
+            );
+        provider.transactions.push((
+            account_id,
+            crate::Transaction {
+                transaction_id,
+                timestamp: date.and_hms(0, 0, 0),
+                amount: record.amount,
+                description,
+                payee_name: record.payee,
+                category: record.category,
+                state: TransactionState::Cleared,
+                running_balance: None,
+                splits: record.splits,
+                exchanged: None,
+            },
+        ));
+    }
+
+    Ok(provider)
+}
+
+/// Splits a date such as `03/04/2021`, `3/ 4'21` or `2021-03-04` into its
+/// three numbers.
+fn date_parts(value: &str) -> Option<[(u32, usize); 3]> {
+    let mut parts = value
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|it| !it.is_empty())
+        .map(|it| Some((it.parse().ok()?, it.len())));
+    let parts = [parts.next()??, parts.next()??, parts.next()??];
+    Some(parts)
+}
+
+fn detect_date_format<'a>(dates: impl Iterator<Item = &'a str>) -> Result<DateFormat> {
+    let mut ambiguous = None;
+    for date in dates {
+        let [(first, first_len), (second, _), _] =
+            date_parts(date).ok_or_else(|| anyhow!("Invalid date {}", date))?;
+        if first_len == 4 {
+            return Ok(DateFormat::YearMonthDay);
+        } else if first > 12 {
+            return Ok(DateFormat::DayMonthYear);
+        } else if second > 12 {
+            return Ok(DateFormat::MonthDayYear);
+        }
+        ambiguous = Some(date);
+    }
+
+    match ambiguous {
+        Some(date) => bail!(
+            "Can't tell if dates like {} are day/month or month/day, set date_format",
+            date
+        ),
+        None => Ok(DateFormat::DayMonthYear),
+    }
+}
+
+fn parse_date(value: &str, format: DateFormat) -> Result<UtcDate> {
+    let invalid = || anyhow!("Invalid date {}", value);
+    let [first, second, third] = date_parts(value).ok_or_else(invalid)?;
+    let ((year, year_len), month, day) = match format {
+        DateFormat::DayMonthYear | DateFormat::Auto => (third, second.0, first.0),
+        DateFormat::MonthDayYear => (third, first.0, second.0),
+        DateFormat::YearMonthDay => (first, second.0, third.0),
+    };
+    let year = match (year, year_len) {
+        (year, 4) => year,
+        (year, _) if year < 70 => 2000 + year,
+        (year, _) => 1900 + year,
+    };
+    let date = NaiveDate::from_ymd_opt(year as i32, month, day).ok_or_else(invalid)?;
+    Ok(Utc.from_utc_date(&date))
+}
+
+/// Parses amounts such as `-1,234.56`, or `-1.234,56` in locales which use
+/// a decimal comma.
+fn parse_amount(value: &str) -> Result<i64> {
+    let decimal_comma = match (value.rfind(','), value.rfind('.')) {
+        (Some(comma), Some(dot)) => comma > dot,
+        (Some(comma), None) => value.len() - comma <= 3,
+        _ => false,
+    };
+    let normalised: String = if decimal_comma {
+        value.replace('.', "").replace(',', ".")
+    } else {
+        value.replace(',', "")
+    };
+    let amount: f64 = normalised
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid amount {}", value))?;
+    Ok((amount * 1000.0).round() as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::DateFormat;
+    use crate::prelude::*;
+
+    const QIF: &str = "\
+!Type:Bank
+D03/04'21
+T-1,012.50
+PTesco
+MWeekly shop
+SFood:Groceries
+EFood
+$-1,000.00
+SHousehold
+$-12.50
+^
+D03/04'21
+T-12.50
+PTesco
+LFood:Groceries
+^
+D13/04'21
+T500.00
+PEmployer
+L[Savings]
+^
+";
+
+    fn source(date_format: DateFormat) -> super::Source {
+        super::Source {
+            display_name: "Bank".to_string(),
+            path: Default::default(),
+            currency: "GBP".to_string(),
+            date_format,
+        }
+    }
+
+    #[test]
+    fn qif() -> Result<()> {
+        let mut records = vec![];
+        crate::files::merge_overlapping(&mut records, super::parse(QIF)?);
+        // Overlapping export
+        crate::files::merge_overlapping(&mut records, super::parse(QIF)?);
+        let provider = super::convert(&source(DateFormat::Auto), records)?;
+
+        assert_eq!("qif:Bank", provider.accounts[0].account_id);
+        assert_eq!(None, provider.accounts[0].balance);
+
+        let transactions: Vec<_> = provider.transactions.iter().map(|(_, it)| it).collect();
+        assert_eq!(3, transactions.len());
+        assert_eq!(
+            "2021-04-03",
+            transactions[0].timestamp.format("%Y-%m-%d").to_string()
+        );
+        assert_eq!(-1012500, transactions[0].amount);
+        assert_eq!(
+            vec![
+                (-1000000, Some("Groceries"), "Food"),
+                (-12500, Some("Household"), "")
+            ],
+            transactions[0]
+                .splits
+                .iter()
+                .map(|it| (it.amount, it.category.as_deref(), it.memo.as_str()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some("Groceries"), transactions[1].category.as_deref());
+        assert_eq!(None, transactions[2].category);
+        assert!(transactions[0]
+            .transaction_id
+            .starts_with("QIF:-1012500:2021-04-03:"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ambiguous_dates() -> Result<()> {
+        let records = super::parse("!Type:Bank\nD03/04/2021\nT1.00\n^\n")?;
+        assert!(super::convert(&source(DateFormat::Auto), records.clone()).is_err());
+
+        let provider = super::convert(&source(DateFormat::MonthDayYear), records)?;
+        assert_eq!(
+            "2021-03-04",
+            provider.transactions[0]
+                .1
+                .timestamp
+                .format("%Y-%m-%d")
+                .to_string()
+        );
+        assert_eq!(-1234560, super::parse_amount("-1.234,56")?);
+
+        Ok(())
+    }
+}
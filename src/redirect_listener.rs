@@ -0,0 +1,182 @@
+//! Minimal HTTP listener which captures the authorization code from an
+//! OAuth redirect to `http://localhost:<port>/`, so it doesn't need to be
+//! copied by hand.
+
+use crate::prelude::*;
+use anyhow::bail;
+use oauth2::url::{form_urlencoded, Url};
+use oauth2::CsrfToken;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn redirect_url(port: u16) -> String {
+    format!("http://localhost:{}/", port)
+}
+
+/// Waits for the OAuth provider to redirect the browser back to us and
+/// returns the authorization code, once the state parameter has been checked
+/// against the one sent in the authorization URL.
+pub fn receive_code(port: u16, state: &CsrfToken) -> Result<String> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .with_context(|| format!("Couldn't listen on port {}", port))?;
+    println!("Waiting for redirect to {}", redirect_url(port));
+    receive_code_from(&listener, state)
+}
+
+fn receive_code_from(listener: &TcpListener, state: &CsrfToken) -> Result<String> {
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        // Don't let a connection which never sends a request block the redirect
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let params = match read_params(&mut stream) {
+            Ok(params) => params,
+            // Anything else on the machine may connect, so keep waiting
+            Err(e) => {
+                eprintln!("Ignoring unreadable request: {:#}", e);
+                let _ = respond(&mut stream, "400 Bad Request", "Couldn't read request");
+                continue;
+            }
+        };
+
+        if let Some(error) = params.get("error") {
+            respond(&mut stream, "400 Bad Request", "Authorization failed")?;
+            bail!(
+                "Authorization failed: {} {}",
+                error,
+                params
+                    .get("error_description")
+                    .map(String::as_str)
+                    .unwrap_or("")
+            );
+        }
+
+        let code = match params.get("code") {
+            Some(code) => code,
+            // Browsers also ask for things like /favicon.ico
+            None => {
+                respond(&mut stream, "404 Not Found", "Not found")?;
+                continue;
+            }
+        };
+
+        if params.get("state").map(String::as_str) != Some(state.secret().as_str()) {
+            respond(&mut stream, "400 Bad Request", "Invalid state parameter")?;
+            bail!("State parameter didn't match, not continuing with authorization");
+        }
+
+        respond(
+            &mut stream,
+            "200 OK",
+            "Authorized, you can close this window and return to import-ynab",
+        )?;
+        return Ok(code.clone());
+    }
+
+    bail!("Listener closed before a redirect was received")
+}
+
+/// Reads the query string parameters of a GET request, or the form
+/// parameters of a POST for providers using `response_mode=form_post`.
+fn read_params(stream: &mut TcpStream) -> Result<HashMap<String, String>> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let target = request_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| anyhow!("Invalid request line {}", request_line.trim()))?;
+    let url = Url::parse("http://localhost")?.join(target)?;
+    let mut params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+
+    if content_length > 0 {
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        params.extend(form_urlencoded::parse(&body).into_owned());
+    }
+
+    Ok(params)
+}
+
+fn respond(stream: &mut TcpStream, status: &str, message: &str) -> Result<()> {
+    let body = format!("<html><body><p>{}</p></body></html>", message);
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use oauth2::CsrfToken;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    /// Sends each request in turn and returns what the listener received.
+    fn redirect(requests: &[&str]) -> Result<String> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let port = listener.local_addr()?.port();
+        let state = CsrfToken::new("expected".to_string());
+        let receiver = std::thread::spawn(move || super::receive_code_from(&listener, &state));
+
+        for request in requests {
+            let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+            stream.write_all(request.as_bytes())?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+        }
+
+        receiver.join().unwrap()
+    }
+
+    #[test]
+    fn state_is_checked() -> Result<()> {
+        assert_eq!(
+            "abc",
+            redirect(&["GET /?code=abc&state=expected HTTP/1.1\r\nHost: localhost\r\n\r\n"])?
+        );
+        assert!(
+            redirect(&["GET /?code=abc&state=forged HTTP/1.1\r\nHost: localhost\r\n\r\n"]).is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_requests_are_skipped() -> Result<()> {
+        assert_eq!(
+            "abc",
+            redirect(&[
+                "nonsense\r\n\r\n",
+                "GET /favicon.ico HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                "GET /?code=abc&state=expected HTTP/1.1\r\nHost: localhost\r\n\r\n"
+            ])?
+        );
+
+        Ok(())
+    }
+}
@@ -1,9 +1,13 @@
+use crate::config::CategoryMap;
 use crate::prelude::*;
 use crate::revolut::api::Transaction;
+use crate::secret::Secret;
 use crate::{AccountType, ConnectedProvider};
 use restson::Response;
 use std::fmt::{Debug, Formatter};
 
+pub mod statement;
+
 const HEADER_FAIL: &str = "Header should be valid";
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -11,7 +15,10 @@ pub struct Token {
     pub display_name: String,
     pub device_id: String,
     pub username: String,
-    pub password: String,
+    pub password: Secret,
+    /// Revolut categories or merchant category codes to YNAB category names
+    #[serde(default)]
+    pub category_map: CategoryMap,
 }
 
 pub fn initialize(token: &mut Token) -> (bool, Result<Box<dyn ConnectedProvider>>) {
@@ -28,6 +35,7 @@ pub fn initialize(token: &mut Token) -> (bool, Result<Box<dyn ConnectedProvider>
             accounts,
             beneficiaries,
             transactions: transactions.transactions,
+            category_map: token.category_map.clone(),
         })),
     )
 }
@@ -140,6 +148,7 @@ pub mod api {
         pub direction: Option<String>,
         pub counterpart: Option<TransactionCounterpart>,
         pub state: Option<String>,
+        pub category: Option<String>,
     }
 
     #[derive(Serialize, Deserialize, Debug)]
@@ -164,6 +173,9 @@ pub mod api {
     #[serde(rename_all = "camelCase")]
     pub struct TransactionMerchant {
         pub name: String,
+        #[serde(default, deserialize_with = "crate::serialisation::deserialize_code")]
+        pub mcc: Option<String>,
+        pub category: Option<String>,
     }
 
     impl RestPath<()> for Transactions {
@@ -222,7 +234,7 @@ impl Client {
     }
 
     pub fn auth(&mut self, auth: &Token) {
-        self.rc.set_auth(&auth.username, &auth.password);
+        self.rc.set_auth(&auth.username, auth.password.secret());
     }
 
     pub fn get_transactions(&mut self) -> api::Transactions {
@@ -257,6 +269,7 @@ struct RevolutProvider {
     accounts: api::Accounts,
     beneficiaries: api::Beneficiaries,
     transactions: Vec<Transaction>,
+    category_map: CategoryMap,
 }
 
 impl Debug for RevolutProvider {
@@ -279,8 +292,10 @@ impl ConnectedProvider for RevolutProvider {
                 account_id: acc.id.clone(),
                 currency: acc.currency.clone(),
                 display_name: acc.currency.clone(),
-                balance: acc.balance * 10,
+                balance: Some(acc.balance * 10),
                 ty: AccountType::Account,
+                balances: Default::default(),
+                identifiers: Default::default(),
             })
             .collect())
     }
@@ -291,10 +306,6 @@ impl ConnectedProvider for RevolutProvider {
             .iter()
             .filter(|it| it.account.id == acc.account_id)
             .filter(|it| it.ty != "EXCHANGE" || it.direction.as_deref() == Some("buy"))
-            .filter(|it| {
-                it.ty != "CARD_PAYMENT"
-                    || matches!(it.state.as_deref(), Some("COMPLETED") | Some("PENDING"))
-            })
             .map(|tran| {
                 let transaction_id = tran.id.clone();
                 let payee_name =
@@ -308,6 +319,10 @@ impl ConnectedProvider for RevolutProvider {
                             .map(|it| format!("{} {}", it.first_name, it.last_name))
                     } else if Some("GOOGLE_PAY") == tran.entry_mode.as_deref() {
                         Some("Google Pay Topup".to_string())
+                    } else if "CARD_REFUND" == &tran.ty {
+                        self.refunded_payment(tran)
+                            .and_then(|it| it.merchant.as_ref())
+                            .map(|it| it.name.clone())
                     } else if "TOPUP" == &tran.ty {
                         tran.description
                             .as_ref()
@@ -332,9 +347,65 @@ impl ConnectedProvider for RevolutProvider {
                     amount: tran.amount * 10,
                     description: tran.description.clone().unwrap_or_else(|| "".to_string()),
                     payee_name,
-                    category: None,
+                    category: self.category(tran),
+                    state: transaction_state(&tran.ty, tran.state.as_deref()),
+                    running_balance: None,
+                    splits: vec![],
+                    exchanged: None,
                 }
             })
             .collect())
     }
 }
+
+impl RevolutProvider {
+    /// Looks up the YNAB category for a transaction, preferring the merchant
+    /// category code over Revolut's own broader categories.
+    fn category(&self, tran: &Transaction) -> Option<String> {
+        let merchant = tran.merchant.as_ref();
+        let keys = [
+            merchant.and_then(|it| it.mcc.as_deref()),
+            merchant.and_then(|it| it.category.as_deref()),
+            tran.category.as_deref(),
+        ];
+        self.category_map.lookup(keys.iter().flatten().copied())
+    }
+
+    /// Finds the card payment a refund was issued against.
+    /// Refunds don't reference the original payment, so match on merchant
+    /// name or description within the same pocket.
+    fn refunded_payment(&self, refund: &Transaction) -> Option<&Transaction> {
+        let description = refund.description.as_deref().map(|it| {
+            it.trim_start_matches("Refund from ")
+                .trim_start_matches("Refund to ")
+                .trim_start_matches("Refund ")
+        })?;
+
+        self.transactions
+            .iter()
+            .filter(|it| it.account.id == refund.account.id && it.ty == "CARD_PAYMENT")
+            .filter(|it| it.date <= refund.date)
+            .filter(|it| {
+                let merchant = it.merchant.as_ref().map(|it| it.name.as_str());
+                merchant.is_some_and(|it| it.eq_ignore_ascii_case(description))
+                    || it
+                        .description
+                        .as_deref()
+                        .is_some_and(|it| it.eq_ignore_ascii_case(description))
+            })
+            .max_by_key(|it| it.date)
+    }
+}
+
+fn transaction_state(ty: &str, state: Option<&str>) -> crate::TransactionState {
+    use crate::TransactionState;
+
+    match state {
+        Some("PENDING") => TransactionState::Pending,
+        Some("REVERTED") | Some("DECLINED") => TransactionState::Reverted,
+        // Only failed card payments and top ups are known to leave the
+        // balance untouched, other failures can still move money
+        Some("FAILED") if matches!(ty, "CARD_PAYMENT" | "TOPUP") => TransactionState::Reverted,
+        _ => TransactionState::Cleared,
+    }
+}
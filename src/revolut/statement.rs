@@ -0,0 +1,298 @@
+//! Reads the CSV statements exported from the Revolut app, as an alternative
+//! to the unofficial API which needs a stored session with payment rights.
+
+use crate::files::StatementProvider;
+use crate::import_id::ImportIds;
+use crate::prelude::*;
+use crate::{AccountType, ConnectedProvider};
+use chrono::{NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+const IMPORT_ID_PREFIX: &str = "RS";
+const FEE_IMPORT_ID_PREFIX: &str = "RSF";
+
+pub use crate::files::Source;
+
+pub fn initialize(source: &mut Source) -> (bool, Result<Box<dyn ConnectedProvider>>) {
+    (
+        false,
+        load(source).map(|it| Box::new(it) as Box<dyn ConnectedProvider>),
+    )
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+struct Row {
+    #[serde(rename = "Type")]
+    ty: String,
+    #[serde(rename = "Product", default)]
+    product: Option<String>,
+    #[serde(rename = "Started Date")]
+    started_date: String,
+    #[serde(rename = "Description")]
+    description: String,
+    #[serde(rename = "Amount")]
+    amount: f64,
+    #[serde(rename = "Fee", default)]
+    fee: Option<f64>,
+    #[serde(rename = "Currency")]
+    currency: String,
+    #[serde(rename = "State", default)]
+    state: Option<String>,
+    #[serde(rename = "Balance", default)]
+    balance: Option<f64>,
+}
+
+impl Row {
+    fn ty(&self) -> String {
+        self.ty.trim().to_ascii_uppercase().replace(' ', "_")
+    }
+
+    fn account_id(&self) -> String {
+        format!(
+            "revolut:{}:{}",
+            self.product
+                .as_deref()
+                .unwrap_or("Current")
+                .to_ascii_lowercase(),
+            self.currency
+        )
+    }
+}
+
+fn load(source: &Source) -> Result<StatementProvider> {
+    let mut rows: Vec<Row> = vec![];
+    for file in crate::files::statement_files(&source.path, &["csv"])? {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(&file)
+            .with_context(|| format!("Error opening {}", file.display()))?;
+        let file_rows = reader
+            .deserialize()
+            .collect::<Result<Vec<Row>, _>>()
+            .with_context(|| format!("Error reading {}", file.display()))?;
+        // Statements for overlapping periods repeat the same rows
+        crate::files::merge_overlapping(&mut rows, file_rows);
+    }
+
+    convert(&source.display_name, rows)
+}
+
+fn convert(display_name: &str, rows: Vec<Row>) -> Result<StatementProvider> {
+    let mut rows = rows
+        .into_iter()
+        .map(|row| Ok((parse_date(&row.started_date)?, row)))
+        .collect::<Result<Vec<_>>>()?;
+    rows.sort_by_key(|(date, _)| *date);
+
+    let mut accounts: Vec<crate::Account> = vec![];
+    let mut transactions = vec![];
+    let mut import_ids = ImportIds::default();
+
+    for (date, row) in &rows {
+        let account_id = row.account_id();
+        let ty = row.ty();
+
+        if !accounts.iter().any(|it| it.account_id == account_id) {
+            accounts.push(crate::Account {
+                account_id: account_id.clone(),
+                currency: row.currency.clone(),
+                display_name: match row.product.as_deref() {
+                    None | Some("Current") => row.currency.clone(),
+                    Some(product) => format!("{} {}", product, row.currency),
+                },
+                ty: AccountType::Account,
+                balance: None,
+                balances: Default::default(),
+                identifiers: Default::default(),
+            });
+        }
+        if let Some(balance) = row.balance {
+            if row.state.as_deref() == Some("COMPLETED") {
+                let account = accounts
+                    .iter_mut()
+                    .find(|it| it.account_id == account_id)
+                    .unwrap();
+                account.balance = Some(to_milliunits(balance));
+            }
+        }
+
+        let amount = to_milliunits(row.amount);
+        let state = super::transaction_state(&ty, row.state.as_deref());
+        let exchange_counterpart = if ty == "EXCHANGE" {
+            rows.iter()
+                .find(|(other_date, other)| {
+                    other_date == date && other.ty() == "EXCHANGE" && other.currency != row.currency
+                })
+                .map(|(_, other)| other)
+        } else {
+            None
+        };
+
+        let fee = to_milliunits(row.fee.unwrap_or(0.0));
+        if fee != 0 {
+            transactions.push((
+                account_id.clone(),
+                crate::Transaction {
+                    transaction_id: import_ids.next(FEE_IMPORT_ID_PREFIX, date.date(), -fee),
+                    timestamp: *date,
+                    amount: -fee,
+                    description: format!("Fee: {}", row.description),
+                    payee_name: Some("Revolut".to_string()),
+                    category: None,
+                    state,
+                    running_balance: None,
+                    splits: vec![],
+                    exchanged: None,
+                },
+            ));
+        }
+
+        // Matches the API provider, which only imports the buying side of
+        // an exchange as a transfer from the selling account
+        if exchange_counterpart.is_some() && amount < 0 {
+            continue;
+        }
+
+        let payee_name = if let Some(counterpart) = exchange_counterpart {
+            Some(counterpart.account_id())
+        } else {
+            payee_name(&ty, &row.description)
+        };
+
+        transactions.push((
+            account_id,
+            crate::Transaction {
+                transaction_id: import_ids.next(IMPORT_ID_PREFIX, date.date(), amount),
+                timestamp: *date,
+                amount,
+                description: row.description.clone(),
+                payee_name,
+                category: None,
+                state,
+                running_balance: match state {
+                    crate::TransactionState::Cleared => row.balance.map(to_milliunits),
+                    _ => None,
+                },
+                splits: vec![],
+                exchanged: None,
+            },
+        ));
+    }
+
+    Ok(StatementProvider {
+        display_name: display_name.to_string(),
+        accounts,
+        transactions,
+    })
+}
+
+fn payee_name(ty: &str, description: &str) -> Option<String> {
+    match ty {
+        "TOPUP" if description.contains("Google Pay") => Some("Google Pay Topup".to_string()),
+        "TOPUP" => Some(description.trim_start_matches("Payment from ").to_string()),
+        "TRANSFER" => Some(description.trim_start_matches("To ").to_string()),
+        "FEE" if description.to_ascii_lowercase().contains("insurance") => {
+            Some("Revolut Insurance".to_string())
+        }
+        "FEE" => Some("Revolut".to_string()),
+        "CARD_PAYMENT" | "CARD_REFUND" | "ATM" => Some(
+            description
+                .trim_start_matches("Refund from ")
+                .trim_start_matches("Refund to ")
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+fn parse_date(value: &str) -> Result<UtcDateTime> {
+    if let Ok(date) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+        return Ok(Utc.from_utc_datetime(&date));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date {}", value))?;
+    Ok(Utc.from_utc_datetime(&date.and_hms(0, 0, 0)))
+}
+
+fn to_milliunits(amount: f64) -> i64 {
+    (amount * 1000.0).round() as i64
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+
+    const STATEMENT: &str = "\
+Type,Product,Started Date,Completed Date,Description,Amount,Fee,Currency,State,Balance
+CARD_PAYMENT,Current,2021-03-04 10:00:00,2021-03-05 09:00:00,Tesco,-12.50,0.00,GBP,COMPLETED,87.50
+EXCHANGE,Current,2021-03-06 12:00:00,2021-03-06 12:00:00,Exchanged to EUR,-50.00,0.25,GBP,COMPLETED,37.25
+EXCHANGE,Current,2021-03-06 12:00:00,2021-03-06 12:00:00,Exchanged to EUR,57.80,0.00,EUR,COMPLETED,57.80
+CARD_PAYMENT,Current,2021-03-07 08:00:00,,Cafe,-3.00,0.00,GBP,PENDING,
+";
+
+    #[test]
+    fn statement() -> Result<()> {
+        let rows = csv::Reader::from_reader(STATEMENT.as_bytes())
+            .deserialize()
+            .collect::<Result<Vec<_>, _>>()?;
+        let provider = super::convert("test", rows)?;
+
+        let balances: Vec<_> = provider
+            .accounts
+            .iter()
+            .map(|it| (it.account_id.as_str(), it.balance))
+            .collect();
+        assert_eq!(
+            vec![
+                ("revolut:current:GBP", Some(37250)),
+                ("revolut:current:EUR", Some(57800))
+            ],
+            balances
+        );
+
+        let transactions: Vec<_> = provider
+            .transactions
+            .iter()
+            .map(|(_, it)| (it.transaction_id.as_str(), it.payee_name.as_deref()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("RS:-12500:2021-03-04:1", Some("Tesco")),
+                ("RSF:-250:2021-03-06:1", Some("Revolut")),
+                ("RS:57800:2021-03-06:1", Some("revolut:current:GBP")),
+                ("RS:-3000:2021-03-07:1", Some("Cafe")),
+            ],
+            transactions
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn overlapping_statements() -> Result<()> {
+        let read = |data: &str| {
+            csv::Reader::from_reader(data.as_bytes())
+                .deserialize()
+                .collect::<Result<Vec<super::Row>, _>>()
+        };
+        let header = STATEMENT.lines().next().unwrap();
+        let coffee = "CARD_PAYMENT,Current,2021-03-08 08:00:00,2021-03-08 09:00:00,Cafe,-3.00,0.00,GBP,COMPLETED,";
+
+        let mut rows = vec![];
+        // Two identical coffees on the same day
+        crate::files::merge_overlapping(
+            &mut rows,
+            read(&format!("{}\n{}\n{}\n", header, coffee, coffee))?,
+        );
+        crate::files::merge_overlapping(&mut rows, read(&format!("{}\n{}\n", header, coffee))?);
+        let provider = super::convert("test", rows)?;
+
+        let ids: Vec<_> = provider
+            .transactions
+            .iter()
+            .map(|(_, it)| it.transaction_id.as_str())
+            .collect();
+        assert_eq!(vec!["RS:-3000:2021-03-08:1", "RS:-3000:2021-03-08:2"], ids);
+
+        Ok(())
+    }
+}
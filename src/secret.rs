@@ -0,0 +1,54 @@
+use crate::prelude::*;
+use std::fmt;
+
+const MASK: &str = "********";
+
+/// A password or token which is masked whenever it is printed, so secrets
+/// don't end up in logs. Serializes as the plain string.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: String) -> Secret {
+        Secret(secret)
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Secret(secret)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret({})", MASK)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", MASK)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn masked() {
+        let secret = super::Secret::new("hunter2".to_string());
+
+        assert_eq!("Secret(********)", format!("{:?}", secret));
+        assert_eq!("********", format!("{}", secret));
+        assert_eq!("\"hunter2\"", serde_json::to_string(&secret).unwrap());
+    }
+}
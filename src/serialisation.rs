@@ -32,3 +32,15 @@ impl<'de> de::Visitor<'de> for DateTimeFromCustomFormatVisitor {
         }
     }
 }
+
+/// Deserializes codes such as MCCs which are sent as either strings or numbers.
+pub fn deserialize_code<'de, D>(d: D) -> Result<Option<String>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    Ok(match Option::<serde_json::Value>::deserialize(d)? {
+        Some(serde_json::Value::String(s)) => Some(s),
+        Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    })
+}
@@ -0,0 +1,535 @@
+//! Starling Bank's public API, using a personal access token from
+//! developer.starlingbank.com which doesn't need renewing.
+
+use crate::config::CategoryMap;
+use crate::prelude::*;
+use crate::provider::{debug_as_display_name, History};
+use crate::secret::Secret;
+use crate::{AccountIdentifiers, AccountType, TransactionState};
+use anyhow::ensure;
+use api::*;
+use chrono::Utc;
+use restson::Response;
+
+const API_URL: &str = "https://api.starlingbank.com/";
+
+/// Where a main account's or Space's feed items are listed
+struct Feed {
+    account_uid: String,
+    category_uid: String,
+    is_space: bool,
+}
+
+struct StarlingProvider {
+    display_name: String,
+    rest_client: RestClient,
+    history: History,
+    category_map: CategoryMap,
+    /// By `crate::Account::account_id`
+    feeds: HashMap<String, Feed>,
+}
+
+debug_as_display_name!(StarlingProvider);
+
+impl crate::ConnectedProvider for StarlingProvider {
+    fn get_accounts(&mut self) -> Result<Vec<crate::Account>> {
+        let accounts: Response<AccountsResponse> = self.rest_client.get(())?;
+
+        let mut converted = vec![];
+        for account in accounts.into_inner().accounts {
+            let uid = account.account_uid.as_str();
+            let balance: Response<Balance> = self.rest_client.get(uid)?;
+            let identifiers: Response<Identifiers> = self.rest_client.get(uid)?;
+            let spaces: Response<SpacesResponse> = self.rest_client.get(uid)?;
+
+            self.feeds.insert(
+                account.account_uid.clone(),
+                Feed {
+                    account_uid: account.account_uid.clone(),
+                    category_uid: account.default_category.clone(),
+                    is_space: false,
+                },
+            );
+            converted.push(convert_account(
+                &account,
+                &balance.into_inner(),
+                identifiers.into_inner(),
+            ));
+
+            for space in convert_spaces(&account, spaces.into_inner()) {
+                self.feeds.insert(
+                    space.account_id.clone(),
+                    Feed {
+                        account_uid: account.account_uid.clone(),
+                        category_uid: space.account_id.clone(),
+                        is_space: true,
+                    },
+                );
+                converted.push(space);
+            }
+        }
+
+        Ok(converted)
+    }
+
+    fn get_transactions(&mut self, acc: &crate::Account) -> Result<Vec<crate::Transaction>> {
+        let feed = self
+            .feeds
+            .get(&acc.account_id)
+            .ok_or_else(|| anyhow!("Unknown starling account {}", acc.display_name))?;
+
+        let from = self.history.from();
+        let from = from.format("%Y-%m-%dT%H:%M:%S.000Z").to_string();
+        let to = Utc::now().format("%Y-%m-%dT%H:%M:%S.000Z").to_string();
+
+        let items: Response<FeedItemsResponse> = self.rest_client.get_with(
+            (feed.account_uid.as_str(), feed.category_uid.as_str()),
+            &[
+                ("minTransactionTimestamp", from.as_str()),
+                ("maxTransactionTimestamp", to.as_str()),
+            ],
+        )?;
+
+        Ok(convert_feed(
+            feed,
+            items.into_inner().feed_items,
+            &self.category_map,
+        ))
+    }
+
+    fn set_history_start(&mut self, from: UtcDate) {
+        self.history.set_start(from);
+    }
+}
+
+fn convert_account(
+    account: &Account,
+    balance: &Balance,
+    identifiers: Identifiers,
+) -> crate::Account {
+    crate::Account {
+        account_id: account.account_uid.clone(),
+        currency: account.currency.clone(),
+        display_name: account.name.clone(),
+        ty: AccountType::Account,
+        // Includes pending transactions, which are imported uncleared
+        balance: Some(balance.effective_balance.milliunits()),
+        balances: crate::Balances {
+            overdraft: Some(balance.accepted_overdraft.milliunits()),
+            ..Default::default()
+        },
+        identifiers: AccountIdentifiers {
+            iban: identifiers.iban,
+            sort_code: identifiers.bank_identifier,
+            account_number: identifiers.account_identifier,
+            partial_card_number: None,
+        },
+    }
+}
+
+fn convert_spaces(account: &Account, spaces: SpacesResponse) -> Vec<crate::Account> {
+    let space = |uid: String, name: String, balance: &Amount| crate::Account {
+        account_id: uid,
+        currency: balance.currency.clone(),
+        display_name: format!("{} {}", account.name, name),
+        ty: AccountType::Account,
+        balance: Some(balance.milliunits()),
+        balances: Default::default(),
+        identifiers: Default::default(),
+    };
+
+    spaces
+        .savings_goals
+        .into_iter()
+        .filter(|it| it.state.as_deref().unwrap_or("ACTIVE") == "ACTIVE")
+        .map(|it| space(it.savings_goal_uid, it.name, &it.total_saved))
+        .chain(
+            spaces
+                .spending_spaces
+                .into_iter()
+                .filter(|it| it.state.as_deref().unwrap_or("ACTIVE") == "ACTIVE")
+                .map(|it| space(it.space_uid, it.name, &it.balance)),
+        )
+        .collect()
+}
+
+fn convert_feed(
+    feed: &Feed,
+    items: Vec<FeedItem>,
+    category_map: &CategoryMap,
+) -> Vec<crate::Transaction> {
+    items
+        .into_iter()
+        // Moves between the main account and a Space are imported from the
+        // main account, and YNAB adds the other side of the transfer
+        .filter(|it| !(feed.is_space && it.counter_party_type == "CATEGORY"))
+        .filter_map(|it| convert_feed_item(it, category_map))
+        .collect()
+}
+
+fn convert_feed_item(item: FeedItem, category_map: &CategoryMap) -> Option<crate::Transaction> {
+    let state = match item.status.as_str() {
+        "SETTLED" | "REFUNDED" => TransactionState::Cleared,
+        "PENDING" | "RETRYING" => TransactionState::Pending,
+        "DECLINED" | "REVERSED" => TransactionState::Reverted,
+        // Future payments, and zero value card checks
+        _ => return None,
+    };
+
+    let amount = match item.direction.as_str() {
+        "OUT" => -item.amount.milliunits(),
+        _ => item.amount.milliunits(),
+    };
+
+    let category = item.spending_category.as_deref().and_then(|it| {
+        category_map
+            .lookup(std::iter::once(it))
+            .or_else(|| match it {
+                "NONE" => None,
+                _ => crate::config::category_name(it),
+            })
+    });
+
+    // A Space's ID makes this a transfer, if a YNAB account has it as ACCOUNT_ID
+    let payee_name = if item.counter_party_type == "CATEGORY" {
+        item.counter_party_uid.clone()
+    } else {
+        item.counter_party_name.clone()
+    };
+    let reference = item.reference.unwrap_or_default();
+
+    Some(crate::Transaction {
+        transaction_id: crate::import_id::fit(&item.feed_item_uid),
+        timestamp: item.transaction_time,
+        amount,
+        description: match item.user_note {
+            Some(note) if !note.is_empty() => note,
+            _ => reference.clone(),
+        },
+        payee_name: payee_name.or(Some(reference)),
+        category,
+        state,
+        running_balance: None,
+        splits: vec![],
+        exchanged: None,
+    })
+}
+
+pub fn initialize(token: &mut Token) -> (bool, Result<Box<dyn crate::ConnectedProvider>>) {
+    (false, connect(token))
+}
+
+fn connect(token: &Token) -> Result<Box<dyn crate::ConnectedProvider>> {
+    let rest_client = crate::provider::rest_client(API_URL, Some(token.access_token.secret()))?;
+    let identity: Response<TokenIdentity> = rest_client.get(())?;
+    ensure!(
+        identity.into_inner().authenticated,
+        "Starling token isn't authenticated"
+    );
+
+    Ok(Box::new(StarlingProvider {
+        display_name: token.display_name.clone(),
+        rest_client,
+        history: History::new(token.history_days),
+        category_map: token.category_map.clone(),
+        feeds: HashMap::new(),
+    }))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Token {
+    pub display_name: String,
+    /// Personal access token, needs the account, balance, savings goal,
+    /// space and transaction read scopes
+    pub access_token: Secret,
+    /// Days of the feed fetched on each sync
+    #[serde(default = "crate::provider::default_history_days")]
+    pub history_days: i64,
+    /// Starling spending categories, e.g. "EATING_OUT", to YNAB category
+    /// names. Unmapped ones are passed on as their name, e.g. "Eating out".
+    #[serde(default)]
+    pub category_map: CategoryMap,
+}
+
+pub mod api {
+    use crate::prelude::*;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Amount {
+        pub currency: String,
+        pub minor_units: i64,
+    }
+
+    impl Amount {
+        pub fn milliunits(&self) -> i64 {
+            self.minor_units * 10
+        }
+    }
+
+    // https://api.starlingbank.com/api/v2/identity/token
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TokenIdentity {
+        pub account_holder_uid: String,
+        pub authenticated: bool,
+    }
+
+    impl RestPath<()> for TokenIdentity {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("api/v2/identity/token".to_string())
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Account {
+        pub account_uid: String,
+        pub account_type: String,
+        pub default_category: String,
+        pub currency: String,
+        pub name: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct AccountsResponse {
+        pub accounts: Vec<Account>,
+    }
+
+    impl RestPath<()> for AccountsResponse {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("api/v2/accounts".to_string())
+        }
+    }
+
+    /// Balances exclude Spaces, unlike the `total` ones which aren't used
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Balance {
+        pub cleared_balance: Amount,
+        pub effective_balance: Amount,
+        pub pending_transactions: Amount,
+        pub accepted_overdraft: Amount,
+    }
+
+    impl RestPath<&str> for Balance {
+        fn get_path(account_uid: &str) -> Result<String, restson::Error> {
+            Ok(format!("api/v2/accounts/{}/balance", account_uid))
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Identifiers {
+        pub account_identifier: Option<String>,
+        pub bank_identifier: Option<String>,
+        pub iban: Option<String>,
+    }
+
+    impl RestPath<&str> for Identifiers {
+        fn get_path(account_uid: &str) -> Result<String, restson::Error> {
+            Ok(format!("api/v2/accounts/{}/identifiers", account_uid))
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct SavingsGoal {
+        pub savings_goal_uid: String,
+        pub name: String,
+        pub total_saved: Amount,
+        pub state: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct SpendingSpace {
+        pub space_uid: String,
+        pub name: String,
+        pub balance: Amount,
+        pub state: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct SpacesResponse {
+        #[serde(default)]
+        pub savings_goals: Vec<SavingsGoal>,
+        #[serde(default)]
+        pub spending_spaces: Vec<SpendingSpace>,
+    }
+
+    impl RestPath<&str> for SpacesResponse {
+        fn get_path(account_uid: &str) -> Result<String, restson::Error> {
+            Ok(format!("api/v2/account/{}/spaces", account_uid))
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct FeedItem {
+        pub feed_item_uid: String,
+        /// Always positive, see `direction`
+        pub amount: Amount,
+        /// `IN` or `OUT`
+        pub direction: String,
+        #[serde(deserialize_with = "crate::serialisation::deserialize")]
+        pub transaction_time: UtcDateTime,
+        pub status: String,
+        /// `CATEGORY` for moves to and from Spaces
+        #[serde(default)]
+        pub counter_party_type: String,
+        pub counter_party_uid: Option<String>,
+        pub counter_party_name: Option<String>,
+        pub reference: Option<String>,
+        pub spending_category: Option<String>,
+        pub user_note: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct FeedItemsResponse {
+        pub feed_items: Vec<FeedItem>,
+    }
+
+    impl RestPath<(&str, &str)> for FeedItemsResponse {
+        fn get_path((account_uid, category_uid): (&str, &str)) -> Result<String, restson::Error> {
+            Ok(format!(
+                "api/v2/feed/account/{}/category/{}/transactions-between",
+                account_uid, category_uid
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::TransactionState;
+
+    const SPACE_UID: &str = "77887788-7788-7788-7788-778877887788";
+
+    fn feed_items() -> Result<Vec<super::FeedItem>> {
+        let response: super::FeedItemsResponse = serde_json::from_str(
+            r#"{"feedItems": [
+                {
+                    "feedItemUid": "11221122-1122-1122-1122-112211221122",
+                    "amount": {"currency": "GBP", "minorUnits": 1250}, "direction": "OUT",
+                    "transactionTime": "2021-03-01T12:30:00.000Z", "status": "SETTLED",
+                    "counterPartyType": "MERCHANT", "counterPartyName": "Tesco",
+                    "reference": "TESCO STORES 3297", "spendingCategory": "GROCERIES"
+                },
+                {
+                    "feedItemUid": "22332233-2233-2233-2233-223322332233",
+                    "amount": {"currency": "GBP", "minorUnits": 5000}, "direction": "OUT",
+                    "transactionTime": "2021-03-02T09:00:00.000Z", "status": "SETTLED",
+                    "counterPartyType": "CATEGORY",
+                    "counterPartyUid": "77887788-7788-7788-7788-778877887788",
+                    "counterPartyName": "Holiday", "spendingCategory": "SAVING"
+                },
+                {
+                    "feedItemUid": "33443344-3344-3344-3344-334433443344",
+                    "amount": {"currency": "GBP", "minorUnits": 1250}, "direction": "IN",
+                    "transactionTime": "2021-03-03T09:00:00.000Z", "status": "REFUNDED",
+                    "counterPartyType": "MERCHANT", "counterPartyName": "Tesco",
+                    "reference": "TESCO STORES 3297", "spendingCategory": "NONE",
+                    "userNote": "Returned"
+                },
+                {
+                    "feedItemUid": "44554455-4455-4455-4455-445544554455",
+                    "amount": {"currency": "GBP", "minorUnits": 1500}, "direction": "OUT",
+                    "transactionTime": "2021-03-31T09:00:00.000Z", "status": "UPCOMING",
+                    "counterPartyType": "PAYEE", "counterPartyName": "Landlord"
+                },
+                {
+                    "feedItemUid": "55665566-5566-5566-5566-556655665566",
+                    "amount": {"currency": "GBP", "minorUnits": 900}, "direction": "OUT",
+                    "transactionTime": "2021-03-04T09:00:00.000Z", "status": "DECLINED",
+                    "counterPartyType": "MERCHANT", "counterPartyName": "Cinema",
+                    "reference": "ODEON"
+                }
+            ]}"#,
+        )?;
+        Ok(response.feed_items)
+    }
+
+    fn feed(is_space: bool) -> super::Feed {
+        super::Feed {
+            account_uid: "account".to_string(),
+            category_uid: "category".to_string(),
+            is_space,
+        }
+    }
+
+    #[test]
+    fn main_account_feed() -> Result<()> {
+        let category_map = crate::config::CategoryMap(
+            vec![("groceries".to_string(), "Food".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        let converted: Vec<_> = super::convert_feed(&feed(false), feed_items()?, &category_map)
+            .into_iter()
+            .map(|it| {
+                (
+                    it.amount,
+                    it.payee_name.unwrap_or_default(),
+                    it.category,
+                    it.description,
+                    it.state,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    -12500,
+                    "Tesco".to_string(),
+                    Some("Food".to_string()),
+                    "TESCO STORES 3297".to_string(),
+                    TransactionState::Cleared
+                ),
+                // Transfer to the Space, by its ID
+                (
+                    -50000,
+                    SPACE_UID.to_string(),
+                    Some("Saving".to_string()),
+                    "".to_string(),
+                    TransactionState::Cleared
+                ),
+                // The refund is a settled credit, without Starling's NONE category
+                (
+                    12500,
+                    "Tesco".to_string(),
+                    None,
+                    "Returned".to_string(),
+                    TransactionState::Cleared
+                ),
+                // The upcoming payment isn't imported yet
+                (
+                    -9000,
+                    "Cinema".to_string(),
+                    None,
+                    "ODEON".to_string(),
+                    TransactionState::Reverted
+                ),
+            ],
+            converted
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn space_feed_skips_transfers() -> Result<()> {
+        let converted = super::convert_feed(&feed(true), feed_items()?, &Default::default());
+
+        // The transfer is only imported from the main account
+        assert!(!converted
+            .iter()
+            .any(|it| it.payee_name.as_deref() == Some(SPACE_UID)));
+        assert_eq!(3, converted.len());
+
+        Ok(())
+    }
+}
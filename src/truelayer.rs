@@ -1,11 +1,14 @@
 use crate::prelude::*;
 use api::*;
 use chrono::Utc;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::ops::{Add, Deref};
 type DateTime = chrono::DateTime<chrono::Utc>;
-use crate::config::YnabConfig;
-use crate::AccountType;
+use crate::config::{CategoryMap, YnabConfig};
+use crate::import_id::ImportIds;
+use crate::provider::{debug_as_display_name, History};
+use crate::{AccountIdentifiers, AccountType, Frequency, ScheduledTransaction, TransactionState};
 
 use oauth2::basic::BasicClient;
 use oauth2::reqwest::http_client;
@@ -16,12 +19,78 @@ use oauth2::{
 };
 use restson::Response;
 
+/// Warn about consent expiring this many days in advance
+const CONSENT_EXPIRY_WARNING_DAYS: i64 = 14;
+/// Prefix for import IDs using the bank's own transaction ID
+const PROVIDER_ID_PREFIX: &str = "TLP";
+/// Prefix for import IDs generated from the transaction details
+const HASHED_ID_PREFIX: &str = "TL";
+
 struct TruelayerProvider {
     display_name: String,
     rest_client: RestClient,
+    consent_expiry: Option<DateTime>,
+    history: History,
+    request_window_days: i64,
+    category_map: CategoryMap,
+    stable_import_ids: bool,
+    balance_source: BalanceSource,
+    invert_balance: bool,
+    /// Accounts whose pending transactions couldn't be fetched on this sync
+    pending_unavailable: Vec<String>,
 }
 
 impl TruelayerProvider {
+    /// Returns the balance to reconcile with, chosen by `balance_source`,
+    /// along with the other figures reported.
+    fn convert_balance(
+        &self,
+        balance: &AccountBalance,
+        ty: &AccountType,
+    ) -> Result<(i64, crate::Balances)> {
+        // Card balances are reported as the amount owed
+        let owed_sign = match ty {
+            AccountType::Account => 1,
+            AccountType::Card => -1,
+        };
+
+        let reconciled = match self.balance_source {
+            BalanceSource::Current => owed_sign * milliunits(balance.current),
+            BalanceSource::Available => {
+                let available = balance
+                    .available
+                    .ok_or_else(|| anyhow!("No available balance reported"))?;
+                match ty {
+                    AccountType::Account => {
+                        milliunits(available) - milliunits(balance.overdraft.unwrap_or(0.0))
+                    }
+                    AccountType::Card => {
+                        let credit_limit = balance
+                            .credit_limit
+                            .ok_or_else(|| anyhow!("No credit limit reported"))?;
+                        milliunits(available) - milliunits(credit_limit)
+                    }
+                }
+            }
+        };
+
+        Ok((
+            if self.invert_balance {
+                -reconciled
+            } else {
+                reconciled
+            },
+            crate::Balances {
+                available: balance.available.map(milliunits),
+                overdraft: balance.overdraft.map(milliunits),
+                credit_limit: balance.credit_limit.map(milliunits),
+                last_statement_balance: balance
+                    .last_statement_balance
+                    .map(|it| owed_sign * milliunits(it)),
+            },
+        ))
+    }
+
     fn test(&mut self) -> Result<()> {
         let metadata: Response<AccessTokenMetadataResponse> = self.rest_client.get(())?;
         let metadata = &metadata.into_inner().results[0];
@@ -40,15 +109,16 @@ impl TruelayerProvider {
                 .unwrap_or("no expiry"),
             metadata.client_id
         );
+        self.consent_expiry = metadata
+            .consent_expires_at
+            .as_deref()
+            .and_then(|it| chrono::DateTime::parse_from_rfc3339(it).ok())
+            .map(|it| it.with_timezone(&Utc));
         Ok(())
     }
 }
 
-impl std::fmt::Debug for TruelayerProvider {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-        write!(fmt, "TruelayerProvider: {}", &self.display_name)
-    }
-}
+debug_as_display_name!(TruelayerProvider);
 
 impl crate::ConnectedProvider for TruelayerProvider {
     fn get_accounts(&mut self) -> Result<Vec<crate::Account>> {
@@ -72,12 +142,23 @@ impl crate::ConnectedProvider for TruelayerProvider {
                     .map(|truelayer_acc| {
                         let balance: Response<AccountBalanceResponse> =
                             self.rest_client.get(truelayer_acc.account_id.deref())?;
+                        let (balance, balances) = self.convert_balance(
+                            &balance.into_inner().results[0],
+                            &AccountType::Account,
+                        )?;
                         Ok(crate::Account {
                             account_id: truelayer_acc.account_id,
                             currency: truelayer_acc.currency,
                             display_name: truelayer_acc.display_name,
                             ty: AccountType::Account,
-                            balance: (balance.into_inner().results[0].current * 1000.0) as i64,
+                            balance: Some(balance),
+                            balances,
+                            identifiers: AccountIdentifiers {
+                                iban: truelayer_acc.account_number.iban,
+                                sort_code: truelayer_acc.account_number.sort_code,
+                                account_number: truelayer_acc.account_number.number,
+                                partial_card_number: None,
+                            },
                         })
                     })
                     .collect::<Result<Vec<_>>>()?,
@@ -93,12 +174,21 @@ impl crate::ConnectedProvider for TruelayerProvider {
                     .map(|truelayer_acc| {
                         let balance: Response<CardBalanceResponse> =
                             self.rest_client.get(truelayer_acc.account_id.deref())?;
+                        let (balance, balances) = self.convert_balance(
+                            &balance.into_inner().results[0],
+                            &AccountType::Card,
+                        )?;
                         Ok(crate::Account {
                             account_id: truelayer_acc.account_id,
                             currency: truelayer_acc.currency,
                             display_name: truelayer_acc.display_name,
                             ty: AccountType::Card,
-                            balance: -(balance.into_inner().results[0].current * 1000.0) as i64,
+                            balance: Some(balance),
+                            balances,
+                            identifiers: AccountIdentifiers {
+                                partial_card_number: Some(truelayer_acc.partial_card_number),
+                                ..Default::default()
+                            },
                         })
                     })
                     .collect::<Result<Vec<_>>>()?,
@@ -109,63 +199,398 @@ impl crate::ConnectedProvider for TruelayerProvider {
     }
 
     fn get_transactions(&mut self, acc: &crate::Account) -> Result<Vec<crate::Transaction>> {
-        let transactions = match acc.ty {
+        let transactions = self.get_settled_transactions(acc)?;
+        let pending = match acc.ty {
             AccountType::Account => {
-                let transactions: Response<TransactionsResponse> =
-                    self.rest_client.get(acc.account_id.deref())?;
-                transactions.into_inner().results
+                let pending: Result<Response<PendingTransactionsResponse>, _> =
+                    self.rest_client.get(acc.account_id.deref());
+                pending.map(|it| it.into_inner().results)
             }
             AccountType::Card => {
-                let transactions: Response<CardTransactionsResponse> =
-                    self.rest_client.get(acc.account_id.deref())?;
-                let mut transactions = transactions.into_inner();
-                for transaction in &mut transactions.results {
-                    transaction.amount = -transaction.amount;
-                }
-                transactions.results
+                let pending: Result<Response<CardPendingTransactionsResponse>, _> =
+                    self.rest_client.get(acc.account_id.deref());
+                pending.map(|it| negate_amounts(it.into_inner().results))
             }
         };
 
+        // Not every bank supports pending transactions
+        let pending = pending.unwrap_or_else(|e| {
+            println!("No pending transactions for {}: {}", acc.display_name, e);
+            self.pending_unavailable.push(acc.account_id.clone());
+            vec![]
+        });
+
+        let (mut transactions, mut pending) = (transactions, pending);
+        if self.stable_import_ids {
+            stabilise_transaction_ids(&mut transactions);
+            stabilise_transaction_ids(&mut pending);
+        }
+
         //println!("{:#?}", transactions.iter().take(10).collect::<Vec<_>>());
 
         Ok(transactions
             .into_iter()
-            .map(|truelayer_tran| crate::Transaction {
-                transaction_id: truelayer_tran.transaction_id,
-                timestamp: truelayer_tran.timestamp,
-                amount: (truelayer_tran.amount * 1000f64) as i64,
-                payee_name: match truelayer_tran.merchant_name {
-                    Some(s) => Some(s),
-                    None => truelayer_tran
-                        .description
-                        .split_whitespace()
-                        .next()
-                        .map(|it| it.to_string()),
-                },
-                description: truelayer_tran.description,
-                category: None,
-            })
+            .map(|it| convert_transaction(it, TransactionState::Cleared, &self.category_map))
+            .chain(
+                pending.into_iter().map(|it| {
+                    convert_transaction(it, TransactionState::Pending, &self.category_map)
+                }),
+            )
             .collect())
     }
+
+    fn set_history_start(&mut self, from: UtcDate) {
+        self.history.set_start(from);
+    }
+
+    fn pending_ids_change(&self, acc: &crate::Account) -> bool {
+        // Without this sync's pending transactions, every imported one would
+        // look like it had settled
+        !self.pending_unavailable.contains(&acc.account_id)
+    }
+
+    fn get_scheduled_transactions(
+        &mut self,
+        acc: &crate::Account,
+    ) -> Result<Vec<ScheduledTransaction>> {
+        if let AccountType::Card = acc.ty {
+            return Ok(vec![]);
+        }
+
+        let mut scheduled = vec![];
+
+        // Not every bank supports these, so carry on without them
+        let standing_orders: Result<Response<StandingOrdersResponse>, _> =
+            self.rest_client.get(acc.account_id.deref());
+        match standing_orders {
+            Ok(standing_orders) => scheduled.extend(
+                standing_orders
+                    .into_inner()
+                    .results
+                    .into_iter()
+                    .filter_map(convert_standing_order),
+            ),
+            Err(e) => println!("No standing orders for {}: {}", acc.display_name, e),
+        }
+
+        let direct_debits: Result<Response<DirectDebitsResponse>, _> =
+            self.rest_client.get(acc.account_id.deref());
+        match direct_debits {
+            Ok(direct_debits) => scheduled.extend(
+                direct_debits
+                    .into_inner()
+                    .results
+                    .into_iter()
+                    .filter_map(convert_direct_debit),
+            ),
+            Err(e) => println!("No direct debits for {}: {}", acc.display_name, e),
+        }
+
+        Ok(scheduled)
+    }
+}
+
+impl TruelayerProvider {
+    /// Requests settled transactions in windows of at most
+    /// `request_window_days`, as some banks reject longer ranges.
+    fn get_settled_transactions(&mut self, acc: &crate::Account) -> Result<Vec<Transaction>> {
+        let to = Utc::now();
+        // Whole days, so the import IDs numbering same day transactions don't
+        // shift as the start of the history moves through a day
+        let from = self.history.from().date().and_hms(0, 0, 0);
+
+        let window = chrono::Duration::days(self.request_window_days.max(1));
+
+        let mut fetched: Vec<Fetched> = vec![];
+        let mut start = from;
+        while start < to {
+            let end = std::cmp::min(start + window, to);
+            let start_param = start.format("%Y-%m-%dT%H:%M:%S").to_string();
+            let end_param = end.format("%Y-%m-%dT%H:%M:%S").to_string();
+            let query = [("from", start_param.as_str()), ("to", end_param.as_str())];
+
+            let chunk = match acc.ty {
+                AccountType::Account => {
+                    let chunk: Response<TransactionsResponse> =
+                        self.rest_client.get_with(acc.account_id.deref(), &query)?;
+                    chunk.into_inner().results
+                }
+                AccountType::Card => {
+                    let chunk: Response<CardTransactionsResponse> =
+                        self.rest_client.get_with(acc.account_id.deref(), &query)?;
+                    negate_amounts(chunk.into_inner().results)
+                }
+            };
+            // Windows share their boundaries, so may return the same transaction twice
+            crate::files::merge_overlapping(&mut fetched, chunk.into_iter().map(Fetched).collect());
+
+            start = end;
+        }
+
+        Ok(fetched.into_iter().map(|it| it.0).collect())
+    }
+}
+
+/// A settled transaction, compared by what stays the same between requests
+/// rather than truelayer's ID, which some banks regenerate.
+struct Fetched(Transaction);
+
+impl PartialEq for Fetched {
+    fn eq(&self, other: &Self) -> bool {
+        let (a, b) = (&self.0, &other.0);
+        provider_transaction_id(a) == provider_transaction_id(b)
+            && a.timestamp == b.timestamp
+            && a.amount == b.amount
+            && a.description == b.description
+    }
+}
+
+fn convert_standing_order(standing_order: StandingOrder) -> Option<ScheduledTransaction> {
+    if standing_order
+        .status
+        .as_deref()
+        .is_some_and(|it| !it.eq_ignore_ascii_case("active"))
+    {
+        return None;
+    }
+    let next_date = parse_date(standing_order.next_payment_date.as_deref()?)?;
+    if let Some(final_date) = standing_order
+        .final_payment_date
+        .as_deref()
+        .and_then(parse_date)
+    {
+        if final_date < Utc::today() {
+            return None;
+        }
+    }
+    let frequency = match parse_frequency(&standing_order.frequency) {
+        Some(frequency) => frequency,
+        None => {
+            println!(
+                "Unsupported standing order frequency {} for {:?}",
+                standing_order.frequency, standing_order.payee
+            );
+            return None;
+        }
+    };
+
+    Some(ScheduledTransaction {
+        next_date,
+        amount: -milliunits(standing_order.next_payment_amount?),
+        payee_name: standing_order
+            .payee
+            .clone()
+            .or_else(|| standing_order.reference.clone())?,
+        memo: match standing_order.reference {
+            Some(reference) => format!("Standing order {}", reference),
+            None => "Standing order".to_string(),
+        },
+        frequency,
+    })
+}
+
+/// Direct debits only report their last payment, so assume they're monthly
+fn convert_direct_debit(direct_debit: DirectDebit) -> Option<ScheduledTransaction> {
+    if direct_debit
+        .status
+        .as_deref()
+        .is_some_and(|it| !it.eq_ignore_ascii_case("active"))
+    {
+        return None;
+    }
+    let previous_date = parse_date(direct_debit.previous_payment_timestamp.as_deref()?)?;
+
+    Some(ScheduledTransaction {
+        next_date: Frequency::Monthly.advance(previous_date),
+        amount: -milliunits(direct_debit.previous_payment_amount?),
+        payee_name: direct_debit.name,
+        memo: "Direct debit".to_string(),
+        frequency: Frequency::Monthly,
+    })
+}
+
+/// Parses Open Banking frequency codes, such as `IntrvlMnthDay:01:15`
+fn parse_frequency(frequency: &str) -> Option<Frequency> {
+    let mut parts = frequency.split(':');
+    let code = parts.next()?;
+    let interval: Option<u32> = parts.next().and_then(|it| it.parse().ok());
+
+    Some(match (code.to_ascii_lowercase().as_str(), interval) {
+        ("evryday", _) | ("evryworkgday", _) | ("daily", _) => Frequency::Daily,
+        ("intrvlwkday", Some(1)) | ("weekly", _) => Frequency::Weekly,
+        ("intrvlwkday", Some(2)) => Frequency::EveryOtherWeek,
+        ("intrvlwkday", Some(4)) => Frequency::Every4Weeks,
+        ("wkinmnthday", _) | ("intrvlmnthday", Some(1)) | ("monthly", _) => Frequency::Monthly,
+        ("intrvlmnthday", Some(2)) => Frequency::EveryOtherMonth,
+        ("intrvlmnthday", Some(3)) | ("qtrday", _) | ("quarterly", _) => Frequency::Every3Months,
+        ("intrvlmnthday", Some(4)) => Frequency::Every4Months,
+        ("intrvlmnthday", Some(6)) => Frequency::TwiceAYear,
+        ("intrvlmnthday", Some(12)) | ("yearly", _) | ("annually", _) => Frequency::Yearly,
+        ("intrvlmnthday", Some(24)) => Frequency::EveryOtherYear,
+        _ => return None,
+    })
+}
+
+fn milliunits(amount: f64) -> i64 {
+    (amount * 1000.0).round() as i64
+}
+
+fn parse_date(value: &str) -> Option<UtcDate> {
+    if let Ok(date) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Utc).date());
+    }
+    let date = value.get(..10)?;
+    chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .map(|it| UtcDate::from_utc(it, Utc))
+}
+
+fn negate_amounts(mut transactions: Vec<Transaction>) -> Vec<Transaction> {
+    for transaction in &mut transactions {
+        transaction.amount = -transaction.amount;
+        if let Some(running_balance) = &mut transaction.running_balance {
+            running_balance.amount = -running_balance.amount;
+        }
+    }
+    transactions
+}
+
+/// Replaces truelayer's transaction IDs, which some banks regenerate between
+/// requests, with the bank's own ID if there is one, or otherwise one derived
+/// from the date, amount and description.
+fn stabilise_transaction_ids(transactions: &mut [Transaction]) {
+    // Stable sort, so same day transactions keep the order the bank lists them in
+    transactions.sort_by_key(|it| it.timestamp);
+
+    let mut import_ids = ImportIds::default();
+    for transaction in transactions {
+        transaction.transaction_id = match provider_transaction_id(transaction) {
+            Some(id) => crate::import_id::fit(&format!("{}:{}", PROVIDER_ID_PREFIX, id)),
+            None => import_ids.next_hashed(
+                HASHED_ID_PREFIX,
+                transaction.timestamp.date(),
+                milliunits(transaction.amount),
+                &transaction.description,
+            ),
+        };
+    }
+}
+
+/// The bank's own ID for a transaction, when truelayer passes it on
+fn provider_transaction_id(transaction: &Transaction) -> Option<&str> {
+    transaction
+        .meta
+        .as_ref()
+        .and_then(|meta| meta.get("provider_transaction_id"))
+        .and_then(|id| id.as_str())
+        .filter(|id| !id.is_empty())
+}
+
+fn convert_transaction(
+    truelayer_tran: Transaction,
+    state: TransactionState,
+    category_map: &CategoryMap,
+) -> crate::Transaction {
+    // Most specific classification first, e.g. "Shopping/Groceries" then "Shopping"
+    let classification = &truelayer_tran.transaction_classification;
+    let mut category_keys: Vec<String> = (1..=classification.len())
+        .rev()
+        .map(|len| classification[..len].join("/"))
+        .collect();
+    category_keys.extend(truelayer_tran.transaction_category.clone());
+    let category = category_map.lookup(category_keys.iter().map(String::as_str));
+
+    crate::Transaction {
+        transaction_id: truelayer_tran.transaction_id,
+        timestamp: truelayer_tran.timestamp,
+        amount: milliunits(truelayer_tran.amount),
+        payee_name: match truelayer_tran.merchant_name {
+            Some(s) => Some(s),
+            None => truelayer_tran
+                .description
+                .split_whitespace()
+                .next()
+                .map(|it| it.to_string()),
+        },
+        description: truelayer_tran.description,
+        category,
+        state,
+        running_balance: truelayer_tran
+            .running_balance
+            .map(|it| milliunits(it.amount)),
+        splits: vec![],
+        exchanged: None,
+    }
 }
 
 pub fn initialize(
     ynab_config: &YnabConfig,
     token: &mut Token,
+) -> (bool, Result<Box<dyn crate::ConnectedProvider>>) {
+    let result = connect(ynab_config, token);
+    warn_consent_expiry(token);
+    result
+}
+
+fn connect(
+    ynab_config: &YnabConfig,
+    token: &mut Token,
 ) -> (bool, Result<Box<dyn crate::ConnectedProvider>>) {
     let (refreshed, access_token) = match refresh(ynab_config, token) {
         Ok((refreshed, token)) => (refreshed, token),
         Err(e) => return (false, Err(e)),
     };
+    let api_url = ynab_config.truelayer.environment.api_url();
+    let rest_client = match crate::provider::rest_client(api_url, Some(access_token.secret())) {
+        Ok(rest_client) => rest_client,
+        Err(e) => return (refreshed, Err(e)),
+    };
     let mut provider = TruelayerProvider {
         display_name: token.display_name.to_string(),
-        rest_client: new_rest_client(&access_token),
+        rest_client,
+        consent_expiry: token.consent_expiry,
+        history: History::new(token.history_days),
+        request_window_days: token.request_window_days,
+        category_map: token.category_map.clone(),
+        stable_import_ids: token.stable_import_ids,
+        balance_source: token.balance_source,
+        invert_balance: token.invert_balance,
+        pending_unavailable: vec![],
     };
     if let Err(e) = provider.test() {
         return (refreshed, Err(e));
     }
     token.display_name = provider.display_name.clone();
-    (refreshed, Ok(Box::new(provider)))
+    let consent_changed = token.consent_expiry != provider.consent_expiry;
+    token.consent_expiry = provider.consent_expiry;
+    (refreshed || consent_changed, Ok(Box::new(provider)))
+}
+
+fn warn_consent_expiry(token: &Token) {
+    if let Some(warning) = consent_warning(token, Utc::now()) {
+        eprintln!("{}", warning);
+    }
+}
+
+/// Why the token's consent needs renewing, if it has expired or is about to
+fn consent_warning(token: &Token, now: DateTime) -> Option<String> {
+    let expiry = token.consent_expiry?;
+    let remaining = expiry - now;
+    if remaining <= chrono::Duration::zero() {
+        Some(format!(
+            "Truelayer consent for {} expired at {}. Run `config reauth-truelayer` to renew it.",
+            token.display_name, expiry
+        ))
+    } else if remaining < chrono::Duration::days(CONSENT_EXPIRY_WARNING_DAYS) {
+        Some(format!(
+            "Truelayer consent for {} expires in {} days at {}. Run `config reauth-truelayer` to renew it.",
+            token.display_name,
+            remaining.num_days(),
+            expiry
+        ))
+    } else {
+        None
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -175,6 +600,59 @@ pub struct Token {
     #[serde(with = "chrono::serde::ts_seconds")]
     pub access_token_expiry: DateTime,
     pub refresh_token: oauth2::RefreshToken,
+    #[serde(default, with = "chrono::serde::ts_seconds_option")]
+    pub consent_expiry: Option<DateTime>,
+    /// Days of transaction history requested on each sync
+    #[serde(default = "crate::provider::default_history_days")]
+    pub history_days: i64,
+    /// Longest date range requested at once, longer ranges are split up
+    #[serde(default = "default_request_window_days")]
+    pub request_window_days: i64,
+    /// Truelayer classifications, e.g. "Shopping/Groceries" or "Shopping",
+    /// or transaction categories such as "ATM", to YNAB category names
+    #[serde(default)]
+    pub category_map: CategoryMap,
+    /// Use import IDs which don't change if the bank regenerates its
+    /// transaction IDs. Off for tokens added before this existed, as turning
+    /// it on re-imports transactions which were imported with the old IDs.
+    #[serde(default)]
+    pub stable_import_ids: bool,
+    /// Which balance YNAB's is reconciled with
+    #[serde(default)]
+    pub balance_source: BalanceSource,
+    /// Flip the sign of the reconciled balance, for card issuers which
+    /// report it the other way around
+    #[serde(default)]
+    pub invert_balance: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceSource {
+    /// The current balance, which usually excludes pending transactions
+    #[default]
+    Current,
+    /// The available funds less any overdraft, or for cards the credit used
+    /// according to the available credit. Usually includes pending
+    /// transactions, like YNAB's balance when they're imported.
+    Available,
+}
+
+pub const DEFAULT_REQUEST_WINDOW_DAYS: i64 = 90;
+
+fn default_request_window_days() -> i64 {
+    DEFAULT_REQUEST_WINDOW_DAYS
+}
+
+impl Token {
+    /// Takes the credentials from a new authorization, keeping everything
+    /// else configured for this provider.
+    pub fn replace_credentials(&mut self, authorized: Token) {
+        self.access_token = authorized.access_token;
+        self.access_token_expiry = authorized.access_token_expiry;
+        self.refresh_token = authorized.refresh_token;
+        self.consent_expiry = authorized.consent_expiry;
+    }
 }
 
 pub mod api {
@@ -237,7 +715,7 @@ pub mod api {
     #[derive(Serialize, Deserialize, Debug)]
     pub struct Transaction {
         // https://docs.truelayer.com/#retrieve-account-transactions
-        // TODO transaction ids are shit
+        // Not stable for every bank, see stabilise_transaction_ids
         pub transaction_id: String,
         #[serde(deserialize_with = "crate::serialisation::deserialize")]
         pub timestamp: UtcDateTime,
@@ -245,11 +723,49 @@ pub mod api {
         pub description: String,
         pub merchant_name: Option<String>,
         pub meta: Option<HashMap<String, serde_json::value::Value>>,
+        #[serde(default)]
+        pub transaction_classification: Vec<String>,
+        pub transaction_category: Option<String>,
+        pub running_balance: Option<RunningBalance>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct RunningBalance {
+        pub amount: f64,
+        pub currency: String,
+    }
+
+    // https://docs.truelayer.com/#retrieve-standing-orders
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct StandingOrder {
+        pub frequency: String,
+        pub status: Option<String>,
+        pub currency: String,
+        pub next_payment_date: Option<String>,
+        pub next_payment_amount: Option<f64>,
+        pub final_payment_date: Option<String>,
+        pub reference: Option<String>,
+        pub payee: Option<String>,
+    }
+
+    // https://docs.truelayer.com/#retrieve-direct-debits
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct DirectDebit {
+        pub direct_debit_id: String,
+        pub name: String,
+        pub status: Option<String>,
+        pub previous_payment_timestamp: Option<String>,
+        pub previous_payment_amount: Option<f64>,
+        pub currency: String,
     }
 
     #[derive(Serialize, Deserialize, Debug)]
     pub struct AccountBalance {
         pub current: f64,
+        pub available: Option<f64>,
+        pub overdraft: Option<f64>,
+        pub credit_limit: Option<f64>,
+        pub last_statement_balance: Option<f64>,
     }
 
     #[derive(Serialize, Deserialize)]
@@ -318,6 +834,53 @@ pub mod api {
         }
     }
 
+    #[derive(Serialize, Deserialize)]
+    pub struct PendingTransactionsResponse {
+        pub results: Vec<Transaction>,
+    }
+
+    impl RestPath<&str> for PendingTransactionsResponse {
+        fn get_path(account_id: &str) -> Result<String, restson::Error> {
+            Ok(format!(
+                "data/v1/accounts/{}/transactions/pending",
+                account_id
+            ))
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct CardPendingTransactionsResponse {
+        pub results: Vec<Transaction>,
+    }
+
+    impl RestPath<&str> for CardPendingTransactionsResponse {
+        fn get_path(account_id: &str) -> Result<String, restson::Error> {
+            Ok(format!("data/v1/cards/{}/transactions/pending", account_id))
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct StandingOrdersResponse {
+        pub results: Vec<StandingOrder>,
+    }
+
+    impl RestPath<&str> for StandingOrdersResponse {
+        fn get_path(account_id: &str) -> Result<String, restson::Error> {
+            Ok(format!("data/v1/accounts/{}/standing_orders", account_id))
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct DirectDebitsResponse {
+        pub results: Vec<DirectDebit>,
+    }
+
+    impl RestPath<&str> for DirectDebitsResponse {
+        fn get_path(account_id: &str) -> Result<String, restson::Error> {
+            Ok(format!("data/v1/accounts/{}/direct_debits", account_id))
+        }
+    }
+
     #[derive(Serialize, Deserialize)]
     pub struct AccountBalanceResponse {
         pub results: Vec<AccountBalance>,
@@ -341,69 +904,145 @@ pub mod api {
     }
 }
 
-pub fn new_oauth2_client(client_id: &str, client_secret: &str) -> Result<BasicClient> {
-    Ok(BasicClient::new(
-        ClientId::new(client_id.to_string()),
-        Some(ClientSecret::new(client_secret.to_string())),
-        AuthUrl::new("https://auth.truelayer.com/".to_string())?,
-        Some(TokenUrl::new(
-            "https://auth.truelayer.com/connect/token".to_string(),
-        )?),
-    )
-    .set_redirect_uri(RedirectUrl::new(
-        "https://console.truelayer.com/redirect-page".to_string(),
-    )?))
+/// Truelayer's page which displays the authorization code for copying by hand
+pub const CONSOLE_REDIRECT_URL: &str = "https://console.truelayer.com/redirect-page";
+
+/// Which truelayer servers to use, set with `truelayer` in `ynab.json`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Settings {
+    #[serde(default)]
+    pub environment: Environment,
+    /// Scopes requested when adding a connection
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// Extra parameters for the auth link, which choose the providers offered
+    /// e.g. `"providers": "uk-ob-all uk-oauth-all"`
+    #[serde(default = "default_provider_params")]
+    pub provider_params: BTreeMap<String, String>,
 }
 
-pub fn new_rest_client(access_token: &AccessToken) -> RestClient {
-    const HOST: &str = "https://api.truelayer.com/";
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            environment: Environment::default(),
+            scopes: default_scopes(),
+            provider_params: default_provider_params(),
+        }
+    }
+}
 
-    let mut rc = restson::RestClient::builder().blocking(HOST).unwrap();
+fn default_scopes() -> Vec<String> {
+    [
+        "accounts",
+        "balance",
+        "info",
+        "offline_access",
+        "transactions",
+        "cards",
+        "direct_debits",
+        "standing_orders",
+    ]
+    .iter()
+    .map(|it| it.to_string())
+    .collect()
+}
 
-    let header_fail = "Header should be valid";
+fn default_provider_params() -> BTreeMap<String, String> {
+    [
+        "enable_mock",
+        "enable_oauth_providers",
+        "enable_open_banking_providers",
+        "enable_credentials_sharing_providers",
+    ]
+    .iter()
+    .map(|it| (it.to_string(), "true".to_string()))
+    .collect()
+}
 
-    rc.set_header(
-        "Authorization",
-        &("Bearer ".to_string() + access_token.secret()),
-    )
-    .expect(header_fail);
-    rc.set_header("User-Agent", "moonstruck.dev/import-ynab")
-        .expect(header_fail);
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Environment {
+    #[default]
+    Production,
+    Sandbox,
+    /// e.g. a local mock server
+    Custom {
+        auth_url: String,
+        api_url: String,
+    },
+}
+
+impl Environment {
+    fn auth_url(&self) -> &str {
+        match self {
+            Environment::Production => "https://auth.truelayer.com/",
+            Environment::Sandbox => "https://auth.truelayer-sandbox.com/",
+            Environment::Custom { auth_url, .. } => auth_url,
+        }
+    }
+
+    fn api_url(&self) -> &str {
+        match self {
+            Environment::Production => "https://api.truelayer.com/",
+            Environment::Sandbox => "https://api.truelayer-sandbox.com/",
+            Environment::Custom { api_url, .. } => api_url,
+        }
+    }
+}
 
-    rc
+pub fn new_oauth2_client(
+    settings: &Settings,
+    client_id: &str,
+    client_secret: &str,
+    redirect_url: &str,
+) -> Result<BasicClient> {
+    let auth_url = Url::parse(settings.environment.auth_url())
+        .with_context(|| format!("Invalid auth url {}", settings.environment.auth_url()))?;
+    Ok(BasicClient::new(
+        ClientId::new(client_id.to_string()),
+        Some(ClientSecret::new(client_secret.to_string())),
+        AuthUrl::from_url(auth_url.clone()),
+        Some(TokenUrl::from_url(auth_url.join("connect/token")?)),
+    )
+    .set_redirect_uri(RedirectUrl::new(redirect_url.to_string())?))
 }
 
-pub fn get_auth_url(config: &YnabConfig) -> Result<Url> {
-    let client = new_oauth2_client(&config.truelayer_client_id, &config.truelayer_client_secret)?;
+/// Returns the URL to authenticate at, and the state parameter which the
+/// redirect back to `redirect_url` must carry.
+pub fn get_auth_url(config: &YnabConfig, redirect_url: &str) -> Result<(Url, CsrfToken)> {
+    let settings = &config.truelayer;
+    let client = new_oauth2_client(
+        settings,
+        &config.truelayer_client_id,
+        config.truelayer_client_secret.secret(),
+        redirect_url,
+    )?;
 
-    let (url, _token) = client
+    let mut request = client
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("accounts".to_string()))
-        .add_scope(Scope::new("balance".to_string()))
-        .add_scope(Scope::new("info".to_string()))
-        .add_scope(Scope::new("offline_access".to_string()))
-        .add_scope(Scope::new("transactions".to_string()))
-        .add_scope(Scope::new("cards".to_string()))
-        .add_scope(Scope::new("direct_debits".to_string()))
-        .add_scope(Scope::new("standing_orders".to_string()))
-        .add_extra_param("enable_mock", "true")
-        .add_extra_param("enable_oauth_providers", "true")
-        .add_extra_param("enable_open_banking_providers", "true")
-        .add_extra_param("enable_credentials_sharing_providers", "true")
-        .url();
-
-    Ok(url)
+        .add_scopes(settings.scopes.iter().cloned().map(Scope::new));
+    for (name, value) in &settings.provider_params {
+        request = request.add_extra_param(name, value);
+    }
+
+    Ok(request.url())
 }
 
 pub fn authorize(
     config: &YnabConfig,
     token: &str,
+    redirect_url: &str,
 ) -> Result<impl oauth2::TokenResponse<oauth2::basic::BasicTokenType>> {
-    let client = new_oauth2_client(&config.truelayer_client_id, &config.truelayer_client_secret)?;
+    let client = new_oauth2_client(
+        &config.truelayer,
+        &config.truelayer_client_id,
+        config.truelayer_client_secret.secret(),
+        redirect_url,
+    )?;
     let token = client
         .exchange_code(AuthorizationCode::new(token.to_string()))
         .request(http_client)
-        .map_err(|e| anyhow!("{:?}", e))
+        .map_err(|e| anyhow!("{}", e))
         .context("Failed to authorize with truelayer")?;
 
     Ok(token)
@@ -417,13 +1056,15 @@ pub fn refresh(ynab_config: &YnabConfig, token: &mut Token) -> Result<(bool, Acc
     }
 
     let client = new_oauth2_client(
+        &ynab_config.truelayer,
         &ynab_config.truelayer_client_id,
-        &ynab_config.truelayer_client_secret,
+        ynab_config.truelayer_client_secret.secret(),
+        CONSOLE_REDIRECT_URL,
     )?;
     let new_token = client
         .exchange_refresh_token(&token.refresh_token)
         .request(http_client)
-        .map_err(|e| anyhow!("{:?}", e))
+        .map_err(|e| anyhow!("{}", e))
         .context("Error refreshing truelayer token")?;
 
     token.access_token = new_token.access_token().clone();
@@ -438,3 +1079,306 @@ pub fn calculate_expiry_time(expires_in: std::time::Duration) -> DateTime {
         expires_in.as_secs().try_into().unwrap(),
     ))
 }
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+
+    #[test]
+    fn custom_environment() -> Result<()> {
+        let config: crate::config::YnabConfig = serde_json::from_str(
+            r#"{
+                "truelayer_client_id": "client",
+                "truelayer": {
+                    "environment": {"custom": {
+                        "auth_url": "http://localhost:8080/auth/",
+                        "api_url": "http://localhost:8080/api/"
+                    }},
+                    "scopes": ["accounts", "transactions"],
+                    "provider_params": {"providers": "mock"}
+                }
+            }"#,
+        )?;
+
+        let (url, _state) = super::get_auth_url(&config, "http://localhost:3000/")?;
+        assert_eq!("localhost", url.host_str().unwrap());
+        assert_eq!("/auth/", url.path());
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!("accounts transactions", params["scope"]);
+        assert_eq!("mock", params["providers"]);
+        assert!(!params.contains_key("enable_mock"));
+
+        Ok(())
+    }
+
+    fn provider(balance_source: super::BalanceSource) -> super::TruelayerProvider {
+        super::TruelayerProvider {
+            display_name: "test".to_string(),
+            rest_client: restson::RestClient::new_blocking("http://localhost/").unwrap(),
+            consent_expiry: None,
+            history: crate::provider::History::new(crate::provider::DEFAULT_HISTORY_DAYS),
+            request_window_days: super::DEFAULT_REQUEST_WINDOW_DAYS,
+            category_map: Default::default(),
+            stable_import_ids: true,
+            balance_source,
+            invert_balance: false,
+            pending_unavailable: vec![],
+        }
+    }
+
+    #[test]
+    fn card_balance_sources() -> Result<()> {
+        let balance = super::AccountBalance {
+            current: 250.0,
+            available: Some(700.0),
+            overdraft: None,
+            credit_limit: Some(1000.0),
+            last_statement_balance: Some(200.0),
+        };
+
+        let (current, balances) = provider(super::BalanceSource::Current)
+            .convert_balance(&balance, &crate::AccountType::Card)?;
+        assert_eq!(-250000, current);
+        assert_eq!(Some(-200000), balances.last_statement_balance);
+        assert_eq!(Some(700000), balances.available);
+
+        let (available, _) = provider(super::BalanceSource::Available)
+            .convert_balance(&balance, &crate::AccountType::Card)?;
+        assert_eq!(-300000, available);
+
+        // Rounded rather than truncated to 2009, which would need reconciling
+        let balance = super::AccountBalance {
+            current: 2.01,
+            available: None,
+            overdraft: None,
+            credit_limit: None,
+            last_statement_balance: None,
+        };
+        let (current, _) = provider(super::BalanceSource::Current)
+            .convert_balance(&balance, &crate::AccountType::Account)?;
+        assert_eq!(2010, current);
+
+        Ok(())
+    }
+
+    fn transaction(id: &str, timestamp: &str, description: &str) -> Result<super::Transaction> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "transaction_id": id,
+            "timestamp": timestamp,
+            "amount": -3.0,
+            "description": description,
+        }))?)
+    }
+
+    #[test]
+    fn overlapping_windows() -> Result<()> {
+        use super::Fetched;
+        use crate::files::merge_overlapping;
+
+        let mut fetched = vec![];
+        merge_overlapping(
+            &mut fetched,
+            vec![
+                Fetched(transaction("a1", "2021-03-01T00:00:00Z", "CAFE")?),
+                Fetched(transaction("a2", "2021-03-01T00:00:00Z", "CAFE")?),
+                Fetched(transaction("a3", "2021-03-08T00:00:00Z", "SHOP")?),
+            ],
+        );
+        // The bank regenerated its IDs for the next window, which shares 8 March
+        merge_overlapping(
+            &mut fetched,
+            vec![
+                Fetched(transaction("b1", "2021-03-08T00:00:00Z", "SHOP")?),
+                Fetched(transaction("b2", "2021-03-09T00:00:00Z", "SHOP")?),
+            ],
+        );
+        let mut transactions: Vec<_> = fetched.into_iter().map(|it| it.0).collect();
+        super::stabilise_transaction_ids(&mut transactions);
+
+        let ids: Vec<_> = transactions
+            .iter()
+            .map(|it| it.transaction_id.rsplit(':').next().unwrap())
+            .collect();
+        // Both coffees are kept, the boundary transaction only once
+        assert_eq!(vec!["1", "2", "1", "1"], ids);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scheduled_payments() -> Result<()> {
+        use crate::Frequency;
+
+        for (code, frequency) in [
+            ("EvryDay", Some(Frequency::Daily)),
+            ("IntrvlWkDay:01:03", Some(Frequency::Weekly)),
+            ("IntrvlWkDay:02:03", Some(Frequency::EveryOtherWeek)),
+            ("IntrvlMnthDay:01:15", Some(Frequency::Monthly)),
+            ("IntrvlMnthDay:03:15", Some(Frequency::Every3Months)),
+            ("QtrDay:ENGLISH", Some(Frequency::Every3Months)),
+            ("IntrvlMnthDay:06:-01", Some(Frequency::TwiceAYear)),
+            ("IntrvlMnthDay:12:01", Some(Frequency::Yearly)),
+            ("IntrvlWkDay:03:01", None),
+            ("Fortnightly", None),
+        ] {
+            assert_eq!(frequency, super::parse_frequency(code), "{}", code);
+        }
+
+        let standing_order: super::StandingOrder = serde_json::from_str(
+            r#"{
+                "frequency": "IntrvlMnthDay:01:15", "status": "Active", "currency": "GBP",
+                "next_payment_date": "2021-03-15T00:00:00+00:00", "next_payment_amount": 2.01,
+                "reference": "RENT", "payee": "Landlord"
+            }"#,
+        )?;
+        let scheduled = super::convert_standing_order(standing_order)
+            .ok_or_else(|| anyhow!("Standing order not converted"))?;
+        // Paid out, and not truncated to 2009
+        assert_eq!(-2010, scheduled.amount);
+        assert_eq!("Landlord", scheduled.payee_name);
+        assert_eq!("Standing order RENT", scheduled.memo);
+        assert_eq!(
+            "2021-03-15",
+            scheduled.next_date.format("%Y-%m-%d").to_string()
+        );
+
+        let direct_debit: super::DirectDebit = serde_json::from_str(
+            r#"{
+                "direct_debit_id": "dd1", "name": "Energy Co", "status": "Active", "currency": "GBP",
+                "previous_payment_timestamp": "2021-02-20T00:00:00+00:00",
+                "previous_payment_amount": 45.3
+            }"#,
+        )?;
+        let scheduled = super::convert_direct_debit(direct_debit)
+            .ok_or_else(|| anyhow!("Direct debit not converted"))?;
+        assert_eq!(-45300, scheduled.amount);
+        assert_eq!(Frequency::Monthly, scheduled.frequency);
+        assert_eq!(
+            "2021-03-20",
+            scheduled.next_date.format("%Y-%m-%d").to_string()
+        );
+
+        let cancelled: super::DirectDebit = serde_json::from_str(
+            r#"{
+                "direct_debit_id": "dd2", "name": "Gym", "status": "Inactive", "currency": "GBP",
+                "previous_payment_timestamp": "2021-02-20T00:00:00+00:00",
+                "previous_payment_amount": 30.0
+            }"#,
+        )?;
+        assert!(super::convert_direct_debit(cancelled).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn classification_categories() -> Result<()> {
+        let category_map = crate::config::CategoryMap(
+            [
+                ("Shopping/Groceries", "Groceries"),
+                ("Shopping", "Stuff"),
+                ("ATM", "Cash"),
+            ]
+            .iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect(),
+        );
+        let category = |classification: serde_json::Value, category: &str| -> Result<_> {
+            let transaction: super::Transaction = serde_json::from_value(serde_json::json!({
+                "transaction_id": "t1",
+                "timestamp": "2021-03-01T00:00:00Z",
+                "amount": -3.0,
+                "description": "SHOP",
+                "transaction_classification": classification,
+                "transaction_category": category,
+            }))?;
+            Ok(super::convert_transaction(
+                transaction,
+                crate::TransactionState::Cleared,
+                &category_map,
+            )
+            .category)
+        };
+
+        // The most specific classification wins
+        assert_eq!(
+            Some("Groceries".to_string()),
+            category(serde_json::json!(["Shopping", "Groceries"]), "PURCHASE")?
+        );
+        assert_eq!(
+            Some("Stuff".to_string()),
+            category(serde_json::json!(["Shopping", "Clothes"]), "PURCHASE")?
+        );
+        // Then the transaction category
+        assert_eq!(
+            Some("Cash".to_string()),
+            category(serde_json::json!([]), "ATM")?
+        );
+        assert_eq!(None, category(serde_json::json!(["Bills"]), "DEBIT")?);
+
+        Ok(())
+    }
+
+    fn token(access_token: &str, consent_expiry: Option<super::DateTime>) -> super::Token {
+        super::Token {
+            display_name: "Bank".to_string(),
+            access_token: oauth2::AccessToken::new(access_token.to_string()),
+            access_token_expiry: chrono::Utc::now(),
+            refresh_token: oauth2::RefreshToken::new(format!("refresh-{}", access_token)),
+            consent_expiry,
+            history_days: crate::provider::DEFAULT_HISTORY_DAYS,
+            request_window_days: super::DEFAULT_REQUEST_WINDOW_DAYS,
+            category_map: Default::default(),
+            stable_import_ids: false,
+            balance_source: Default::default(),
+            invert_balance: false,
+        }
+    }
+
+    #[test]
+    fn reauth_keeps_settings() {
+        let consent_expiry = chrono::Utc::now() + chrono::Duration::days(90);
+        let mut existing = token("old", None);
+        existing.display_name = "Joint account".to_string();
+        existing.history_days = 30;
+        existing.request_window_days = 7;
+        existing.category_map = crate::config::CategoryMap(
+            vec![("ATM".to_string(), "Cash".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        existing.stable_import_ids = true;
+        existing.balance_source = super::BalanceSource::Available;
+        existing.invert_balance = true;
+
+        existing.replace_credentials(token("new", Some(consent_expiry)));
+
+        // Credentials and consent come from the new authorization
+        assert_eq!("new", existing.access_token.secret());
+        assert_eq!("refresh-new", existing.refresh_token.secret());
+        assert_eq!(Some(consent_expiry), existing.consent_expiry);
+        // Everything set up for the connection is kept
+        assert_eq!("Joint account", existing.display_name);
+        assert_eq!(30, existing.history_days);
+        assert_eq!(7, existing.request_window_days);
+        assert_eq!(
+            Some("Cash"),
+            existing.category_map.0.get("ATM").map(String::as_str)
+        );
+        assert!(existing.stable_import_ids);
+        assert_eq!(super::BalanceSource::Available, existing.balance_source);
+        assert!(existing.invert_balance);
+    }
+
+    #[test]
+    fn consent_warnings() {
+        let now = chrono::Utc::now();
+        let warning = |days| {
+            super::consent_warning(&token("t", Some(now + chrono::Duration::days(days))), now)
+        };
+
+        assert_eq!(None, super::consent_warning(&token("t", None), now));
+        assert_eq!(None, warning(30));
+        assert!(warning(5).is_some_and(|it| it.contains("expires in 5 days")));
+        assert!(warning(-1).is_some_and(|it| it.contains("expired at")));
+    }
+}
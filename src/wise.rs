@@ -0,0 +1,478 @@
+//! Wise multi-currency accounts, using a personal API token. Each currency
+//! balance is a separate account, and conversions between them carry the
+//! amount Wise actually exchanged.
+
+use crate::config::CategoryMap;
+use crate::prelude::*;
+use crate::provider::{debug_as_display_name, History};
+use crate::secret::Secret;
+use crate::{AccountType, TransactionState};
+use api::*;
+use chrono::Utc;
+use restson::Response;
+
+const API_URL: &str = "https://api.wise.com/";
+const IMPORT_ID_PREFIX: &str = "WISE";
+
+struct WiseProvider {
+    display_name: String,
+    rest_client: RestClient,
+    profile_id: i64,
+    history: History,
+    category_map: CategoryMap,
+    balances: Vec<Balance>,
+    mapped_accounts: Vec<String>,
+}
+
+debug_as_display_name!(WiseProvider);
+
+impl crate::ConnectedProvider for WiseProvider {
+    fn get_accounts(&mut self) -> Result<Vec<crate::Account>> {
+        let balances: Response<BalancesResponse> = self
+            .rest_client
+            .get_with(self.profile_id, &[("types", "STANDARD")])?;
+        self.balances = balances.into_inner().0;
+
+        Ok(self
+            .balances
+            .iter()
+            .map(|balance| crate::Account {
+                account_id: account_id(balance.id),
+                currency: balance.currency.clone(),
+                display_name: balance
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("Wise {}", balance.currency)),
+                ty: AccountType::Account,
+                balance: Some(milliunits(balance.amount.value)),
+                balances: Default::default(),
+                identifiers: Default::default(),
+            })
+            .collect())
+    }
+
+    fn get_transactions(&mut self, acc: &crate::Account) -> Result<Vec<crate::Transaction>> {
+        let balance = self
+            .balances
+            .iter()
+            .find(|it| account_id(it.id) == acc.account_id)
+            .ok_or_else(|| anyhow!("Unknown wise balance {}", acc.display_name))?;
+
+        let from = self.history.from();
+        let from = from.format("%Y-%m-%dT%H:%M:%S.000Z").to_string();
+        let to = Utc::now().format("%Y-%m-%dT%H:%M:%S.000Z").to_string();
+
+        let statement: Response<Statement> = self
+            .rest_client
+            .get_with(
+                (self.profile_id, balance.id),
+                &[
+                    ("currency", balance.currency.as_str()),
+                    ("intervalStart", from.as_str()),
+                    ("intervalEnd", to.as_str()),
+                    ("type", "COMPACT"),
+                ],
+            )
+            .context(
+                "Error fetching wise statement, which may need strong customer authentication",
+            )?;
+
+        Ok(convert_statement(
+            statement.into_inner(),
+            &self.balances,
+            &self.mapped_accounts,
+            &self.category_map,
+        ))
+    }
+
+    fn set_history_start(&mut self, from: UtcDate) {
+        self.history.set_start(from);
+    }
+
+    fn set_mapped_accounts(&mut self, account_ids: &[&str]) {
+        self.mapped_accounts = account_ids.iter().map(|it| it.to_string()).collect();
+    }
+}
+
+fn account_id(balance_id: i64) -> String {
+    format!("wise:{}", balance_id)
+}
+
+fn milliunits(value: f64) -> i64 {
+    (value * 1000.0).round() as i64
+}
+
+fn convert_statement(
+    statement: Statement,
+    balances: &[Balance],
+    mapped_accounts: &[String],
+    category_map: &CategoryMap,
+) -> Vec<crate::Transaction> {
+    statement
+        .transactions
+        .into_iter()
+        .filter_map(|it| convert_transaction(it, balances, mapped_accounts, category_map))
+        .collect()
+}
+
+fn convert_transaction(
+    wise_tran: StatementTransaction,
+    balances: &[Balance],
+    mapped_accounts: &[String],
+    category_map: &CategoryMap,
+) -> Option<crate::Transaction> {
+    let details = wise_tran.details;
+    let amount = milliunits(wise_tran.amount.value);
+    let currency = &wise_tran.amount.currency;
+    // Signed like `amount`
+    let exchanged = |other: &Amount| crate::Money {
+        amount: amount.signum() * milliunits(other.value).abs(),
+        currency: other.currency.clone(),
+    };
+
+    let (payee_name, exchanged) = if details.ty == "CONVERSION" {
+        // When both balances are synced, only the one which receives the
+        // money imports the conversion, as a transfer, and YNAB adds the other
+        // side. Otherwise each side is imported by its own balance.
+        let other = if amount < 0 {
+            details.target_amount.as_ref()
+        } else {
+            details.source_amount.as_ref()
+        };
+        let other_account = other
+            .and_then(|other| {
+                balances
+                    .iter()
+                    .find(|it| it.currency.eq_ignore_ascii_case(&other.currency))
+            })
+            .map(|it| account_id(it.id))
+            .filter(|it| mapped_accounts.contains(it));
+        if amount < 0 && other_account.is_some() {
+            return None;
+        }
+        (other_account, other.map(exchanged))
+    } else {
+        let payee_name = details
+            .merchant
+            .as_ref()
+            .and_then(|it| it.name.clone())
+            .or_else(|| details.sender_name.clone())
+            .or_else(|| details.recipient.as_ref().and_then(|it| it.name.clone()));
+        let exchanged = wise_tran
+            .exchange_details
+            .as_ref()
+            .and_then(|it| it.for_amount.as_ref())
+            .filter(|it| !it.currency.eq_ignore_ascii_case(currency))
+            .map(exchanged);
+        (payee_name, exchanged)
+    };
+
+    let merchant_category = details.merchant.as_ref().and_then(|it| it.category.clone());
+    let category = category_map.lookup(
+        details
+            .category
+            .iter()
+            .chain(merchant_category.iter())
+            .map(String::as_str),
+    );
+    let description = details
+        .payment_reference
+        .filter(|it| !it.is_empty())
+        .or(details.description)
+        .unwrap_or_default();
+
+    Some(crate::Transaction {
+        transaction_id: crate::import_id::fit(&format!(
+            "{}:{}",
+            IMPORT_ID_PREFIX, wise_tran.reference_number
+        )),
+        timestamp: wise_tran.date,
+        amount,
+        payee_name: payee_name.or_else(|| Some(description.clone())),
+        description,
+        category,
+        state: TransactionState::Cleared,
+        running_balance: wise_tran.running_balance.map(|it| milliunits(it.value)),
+        splits: vec![],
+        exchanged,
+    })
+}
+
+pub fn initialize(token: &mut Token) -> (bool, Result<Box<dyn crate::ConnectedProvider>>) {
+    let had_profile = token.profile_id.is_some();
+    let result = connect(token);
+    (!had_profile && token.profile_id.is_some(), result)
+}
+
+fn connect(token: &mut Token) -> Result<Box<dyn crate::ConnectedProvider>> {
+    let rest_client = crate::provider::rest_client(API_URL, Some(token.api_token.secret()))?;
+    let profile_id = match token.profile_id {
+        Some(profile_id) => profile_id,
+        None => {
+            let profiles: Response<ProfilesResponse> = rest_client.get(())?;
+            let profiles = profiles.into_inner().0;
+            let profile = profiles
+                .iter()
+                .find(|it| it.ty.eq_ignore_ascii_case("personal"))
+                .or_else(|| profiles.first())
+                .ok_or_else(|| anyhow!("No wise profiles"))?;
+            token.profile_id = Some(profile.id);
+            profile.id
+        }
+    };
+
+    Ok(Box::new(WiseProvider {
+        display_name: token.display_name.clone(),
+        rest_client,
+        profile_id,
+        history: History::new(token.history_days),
+        category_map: token.category_map.clone(),
+        balances: vec![],
+        mapped_accounts: vec![],
+    }))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Token {
+    pub display_name: String,
+    /// Read only personal API token
+    pub api_token: Secret,
+    /// Which profile's balances to import, the personal one if not set
+    #[serde(default)]
+    pub profile_id: Option<i64>,
+    /// Days of balance statements fetched on each sync
+    #[serde(default = "crate::provider::default_history_days")]
+    pub history_days: i64,
+    /// Wise card transaction categories, e.g. "Eating Places, Restaurants",
+    /// to YNAB category names
+    #[serde(default)]
+    pub category_map: CategoryMap,
+}
+
+pub mod api {
+    use crate::prelude::*;
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Amount {
+        pub value: f64,
+        pub currency: String,
+    }
+
+    // https://docs.wise.com/api-docs/api-reference/profile
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Profile {
+        pub id: i64,
+        #[serde(rename = "type")]
+        pub ty: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(transparent)]
+    pub struct ProfilesResponse(pub Vec<Profile>);
+
+    impl RestPath<()> for ProfilesResponse {
+        fn get_path(_: ()) -> Result<String, restson::Error> {
+            Ok("v2/profiles".to_string())
+        }
+    }
+
+    // https://docs.wise.com/api-docs/api-reference/balance
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Balance {
+        pub id: i64,
+        pub currency: String,
+        pub amount: Amount,
+        pub name: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(transparent)]
+    pub struct BalancesResponse(pub Vec<Balance>);
+
+    impl RestPath<i64> for BalancesResponse {
+        fn get_path(profile_id: i64) -> Result<String, restson::Error> {
+            Ok(format!("v4/profiles/{}/balances", profile_id))
+        }
+    }
+
+    // https://docs.wise.com/api-docs/api-reference/balance-statement
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Statement {
+        pub transactions: Vec<StatementTransaction>,
+    }
+
+    impl RestPath<(i64, i64)> for Statement {
+        fn get_path((profile_id, balance_id): (i64, i64)) -> Result<String, restson::Error> {
+            Ok(format!(
+                "v1/profiles/{}/balance-statements/{}/statement.json",
+                profile_id, balance_id
+            ))
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct StatementTransaction {
+        #[serde(deserialize_with = "crate::serialisation::deserialize")]
+        pub date: UtcDateTime,
+        /// Negative for money out
+        pub amount: Amount,
+        pub details: Details,
+        pub exchange_details: Option<ExchangeDetails>,
+        pub running_balance: Option<Amount>,
+        pub reference_number: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Details {
+        /// e.g. `CARD`, `CONVERSION`, `DEPOSIT` or `TRANSFER`
+        #[serde(rename = "type")]
+        pub ty: String,
+        pub description: Option<String>,
+        /// What a `CONVERSION` took from one balance
+        pub source_amount: Option<Amount>,
+        /// What a `CONVERSION` added to the other balance
+        pub target_amount: Option<Amount>,
+        pub category: Option<String>,
+        pub merchant: Option<Merchant>,
+        pub sender_name: Option<String>,
+        pub recipient: Option<Recipient>,
+        pub payment_reference: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Merchant {
+        pub name: Option<String>,
+        pub category: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Recipient {
+        pub name: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ExchangeDetails {
+        /// The amount in the currency paid, e.g. a card payment abroad
+        pub for_amount: Option<Amount>,
+        pub rate: Option<f64>,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+
+    #[test]
+    fn conversions() -> Result<()> {
+        let balances: super::BalancesResponse = serde_json::from_str(
+            r#"[
+                {"id": 100, "currency": "GBP", "amount": {"value": 20.0, "currency": "GBP"}},
+                {"id": 200, "currency": "EUR", "amount": {"value": 110.0, "currency": "EUR"}}
+            ]"#,
+        )?;
+        let statement = r#"{"transactions": [
+                {
+                    "type": "CREDIT", "date": "2021-03-01T10:00:00.000Z",
+                    "amount": {"value": 115.0, "currency": "EUR"},
+                    "details": {
+                        "type": "CONVERSION", "description": "Converted 100.00 GBP to 115.00 EUR",
+                        "sourceAmount": {"value": 100.0, "currency": "GBP"},
+                        "targetAmount": {"value": 115.0, "currency": "EUR"}
+                    },
+                    "exchangeDetails": null,
+                    "runningBalance": {"value": 115.0, "currency": "EUR"},
+                    "referenceNumber": "BALANCE-1"
+                },
+                {
+                    "type": "DEBIT", "date": "2021-03-02T10:00:00.000Z",
+                    "amount": {"value": -5.0, "currency": "EUR"},
+                    "details": {
+                        "type": "CARD", "description": "Card transaction of 6.00 USD",
+                        "category": "Eating Places, Restaurants",
+                        "merchant": {"name": "Diner", "category": "Eating Places, Restaurants"}
+                    },
+                    "exchangeDetails": {"forAmount": {"value": 6.0, "currency": "USD"}, "rate": 1.2},
+                    "runningBalance": {"value": 110.0, "currency": "EUR"},
+                    "referenceNumber": "CARD-2"
+                },
+                {
+                    "type": "DEBIT", "date": "2021-03-03T10:00:00.000Z",
+                    "amount": {"value": -10.0, "currency": "EUR"},
+                    "details": {
+                        "type": "CONVERSION", "description": "Converted 10.00 EUR to 8.70 GBP",
+                        "sourceAmount": {"value": 10.0, "currency": "EUR"},
+                        "targetAmount": {"value": 8.7, "currency": "GBP"}
+                    },
+                    "referenceNumber": "BALANCE-3"
+                }
+            ]}"#;
+        let category_map = crate::config::CategoryMap(
+            vec![(
+                "Eating Places, Restaurants".to_string(),
+                "Dining Out".to_string(),
+            )]
+            .into_iter()
+            .collect(),
+        );
+
+        let mapped = ["wise:100".to_string(), "wise:200".to_string()];
+        let transactions = super::convert_statement(
+            serde_json::from_str(statement)?,
+            &balances.0,
+            &mapped,
+            &category_map,
+        );
+
+        assert_eq!(2, transactions.len());
+        assert_eq!(115000, transactions[0].amount);
+        assert_eq!(Some("wise:100"), transactions[0].payee_name.as_deref());
+        assert_eq!(
+            Some(crate::Money {
+                amount: 100000,
+                currency: "GBP".to_string()
+            }),
+            transactions[0].exchanged
+        );
+        assert_eq!("WISE:BALANCE-1", transactions[0].transaction_id);
+
+        assert_eq!(-5000, transactions[1].amount);
+        assert_eq!(Some("Diner"), transactions[1].payee_name.as_deref());
+        assert_eq!(Some("Dining Out"), transactions[1].category.as_deref());
+        assert_eq!(
+            Some(-6000),
+            transactions[1].exchanged.as_ref().map(|it| it.amount)
+        );
+        assert_eq!(Some(110000), transactions[1].running_balance);
+
+        // Without the GBP balance in YNAB, both sides are kept by the EUR one
+        let transactions = super::convert_statement(
+            serde_json::from_str(statement)?,
+            &balances.0,
+            &mapped[1..],
+            &category_map,
+        );
+
+        assert_eq!(3, transactions.len());
+        assert_eq!(
+            Some("Converted 100.00 GBP to 115.00 EUR"),
+            transactions[0].payee_name.as_deref()
+        );
+        assert_eq!(-10000, transactions[2].amount);
+        assert_eq!(
+            Some("Converted 10.00 EUR to 8.70 GBP"),
+            transactions[2].payee_name.as_deref()
+        );
+        assert_eq!(
+            Some(crate::Money {
+                amount: -8700,
+                currency: "GBP".to_string()
+            }),
+            transactions[2].exchanged
+        );
+
+        Ok(())
+    }
+}
@@ -1,123 +1,520 @@
 use crate::config::Config;
 use crate::prelude::*;
-use crate::Transaction;
+use crate::{AccountIdentifiers, Frequency, ScheduledTransaction, Transaction, TransactionState};
 use anyhow::ensure;
 use api::*;
 use chrono::Utc;
 use restson::Response;
 
-pub fn sync(config: &mut Config) -> Result<()> {
+/// Prefix for the import IDs of pending transactions from providers which
+/// settle transactions under a new ID. These are deleted once the provider
+/// stops reporting them as pending.
+const PENDING_PREFIX: &str = "P:";
+
+/// Identifiers entered in the notes of a YNAB account, which link it to a
+/// provider's account, e.g. `ACCOUNT_ID="..."`, `IBAN="..."`,
+/// `SORT_CODE="..." ACCOUNT_NUMBER="..."` or `CARD="1234"`.
+#[derive(Debug, Default, PartialEq)]
+struct AccountNote {
+    account_id: Option<String>,
+    iban: Option<String>,
+    sort_code: Option<String>,
+    account_number: Option<String>,
+    card: Option<String>,
+}
+
+impl AccountNote {
+    fn parse(note: &str) -> AccountNote {
+        let value = |key: &str| {
+            let pre = format!("{}=\"", key);
+            let rest = &note[(note.find(&pre)? + pre.len())..];
+            Some(rest[..rest.find('"')?].to_string())
+        };
+        AccountNote {
+            account_id: value("ACCOUNT_ID"),
+            iban: value("IBAN"),
+            sort_code: value("SORT_CODE"),
+            account_number: value("ACCOUNT_NUMBER"),
+            card: value("CARD"),
+        }
+    }
+
+    /// Whether the IBAN or the sort code and account number match, ignoring
+    /// spaces, dashes and case
+    fn matches_bank_details(&self, identifiers: &AccountIdentifiers) -> bool {
+        let same = |a: &Option<String>, b: &Option<String>| match (a, b) {
+            (Some(a), Some(b)) => {
+                let a = normalise_identifier(a);
+                !a.is_empty() && a == normalise_identifier(b)
+            }
+            _ => false,
+        };
+
+        same(&self.iban, &identifiers.iban)
+            || (same(&self.sort_code, &identifiers.sort_code)
+                && same(&self.account_number, &identifiers.account_number))
+    }
+
+    /// Whether the card number ends in the digits given by `CARD`
+    fn matches_card(&self, identifiers: &AccountIdentifiers) -> bool {
+        match (&self.card, &identifiers.partial_card_number) {
+            (Some(card), Some(partial)) => {
+                let card = normalise_identifier(card);
+                !card.is_empty() && normalise_identifier(partial).ends_with(&card)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn normalise_identifier(value: &str) -> String {
+    value
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Finds the YNAB account for a provider's account by its ID, or failing
+/// that by bank details, which keep working when a renewed consent changes
+/// the provider's account IDs. `accounts` are those of every provider, as
+/// the last digits of a card only identify it if no other card shares them.
+fn find_ynab_account<'a>(
+    account_notes: &[(AccountNote, &'a Account)],
+    accounts: &[&crate::Account],
+    account: &crate::Account,
+) -> Option<&'a Account> {
+    let by_id = account_notes
+        .iter()
+        .find(|(note, _)| note.account_id.as_deref() == Some(account.account_id.as_str()));
+    let by_details = || {
+        account_notes
+            .iter()
+            .find(|(note, _)| note.matches_bank_details(&account.identifiers))
+    };
+    if let Some((_, acc)) = by_id.or_else(by_details) {
+        return Some(acc);
+    }
+
+    let by_card: Vec<_> = account_notes
+        .iter()
+        .filter(|(note, _)| note.matches_card(&account.identifiers))
+        .collect();
+    let (note, acc) = by_card.first()?;
+    let sharing = accounts
+        .iter()
+        .filter(|it| note.matches_card(&it.identifiers))
+        .count();
+    if by_card.len() > 1 || sharing > 1 {
+        println!(
+            "Not matching {} by card number, as it matches {} YNAB accounts and {} cards. \
+             Use ACCOUNT_ID or more digits of the card instead.",
+            account.display_name,
+            by_card.len(),
+            sharing
+        );
+        return None;
+    }
+    Some(acc)
+}
+
+/// Notes which would link a YNAB account to this account, for the sync log
+fn note_keys(account: &crate::Account) -> String {
+    let identifiers = &account.identifiers;
+    let mut keys = vec![format!("ACCOUNT_ID=\"{}\"", account.account_id)];
+    if let Some(iban) = &identifiers.iban {
+        keys.push(format!("IBAN=\"{}\"", iban));
+    }
+    if let (Some(sort_code), Some(number)) = (&identifiers.sort_code, &identifiers.account_number) {
+        keys.push(format!(
+            "SORT_CODE=\"{}\" ACCOUNT_NUMBER=\"{}\"",
+            sort_code, number
+        ));
+    }
+    if let Some(card) = &identifiers.partial_card_number {
+        keys.push(format!("CARD=\"{}\"", card));
+    }
+    keys.join(" or ")
+}
+
+/// Imports transactions from every provider into YNAB.
+/// `history_start` requests older transactions than usual for a backfill.
+pub fn sync(config: &mut Config, history_start: Option<UtcDate>) -> Result<()> {
     ensure!(
         !&config.ynab_config.access_token.is_empty(),
         "access_token for YNAB must be set in config"
     );
 
     let currency_converter = crate::currency::load_currency_converter()?;
-    let mut rc = new_rest_client(&config.ynab_config.access_token);
+    let mut rc = new_rest_client(config.ynab_config.access_token.secret());
     let ynab_accounts = get_accounts(&mut rc, &config.ynab_config.budget_id)?;
     let budget = get_budget(&mut rc, &config.ynab_config.budget_id)?;
     let currency = &budget.currency_format.iso_code;
     println!("{:#?}", ynab_accounts);
 
+    let account_notes: Vec<(AccountNote, &Account)> = ynab_accounts
+        .iter()
+        .filter_map(|acc| Some((AccountNote::parse(acc.note.as_deref()?), acc)))
+        .collect();
     let mut ided_accounts: HashMap<String, &Account> = HashMap::new();
-    for acc in &ynab_accounts {
-        let pre = "ACCOUNT_ID=\"";
-        if let Some(note) = &acc.note {
-            if let Some(idx) = note.find(pre) {
-                let rest = &note[(idx + pre.len())..];
-                if let Some(end_idx) = rest.find('"') {
-                    let id = &rest[0..end_idx];
-                    println!("Found account ID {} for {}", id, acc.name);
-                    ided_accounts.insert(id.to_string(), acc);
-                }
-            }
+    for (note, acc) in &account_notes {
+        if let Some(id) = &note.account_id {
+            println!("Found account ID {} for {}", id, acc.name);
+            ided_accounts.insert(id.to_string(), acc);
         }
     }
 
     let mut providers = crate::load_connections(config)?;
-    let mut visited_accounts = vec![];
+    if let Some(history_start) = history_start {
+        for provider in &mut providers {
+            provider.set_history_start(history_start);
+        }
+    }
+    let mut provider_accounts = vec![];
     for provider in &mut providers {
-        for account in provider.get_accounts()? {
-            let ynab_account = ided_accounts.get(&account.account_id);
+        provider_accounts.push(provider.get_accounts()?);
+    }
+    let all_accounts: Vec<&crate::Account> = provider_accounts.iter().flatten().collect();
+    // Also used to turn transfer payees, given as the other account's ID, into YNAB accounts
+    let matched_accounts: HashMap<&str, &Account> = all_accounts
+        .iter()
+        .filter_map(|account| {
+            let ynab_account = find_ynab_account(&account_notes, &all_accounts, account)?;
+            Some((account.account_id.as_str(), ynab_account))
+        })
+        .collect();
+    for (provider, accounts) in providers.iter_mut().zip(&provider_accounts) {
+        let mapped: Vec<&str> = accounts
+            .iter()
+            .map(|it| it.account_id.as_str())
+            .filter(|it| matched_accounts.contains_key(it))
+            .collect();
+        provider.set_mapped_accounts(&mapped);
+    }
+
+    let mut visited_accounts = vec![];
+    for (provider, accounts) in providers.iter_mut().zip(&provider_accounts) {
+        for account in accounts {
+            let ynab_account = matched_accounts.get(account.account_id.as_str()).copied();
             println!(
-                "Looking for ynab account with ACCOUNT_ID=\"{}\" for {:?} {}",
-                account.account_id, provider, account.display_name
+                "Looking for ynab account with {} for {:?} {}",
+                note_keys(account),
+                provider,
+                account.display_name
             );
             if let Some(ynab_account) = ynab_account {
-                let mut trans = provider.get_transactions(&account)?;
+                let mut trans = provider.get_transactions(account)?;
+                let prefix_pending = provider.pending_ids_change(account);
                 println!(
                     "Found ynab account {} = {}. {} Transactions to import.",
                     ynab_account.name,
                     ynab_account.balance,
                     trans.len()
                 );
+                let mut scheduled = provider.get_scheduled_transactions(account)?;
                 if !account.currency.eq_ignore_ascii_case(currency) {
+                    let rate = currency_converter
+                        .get_rate(Utc::today(), &account.currency, currency)
+                        .ok_or_else(|| anyhow!("Missing rates for {:#?}", &account))?;
+                    for scheduled in &mut scheduled {
+                        scheduled.amount =
+                            (scheduled.amount as crate::currency::Rate * rate) as i64;
+                    }
                     for tran in &mut trans {
+                        if let Some(exchanged) = &tran.exchanged {
+                            if exchanged.currency.eq_ignore_ascii_case(currency) {
+                                let amount = exchanged.amount;
+                                set_converted_amount(tran, amount);
+                                continue;
+                            }
+                        }
                         let rate = currency_converter
                             .get_rate(tran.timestamp.date(), &account.currency, currency)
                             .ok_or_else(|| anyhow!("Missing rates for {:#?}", tran))?;
-                        tran.amount = (tran.amount as crate::currency::Rate * rate) as i64;
+                        let amount = (tran.amount as crate::currency::Rate * rate) as i64;
+                        set_converted_amount(tran, amount);
                     }
                 }
                 for tran in &mut trans {
                     if let Some(payee_id) = &tran.payee_name {
-                        if let Some(acc) = ided_accounts.get(payee_id) {
+                        let transfer_account = matched_accounts
+                            .get(payee_id.as_str())
+                            .or_else(|| ided_accounts.get(payee_id));
+                        if let Some(acc) = transfer_account {
                             tran.payee_name = Some(acc.id.clone());
                         }
                     }
                 }
+                // Running balances are in the account's currency, so can only
+                // be compared with YNAB's when no conversion was needed
+                let running_balances = if account.currency.eq_ignore_ascii_case(currency) {
+                    trans
+                        .iter()
+                        .filter_map(|it| {
+                            it.running_balance.map(|balance| BalancePoint {
+                                timestamp: it.timestamp,
+                                balance,
+                                description: it.description.clone(),
+                            })
+                        })
+                        .collect()
+                } else {
+                    vec![]
+                };
+                update_transaction_states(
+                    &mut rc,
+                    &config.ynab_config.budget_id,
+                    &ynab_account.id,
+                    &trans,
+                    prefix_pending,
+                )?;
+                let trans = trans
+                    .into_iter()
+                    .filter(|it| it.state != TransactionState::Reverted)
+                    .collect();
                 import_transactions(
                     &mut rc,
                     &config.ynab_config.budget_id,
                     &ynab_account.id,
                     trans,
+                    prefix_pending,
                 )?;
-                visited_accounts.push(account);
-            }
-        }
-    }
-
-    for account in visited_accounts {
-        let ynab_account = ided_accounts.get(&account.account_id);
-        if let Some(ynab_account) = ynab_account {
-            let ynab_account =
-                get_account(&mut rc, &config.ynab_config.budget_id, &ynab_account.id)?;
-
-            let rate = currency_converter
-                .get_rate(Utc::today(), &account.currency, currency)
-                .ok_or_else(|| anyhow!("Missing rates for {:#?}", &account))?;
-            let calc_balance = (account.balance as crate::currency::Rate * rate) as i64;
-            println!(
-                "Account {} = {}. Expected balance {}",
-                ynab_account.name, ynab_account.balance, calc_balance
-            );
-            if should_reconcile(ynab_account.balance, calc_balance) {
-                let correction = crate::Transaction {
-                    transaction_id: "correction_".to_string() + &Utc::now().timestamp().to_string(),
-                    timestamp: Utc::now(),
-                    amount: calc_balance - ynab_account.balance,
-                    description: format!(
-                        "Reconciliation. {} @ {} = {}",
-                        (account.balance as f64 / 1000.0),
-                        rate,
-                        (calc_balance as f64 / 1000.0)
-                    ),
-                    category: Some("Inflow: To be Budgeted".to_string()),
-                    payee_name: Some("Sync Reconciliation".to_string()),
-                };
-                import_transactions(
+                sync_scheduled_transactions(
                     &mut rc,
                     &config.ynab_config.budget_id,
                     &ynab_account.id,
-                    vec![correction],
+                    scheduled,
+                    config.ynab_config.create_scheduled_transactions,
                 )?;
+                visited_accounts.push((account, ynab_account.id.clone(), running_balances));
             }
         }
     }
 
+    for (account, ynab_account_id, running_balances) in visited_accounts {
+        let ynab_account = get_account(&mut rc, &config.ynab_config.budget_id, &ynab_account_id)?;
+        report_balances(account);
+        let balance = match account.balance {
+            Some(balance) => balance,
+            None => {
+                println!(
+                    "No balance for {}, not reconciling {}",
+                    account.display_name, ynab_account.name
+                );
+                continue;
+            }
+        };
+
+        let rate = currency_converter
+            .get_rate(Utc::today(), &account.currency, currency)
+            .ok_or_else(|| anyhow!("Missing rates for {:#?}", &account))?;
+        let calc_balance = (balance as crate::currency::Rate * rate) as i64;
+        println!(
+            "Account {} = {}. Expected balance {}",
+            ynab_account.name, ynab_account.balance, calc_balance
+        );
+        if should_reconcile(ynab_account.balance, calc_balance) {
+            find_discrepancy_start(
+                &mut rc,
+                &config.ynab_config.budget_id,
+                &ynab_account,
+                &running_balances,
+            )?;
+            let correction = crate::Transaction {
+                transaction_id: "correction_".to_string() + &Utc::now().timestamp().to_string(),
+                timestamp: Utc::now(),
+                amount: calc_balance - ynab_account.balance,
+                description: format!(
+                    "Reconciliation. {} @ {} = {}",
+                    (balance as f64 / 1000.0),
+                    rate,
+                    (calc_balance as f64 / 1000.0)
+                ),
+                category: Some("Inflow: To be Budgeted".to_string()),
+                payee_name: Some("Sync Reconciliation".to_string()),
+                state: TransactionState::Cleared,
+                running_balance: None,
+                splits: vec![],
+                exchanged: None,
+            };
+            import_transactions(
+                &mut rc,
+                &config.ynab_config.budget_id,
+                &ynab_account.id,
+                vec![correction],
+                false,
+            )?;
+        }
+    }
+
     Ok(())
 }
 
+/// Replaces a transaction's amount with one in the budget's currency. Splits
+/// are scaled by the same rate, with the rounding remainder on the last one
+/// so that they still add up to the amount.
+fn set_converted_amount(tran: &mut Transaction, amount: i64) {
+    if let Some((last, others)) = tran.splits.split_last_mut() {
+        let rate = match tran.amount {
+            0 => 0.0,
+            original => amount as f64 / original as f64,
+        };
+        let mut remaining = amount;
+        for split in others {
+            split.amount = (split.amount as f64 * rate).round() as i64;
+            remaining -= split.amount;
+        }
+        last.amount = remaining;
+    }
+    tran.amount = amount;
+}
+
+/// Logs the remaining credit, overdraft and statement balance, in the
+/// account's own currency.
+fn report_balances(account: &crate::Account) {
+    let balances = &account.balances;
+    let units = |amount: i64| amount as f64 / 1000.0;
+    if let (crate::AccountType::Card, Some(available)) = (&account.ty, balances.available) {
+        match balances.credit_limit {
+            Some(limit) => println!(
+                "{} remaining credit {} {} of {}",
+                account.display_name,
+                units(available),
+                account.currency,
+                units(limit)
+            ),
+            None => println!(
+                "{} remaining credit {} {}",
+                account.display_name,
+                units(available),
+                account.currency
+            ),
+        }
+    }
+    if let Some(overdraft) = balances.overdraft.filter(|it| *it != 0) {
+        println!(
+            "{} overdraft {} {}",
+            account.display_name,
+            units(overdraft),
+            account.currency
+        );
+    }
+    if let Some(statement) = balances.last_statement_balance {
+        println!(
+            "{} statement balance {} {}",
+            account.display_name,
+            units(statement),
+            account.currency
+        );
+    }
+}
+
+/// The balance a provider reported after one of its transactions
+struct BalancePoint {
+    timestamp: UtcDateTime,
+    balance: i64,
+    description: String,
+}
+
+/// Compares the provider's running balances with YNAB's balance at the end
+/// of each day, and reports the day from which they differ by the same
+/// amount as the final discrepancy.
+fn find_discrepancy_start(
+    rc: &mut RestClient,
+    budget_id: &str,
+    ynab_account: &Account,
+    running_balances: &[BalancePoint],
+) -> Result<()> {
+    let first_date = match running_balances.iter().map(|it| it.timestamp.date()).min() {
+        Some(date) => date,
+        None => return Ok(()),
+    };
+    let ynab_transactions: Vec<TransactionDetail> =
+        get_transactions(rc, budget_id, &ynab_account.id, first_date)?
+            .into_iter()
+            .filter(|it| !it.deleted)
+            .collect();
+
+    let discrepancy =
+        match discrepancy_start(ynab_account.balance, &ynab_transactions, running_balances) {
+            Some(discrepancy) => discrepancy,
+            None => return Ok(()),
+        };
+    let point = discrepancy.point;
+    if discrepancy.predates_balances {
+        println!(
+            "Balance discrepancy of {} predates the earliest transaction with a running balance, on {} ({})",
+            discrepancy.amount, discrepancy.date, point.description
+        );
+    } else {
+        println!(
+            "Balance discrepancy of {} starts on {}, bank balance was {} after {}",
+            discrepancy.amount, discrepancy.date, point.balance, point.description
+        );
+    }
+
+    Ok(())
+}
+
+struct Discrepancy<'a> {
+    /// The bank's balance less YNAB's
+    amount: i64,
+    date: String,
+    /// The bank's last balance on `date`
+    point: &'a BalancePoint,
+    /// Whether the balances already differ on the first day with a running balance
+    predates_balances: bool,
+}
+
+/// Finds the day from which the bank's running balances and YNAB's differ by
+/// the final discrepancy, given YNAB's current balance and its transactions
+/// since the first running balance.
+fn discrepancy_start<'a>(
+    ynab_balance: i64,
+    ynab_transactions: &[TransactionDetail],
+    running_balances: &'a [BalancePoint],
+) -> Option<Discrepancy<'a>> {
+    // Bank balance at the end of each day, from its last transaction that day
+    let mut daily: Vec<(String, &BalancePoint)> = vec![];
+    let mut sorted: Vec<&BalancePoint> = running_balances.iter().collect();
+    sorted.sort_by_key(|it| it.timestamp);
+    for point in sorted {
+        let date = point.timestamp.date().format("%Y-%m-%d").to_string();
+        match daily.last_mut() {
+            Some((last_date, last)) if *last_date == date => *last = point,
+            _ => daily.push((date, point)),
+        }
+    }
+
+    let differences: Vec<i64> = daily
+        .iter()
+        .map(|(date, point)| {
+            let later: i64 = ynab_transactions
+                .iter()
+                .filter(|it| &it.date > date)
+                .map(|it| it.amount)
+                .sum();
+            point.balance - (ynab_balance - later)
+        })
+        .collect();
+
+    let final_difference = match differences.last() {
+        Some(difference) if *difference != 0 => *difference,
+        _ => return None,
+    };
+    let start = differences
+        .iter()
+        .rposition(|it| *it != final_difference)
+        .map_or(0, |it| it + 1);
+    let (date, point) = &daily[start];
+    Some(Discrepancy {
+        amount: final_difference,
+        date: date.clone(),
+        point,
+        predates_balances: start == 0,
+    })
+}
+
 pub fn new_rest_client(access_token: &str) -> RestClient {
     const HOST: &str = "https://api.youneedabudget.com/";
 
@@ -156,11 +553,114 @@ pub fn get_payees(rc: &mut RestClient, budget_id: &str) -> Result<Vec<Payee>> {
     Ok(accounts.into_inner().data.payees)
 }
 
+pub fn get_transactions(
+    rc: &mut RestClient,
+    budget_id: &str,
+    account_id: &str,
+    since_date: UtcDate,
+) -> Result<Vec<TransactionDetail>> {
+    let since_date = since_date.format("%Y-%m-%d").to_string();
+    let transactions: Response<Wrapper<TransactionsResponse>> =
+        rc.get_with((budget_id, account_id), &[("since_date", &since_date)])?;
+    Ok(transactions.into_inner().data.transactions)
+}
+
+/// Brings transactions already imported into YNAB in line with the provider's
+/// current view of them: reverted transactions are deleted, pending ones
+/// which have since completed are marked as cleared, and pending ones which
+/// are no longer reported are deleted.
+pub fn update_transaction_states(
+    rc: &mut RestClient,
+    budget_id: &str,
+    account_id: &str,
+    transactions: &[Transaction],
+    prefix_pending: bool,
+) -> Result<()> {
+    let since_date = match transactions.iter().map(|it| it.timestamp.date()).min() {
+        Some(date) => date,
+        None => return Ok(()),
+    };
+    let states: HashMap<String, TransactionState> = transactions
+        .iter()
+        .map(|it| (import_id(it, prefix_pending), it.state))
+        .collect();
+
+    let mut cleared = vec![];
+    for existing in get_transactions(rc, budget_id, account_id, since_date)? {
+        match state_change(&existing, &states, prefix_pending) {
+            Some(StateChange::Delete(reason)) => {
+                println!(
+                    "Deleting {} {} {} {}",
+                    reason,
+                    existing.date,
+                    existing.amount,
+                    existing.payee_name.as_deref().unwrap_or("")
+                );
+                rc.delete::<_, Wrapper<TransactionResponse>>((budget_id, existing.id.as_str()))?;
+            }
+            Some(StateChange::Clear) => {
+                cleared.push(UpdateTransaction {
+                    id: existing.id,
+                    cleared: "cleared".to_string(),
+                });
+            }
+            None => {}
+        }
+    }
+
+    if !cleared.is_empty() {
+        println!(
+            "Marking {} completed transactions as cleared",
+            cleared.len()
+        );
+        rc.patch(
+            budget_id,
+            &UpdateTransactions {
+                transactions: cleared,
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum StateChange {
+    /// Deleted from YNAB, with the reason shown to the user
+    Delete(&'static str),
+    /// Marked as cleared in YNAB
+    Clear,
+}
+
+/// Decides how a transaction already in YNAB should change, given the states
+/// of the provider's transactions by import ID.
+fn state_change(
+    existing: &TransactionDetail,
+    states: &HashMap<String, TransactionState>,
+    prefix_pending: bool,
+) -> Option<StateChange> {
+    if existing.deleted {
+        return None;
+    }
+    let import_id = existing.import_id.as_deref()?;
+    match states.get(import_id) {
+        Some(TransactionState::Reverted) => Some(StateChange::Delete("reverted transaction")),
+        Some(TransactionState::Cleared) if existing.cleared == "uncleared" => {
+            Some(StateChange::Clear)
+        }
+        None if prefix_pending && import_id.starts_with(PENDING_PREFIX) => Some(
+            StateChange::Delete("pending transaction which is no longer pending"),
+        ),
+        _ => None,
+    }
+}
+
 pub fn import_transactions(
     rc: &mut RestClient,
     budget_id: &str,
     account_id: &str,
     transactions: Vec<Transaction>,
+    prefix_pending: bool,
 ) -> Result<()> {
     let payees = get_payees(rc, budget_id)?;
     for trans in transactions.chunks(50) {
@@ -185,9 +685,22 @@ pub fn import_transactions(
                     payee_name,
                     payee_id,
                     memo: tran.description.clone(),
-                    cleared: "cleared".to_string(),
-                    import_id: Some(tran.transaction_id.clone()),
+                    cleared: match tran.state {
+                        TransactionState::Pending => "uncleared",
+                        _ => "cleared",
+                    }
+                    .to_string(),
+                    import_id: Some(import_id(tran, prefix_pending)),
                     category_name: tran.category.clone(),
+                    subtransactions: tran
+                        .splits
+                        .iter()
+                        .map(|split| NewSubTransaction {
+                            amount: split.amount,
+                            memo: split.memo.clone(),
+                            category_name: split.category.clone(),
+                        })
+                        .collect(),
                 }
             })
             .collect();
@@ -203,6 +716,139 @@ pub fn import_transactions(
     Ok(())
 }
 
+/// Compares recurring payments reported by the provider with the account's
+/// scheduled transactions in YNAB, matching them by payee. Missing ones are
+/// created if `create` is set, otherwise they're listed for manual entry.
+pub fn sync_scheduled_transactions(
+    rc: &mut RestClient,
+    budget_id: &str,
+    account_id: &str,
+    scheduled: Vec<ScheduledTransaction>,
+    create: bool,
+) -> Result<()> {
+    if scheduled.is_empty() {
+        return Ok(());
+    }
+
+    let existing: Response<Wrapper<ScheduledTransactionsResponse>> = rc.get(budget_id)?;
+    let existing: Vec<ScheduledTransactionDetail> = existing
+        .into_inner()
+        .data
+        .scheduled_transactions
+        .into_iter()
+        .filter(|it| !it.deleted && it.account_id == account_id)
+        .collect();
+
+    let today = Utc::today();
+    for mut scheduled in scheduled {
+        match scheduled_change(&existing, &scheduled, today) {
+            ScheduledChange::Unchanged => continue,
+            ScheduledChange::AmountDiffers(ynab_amount) => {
+                println!(
+                    "Scheduled transaction for {} is {} in YNAB but {} at the bank",
+                    scheduled.payee_name, ynab_amount, scheduled.amount
+                );
+                continue;
+            }
+            ScheduledChange::Missing(next_date) => scheduled.next_date = next_date,
+        }
+
+        if !create {
+            println!(
+                "Missing scheduled transaction: {} {} {} from {}",
+                scheduled.payee_name,
+                scheduled.amount,
+                ynab_frequency(scheduled.frequency),
+                scheduled.next_date.format("%Y-%m-%d")
+            );
+            continue;
+        }
+
+        println!(
+            "Creating scheduled transaction: {} {} {} from {}",
+            scheduled.payee_name,
+            scheduled.amount,
+            ynab_frequency(scheduled.frequency),
+            scheduled.next_date.format("%Y-%m-%d")
+        );
+        rc.post(
+            budget_id,
+            &NewScheduledTransactionWrapper {
+                scheduled_transaction: NewScheduledTransaction {
+                    account_id: account_id.to_string(),
+                    date: scheduled.next_date.format("%Y-%m-%d").to_string(),
+                    amount: scheduled.amount,
+                    payee_name: scheduled.payee_name,
+                    memo: scheduled.memo,
+                    frequency: ynab_frequency(scheduled.frequency).to_string(),
+                },
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum ScheduledChange {
+    /// In YNAB already, for the same amount
+    Unchanged,
+    /// In YNAB already, but for the given amount
+    AmountDiffers(i64),
+    /// Not in YNAB, and next due on the given date
+    Missing(UtcDate),
+}
+
+/// Finds a provider's scheduled transaction among YNAB's by payee name.
+fn scheduled_change(
+    existing: &[ScheduledTransactionDetail],
+    scheduled: &ScheduledTransaction,
+    today: UtcDate,
+) -> ScheduledChange {
+    let matching = existing.iter().find(|it| {
+        it.payee_name
+            .as_deref()
+            .is_some_and(|it| it.eq_ignore_ascii_case(&scheduled.payee_name))
+    });
+    match matching {
+        Some(matching) if matching.amount == scheduled.amount => ScheduledChange::Unchanged,
+        Some(matching) => ScheduledChange::AmountDiffers(matching.amount),
+        None => {
+            // YNAB only accepts scheduled transactions starting in the future
+            let mut next_date = scheduled.next_date;
+            while next_date <= today {
+                next_date = scheduled.frequency.advance(next_date);
+            }
+            ScheduledChange::Missing(next_date)
+        }
+    }
+}
+
+fn ynab_frequency(frequency: Frequency) -> &'static str {
+    match frequency {
+        Frequency::Daily => "daily",
+        Frequency::Weekly => "weekly",
+        Frequency::EveryOtherWeek => "everyOtherWeek",
+        Frequency::Every4Weeks => "every4Weeks",
+        Frequency::Monthly => "monthly",
+        Frequency::EveryOtherMonth => "everyOtherMonth",
+        Frequency::Every3Months => "every3Months",
+        Frequency::Every4Months => "every4Months",
+        Frequency::TwiceAYear => "twiceAYear",
+        Frequency::Yearly => "yearly",
+        Frequency::EveryOtherYear => "everyOtherYear",
+    }
+}
+
+fn import_id(tran: &Transaction, prefix_pending: bool) -> String {
+    match tran.state {
+        TransactionState::Pending if prefix_pending => {
+            crate::import_id::fit(&format!("{}{}", PENDING_PREFIX, tran.transaction_id))
+        }
+        _ => crate::import_id::fit(&tran.transaction_id),
+    }
+}
+
 fn should_reconcile(acc_balance: i64, calc_balance: i64) -> bool {
     if acc_balance == calc_balance {
         return false;
@@ -291,6 +937,16 @@ pub mod api {
         pub import_id: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub category_name: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        pub subtransactions: Vec<NewSubTransaction>,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct NewSubTransaction {
+        pub amount: i64,
+        pub memo: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub category_name: Option<String>,
     }
 
     #[derive(Serialize)]
@@ -298,6 +954,70 @@ pub mod api {
         pub transactions: Vec<NewTransaction>,
     }
 
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct TransactionDetail {
+        pub id: String,
+        pub date: String,
+        pub amount: i64,
+        pub memo: Option<String>,
+        pub cleared: String,
+        pub payee_name: Option<String>,
+        pub import_id: Option<String>,
+        pub deleted: bool,
+    }
+
+    #[derive(Deserialize)]
+    pub struct TransactionsResponse {
+        pub transactions: Vec<TransactionDetail>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct TransactionResponse {
+        pub transaction: TransactionDetail,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct ScheduledTransactionDetail {
+        pub id: String,
+        pub date_next: String,
+        pub frequency: String,
+        pub amount: i64,
+        pub account_id: String,
+        pub payee_name: Option<String>,
+        pub deleted: bool,
+    }
+
+    #[derive(Deserialize)]
+    pub struct ScheduledTransactionsResponse {
+        pub scheduled_transactions: Vec<ScheduledTransactionDetail>,
+    }
+
+    #[derive(Serialize)]
+    pub struct NewScheduledTransaction {
+        pub account_id: String,
+        pub date: String,
+        pub amount: i64,
+        pub payee_name: String,
+        pub memo: String,
+        pub frequency: String,
+    }
+
+    #[derive(Serialize)]
+    pub struct NewScheduledTransactionWrapper {
+        pub scheduled_transaction: NewScheduledTransaction,
+    }
+
+    #[derive(Serialize)]
+    pub struct UpdateTransaction {
+        pub id: String,
+        pub cleared: String,
+    }
+
+    #[derive(Serialize)]
+    pub struct UpdateTransactions {
+        pub transactions: Vec<UpdateTransaction>,
+    }
+
     impl RestPath<()> for BudgetsResponse {
         fn get_path(_: ()) -> Result<String, restson::Error> {
             Ok("v1/budgets".to_string())
@@ -335,8 +1055,308 @@ pub mod api {
         }
     }
 
+    impl RestPath<(&str, &str)> for Wrapper<TransactionsResponse> {
+        fn get_path(params: (&str, &str)) -> Result<String, restson::Error> {
+            let (budget_id, account_id) = params;
+            Ok(format!(
+                "v1/budgets/{}/accounts/{}/transactions",
+                budget_id, account_id
+            ))
+        }
+    }
+
+    impl RestPath<(&str, &str)> for Wrapper<TransactionResponse> {
+        fn get_path(params: (&str, &str)) -> Result<String, restson::Error> {
+            let (budget_id, transaction_id) = params;
+            Ok(format!(
+                "v1/budgets/{}/transactions/{}",
+                budget_id, transaction_id
+            ))
+        }
+    }
+
+    impl RestPath<&str> for Wrapper<ScheduledTransactionsResponse> {
+        fn get_path(budget_id: &str) -> Result<String, restson::Error> {
+            Ok(format!("v1/budgets/{}/scheduled_transactions", budget_id))
+        }
+    }
+
+    impl RestPath<&str> for NewScheduledTransactionWrapper {
+        fn get_path(budget_id: &str) -> Result<String, restson::Error> {
+            Ok(format!("v1/budgets/{}/scheduled_transactions", budget_id))
+        }
+    }
+
+    impl RestPath<&str> for UpdateTransactions {
+        fn get_path(budget_id: &str) -> Result<String, restson::Error> {
+            Ok(format!("v1/budgets/{}/transactions", budget_id))
+        }
+    }
+
     #[derive(Deserialize)]
     pub struct Wrapper<T> {
         pub data: T,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{AccountNote, BalancePoint, ScheduledChange, StateChange, TransactionDetail};
+    use crate::prelude::*;
+    use crate::{AccountIdentifiers, TransactionState};
+
+    #[test]
+    fn account_note_matching() {
+        let note = AccountNote::parse(
+            "Joint account\nACCOUNT_ID=\"old\" SORT_CODE=\"12-34-56\" ACCOUNT_NUMBER=\"12345678\"",
+        );
+        assert_eq!(Some("old"), note.account_id.as_deref());
+
+        let mut identifiers = AccountIdentifiers {
+            sort_code: Some("123456".to_string()),
+            account_number: Some("12345678".to_string()),
+            ..Default::default()
+        };
+        assert!(note.matches_bank_details(&identifiers));
+        identifiers.account_number = Some("87654321".to_string());
+        assert!(!note.matches_bank_details(&identifiers));
+
+        let card = AccountIdentifiers {
+            partial_card_number: Some("************4321".to_string()),
+            ..Default::default()
+        };
+        assert!(AccountNote::parse("CARD=\"4321\"").matches_card(&card));
+        assert!(
+            !AccountNote::parse("IBAN=\"\"").matches_bank_details(&AccountIdentifiers {
+                iban: Some("".to_string()),
+                ..Default::default()
+            })
+        );
+    }
+
+    fn account(id: &str, note: &str) -> super::Account {
+        super::Account {
+            id: id.to_string(),
+            name: id.to_string(),
+            note: Some(note.to_string()),
+            balance: 0,
+        }
+    }
+
+    fn card(id: &str, number: &str) -> crate::Account {
+        crate::Account {
+            account_id: id.to_string(),
+            currency: "GBP".to_string(),
+            display_name: id.to_string(),
+            ty: crate::AccountType::Card,
+            balance: None,
+            balances: Default::default(),
+            identifiers: AccountIdentifiers {
+                partial_card_number: Some(number.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn card_matches_must_be_unique() {
+        let ynab_accounts = [
+            account("ynab-card", "CARD=\"4321\""),
+            account("ynab-other", "ACCOUNT_ID=\"other\""),
+        ];
+        let notes: Vec<_> = ynab_accounts
+            .iter()
+            .map(|it| (AccountNote::parse(it.note.as_deref().unwrap()), it))
+            .collect();
+        let find = |accounts: &[&crate::Account], account| {
+            super::find_ynab_account(&notes, accounts, account).map(|it| it.id.as_str())
+        };
+
+        let amex = card("amex", "***4321");
+        assert_eq!(Some("ynab-card"), find(&[&amex], &amex));
+
+        // Another provider's card ending in the same digits
+        let visa = card("visa", "***4321");
+        assert_eq!(None, find(&[&amex, &visa], &amex));
+        // Unless an ACCOUNT_ID note says which is which
+        let other = card("other", "***4321");
+        assert_eq!(Some("ynab-other"), find(&[&amex, &other], &other));
+    }
+
+    fn existing(import_id: &str, cleared: &str) -> TransactionDetail {
+        TransactionDetail {
+            id: "ynab-id".to_string(),
+            date: "2021-03-01".to_string(),
+            amount: -1000,
+            memo: None,
+            cleared: cleared.to_string(),
+            payee_name: None,
+            import_id: Some(import_id.to_string()),
+            deleted: false,
+        }
+    }
+
+    #[test]
+    fn state_changes() -> Result<()> {
+        let states: HashMap<String, TransactionState> = vec![
+            ("reverted".to_string(), TransactionState::Reverted),
+            ("settled".to_string(), TransactionState::Cleared),
+            ("P:pending".to_string(), TransactionState::Pending),
+        ]
+        .into_iter()
+        .collect();
+        let change =
+            |import_id, cleared| super::state_change(&existing(import_id, cleared), &states, true);
+
+        assert!(matches!(
+            change("reverted", "cleared"),
+            Some(StateChange::Delete(_))
+        ));
+        assert_eq!(Some(StateChange::Clear), change("settled", "uncleared"));
+        // Reconciled transactions are left alone
+        assert_eq!(None, change("settled", "reconciled"));
+        assert_eq!(None, change("P:pending", "uncleared"));
+        // Settled under a new ID, so the pending one is removed
+        assert!(matches!(
+            change("P:settled-elsewhere", "uncleared"),
+            Some(StateChange::Delete(_))
+        ));
+        // Older than the provider's history, or imported by hand
+        assert_eq!(None, change("unknown", "uncleared"));
+        // Providers which keep IDs when settling, or couldn't list pending transactions
+        assert_eq!(
+            None,
+            super::state_change(
+                &existing("P:settled-elsewhere", "uncleared"),
+                &states,
+                false
+            )
+        );
+
+        let mut deleted = existing("reverted", "cleared");
+        deleted.deleted = true;
+        assert_eq!(None, super::state_change(&deleted, &states, true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn converted_splits() -> Result<()> {
+        let split = |amount| crate::Split {
+            amount,
+            category: None,
+            memo: String::new(),
+        };
+        let mut tran = crate::Transaction {
+            transaction_id: "t1".to_string(),
+            timestamp: chrono::Utc::now(),
+            amount: -10000,
+            description: "Groceries and wine".to_string(),
+            payee_name: None,
+            category: None,
+            state: TransactionState::Cleared,
+            running_balance: None,
+            splits: vec![split(-3330), split(-3330), split(-3340)],
+            exchanged: None,
+        };
+
+        // e.g. EUR in a GBP budget, or the amount exchanged by the provider
+        super::set_converted_amount(&mut tran, -8571);
+        let amounts: Vec<_> = tran.splits.iter().map(|it| it.amount).collect();
+        assert_eq!(vec![-2854, -2854, -2863], amounts);
+        assert_eq!(-8571, amounts.iter().sum::<i64>());
+        assert_eq!(-8571, tran.amount);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scheduled_changes() -> Result<()> {
+        use super::ScheduledTransactionDetail;
+        use chrono::TimeZone;
+
+        let detail = |payee_name: &str, amount| ScheduledTransactionDetail {
+            id: "ynab-id".to_string(),
+            date_next: "2021-04-01".to_string(),
+            frequency: "monthly".to_string(),
+            amount,
+            account_id: "ynab-account".to_string(),
+            payee_name: Some(payee_name.to_string()),
+            deleted: false,
+        };
+        let existing = [detail("Landlord", -800000), detail("Energy Co", -45000)];
+        let scheduled = |payee_name: &str, amount| crate::ScheduledTransaction {
+            next_date: chrono::Utc.ymd(2021, 3, 1),
+            amount,
+            payee_name: payee_name.to_string(),
+            memo: String::new(),
+            frequency: crate::Frequency::Monthly,
+        };
+        let today = chrono::Utc.ymd(2021, 3, 15);
+        let change = |payee_name, amount| {
+            super::scheduled_change(&existing, &scheduled(payee_name, amount), today)
+        };
+
+        assert_eq!(ScheduledChange::Unchanged, change("LANDLORD", -800000));
+        assert_eq!(
+            ScheduledChange::AmountDiffers(-45000),
+            change("Energy Co", -45300)
+        );
+        // Moved on to the first date after today
+        assert_eq!(
+            ScheduledChange::Missing(chrono::Utc.ymd(2021, 4, 1)),
+            change("Gym", -30000)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn discrepancy_starts() -> Result<()> {
+        let ynab_transaction = |date: &str, amount| TransactionDetail {
+            amount,
+            date: date.to_string(),
+            ..existing("", "cleared")
+        };
+        let ynab_transactions = [
+            ynab_transaction("2021-03-01", -100000),
+            ynab_transaction("2021-03-02", -50000),
+            ynab_transaction("2021-03-03", -150000),
+        ];
+        // So YNAB's balance was 900 after 1 March and 850 after 2 March
+        let ynab_balance = 700000;
+        let point = |day, balance| -> Result<BalancePoint> {
+            Ok(BalancePoint {
+                timestamp: format!("2021-03-0{}T12:00:00Z", day).parse()?,
+                balance,
+                description: format!("Day {}", day),
+            })
+        };
+        let start = |points: &[BalancePoint]| {
+            super::discrepancy_start(ynab_balance, &ynab_transactions, points)
+                .map(|it| (it.amount, it.date, it.predates_balances))
+        };
+
+        assert_eq!(
+            None,
+            start(&[point(1, 900000)?, point(2, 850000)?, point(3, 700000)?])
+        );
+        // A 20 fee on 2 March that YNAB doesn't have
+        assert_eq!(
+            Some((-20000, "2021-03-02".to_string(), false)),
+            start(&[point(1, 900000)?, point(2, 830000)?, point(3, 680000)?])
+        );
+        // Without a running balance for 2 March, 3 March is the first known to differ
+        assert_eq!(
+            Some((-20000, "2021-03-03".to_string(), false)),
+            start(&[point(1, 900000)?, point(3, 680000)?])
+        );
+        assert_eq!(
+            Some((-20000, "2021-03-01".to_string(), true)),
+            start(&[point(1, 880000)?, point(3, 680000)?])
+        );
+        assert_eq!(None, start(&[]));
+
+        Ok(())
+    }
+}